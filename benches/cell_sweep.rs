@@ -0,0 +1,49 @@
+use std::path::Path;
+
+use criterion::{black_box, criterion_group, criterion_main, Criterion, Throughput};
+use inkwell::context::Context;
+
+use brainfuck_rs::{
+    code_gen::{CodeGen, CodeGenOptions},
+    instruction::Instruction,
+    interpreter,
+    parser::Parser,
+    tok::Tokenizer,
+};
+
+/// The number of cells the standard workload sweeps over.
+const SWEEP_CELLS: usize = 1 << 16;
+
+/// Parses the standard workload, which increments every cell once while moving right, without
+/// optimizing it, so that every `+` and `>` remains a separate instruction.
+fn cell_sweep() -> Vec<Instruction> {
+    let source = "+>".repeat(SWEEP_CELLS);
+
+    Parser::new(Tokenizer::new(&source))
+        .collect::<Result<Vec<_>, _>>()
+        .unwrap()
+}
+
+fn bench_cell_sweep(c: &mut Criterion) {
+    let instructions = cell_sweep();
+
+    let mut group = c.benchmark_group("cell_sweep");
+    group.throughput(Throughput::Elements(instructions.len() as u64));
+
+    group.bench_function("interpret", |b| {
+        b.iter(|| interpreter::interpret(black_box(&instructions), &[]).unwrap())
+    });
+
+    let context = Context::create();
+    group.bench_function("code_gen", |b| {
+        b.iter(|| {
+            let code_gen = CodeGen::new(Path::new("sweep.bf"), CodeGenOptions::default(), &context);
+            code_gen.generate_module(black_box(&instructions)).unwrap();
+        })
+    });
+
+    group.finish();
+}
+
+criterion_group!(benches, bench_cell_sweep);
+criterion_main!(benches);
@@ -0,0 +1,30 @@
+Applies ROT13 to its input until the end of the input
+Based on the ROT13 program from the Brainfuck article on Wikipedia and changed to stop at the end of
+the input where a read sets the cell to 0
+
+,[                          Read the first character and start the character loop
+    [                       Skip forward if the character is 0
+        >>++++[>++++++++<-] Set up the divisor (32) for the division loop
+        <+<-[               Set up the dividend (character minus 1) and enter the division loop
+            >+>+>-[>>>]     Increase the copy and the remainder and reduce the divisor
+            <[[>+<-]>>+>]   If the divisor is 0 move the remainder back and increase the quotient
+            <<<<<-          Decrement the dividend
+        ]                   End the division loop
+    ]>>>[-]+                End the skip loop and reuse the former divisor as a flag
+    >--[-[<->+++[-]]]<[     Clear the flag unless the quotient was 2 or 3 and check the flag
+        ++++++++++++<[      If the flag is set set up the divisor (13) for the second division
+            >-[>+>>]        Reduce the divisor and increase the remainder
+            >[+[<+>-]>+>>]  If the divisor is 0 move the remainder back and increase the quotient
+            <<<<<-          Decrement the dividend
+        ]                   End the division loop
+        >>[<+>-]            Add the remainder back to the divisor to get 13
+        >[                  Skip forward if the quotient was 0
+            -[              Skip forward if the quotient was 1
+                -<<[-]>>    Clear the quotient and the divisor if the quotient was 2
+            ]<<[<<->>-]>>   Clear the divisor and subtract 13 from the copy if the quotient was 1
+        ]<<[<<+>>-]         Clear the divisor and add 13 to the copy if the quotient was 0
+    ]                       End the outer skip loop
+    <[-]                    Clear the remainder of the first division if the second was skipped
+    <.[-]                   Output the converted character from the copy and clear it
+    <,                      Read the next character
+]                           End the character loop
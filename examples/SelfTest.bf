@@ -0,0 +1,22 @@
+Self checking test program for the assertion directive
+Compile or run it with the assertions flag enabled; it prints OK if all checks pass
+
+Increments and decrements
++++++ =5 -- =3
+
+Wrapping around in both directions
+[-] =0 - =255 + =0
+
+Multiplication loop computing 7 times 8 in the next cell
++++++++ =7
+[>++++++++<-] =0
+> =56
+
+Moving a value two cells to the right
+[>>+<<-] =0 >> =56
+
+Print the letters O and K followed by a newline
+[-] =0
+++++++++[>++++++++++<-]> =80 - =79 .
+---- =75 .
+[-] ++++++++++ =10 .
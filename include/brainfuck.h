@@ -0,0 +1,59 @@
+/*
+ * The C interface of brainfuck-rs, which is exported by the library when it is built with the
+ * `capi` feature. See src/capi.rs for the details of every function.
+ *
+ * Every buffer returned by these functions is owned by the caller and has to be freed with the
+ * matching bf_free_* function, never with free().
+ */
+
+#ifndef BRAINFUCK_H
+#define BRAINFUCK_H
+
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+/* The options of bf_compile and bf_run. Passing NULL instead disables every option. */
+typedef struct bf_options {
+    bool optimize;
+    /* Moving left of the first cell stays on it instead of failing. */
+    bool clamp_left;
+    /* `=N` asserts that the current cell equals N instead of being a comment. */
+    bool assertions;
+} bf_options;
+
+/*
+ * Compiles the program in the source_len bytes at source into an object file for the host, which
+ * still has to be linked with stdlib/helpers.c.
+ *
+ * Returns the object file and stores its length in object_len, free it with bf_free_object. On
+ * failure, returns NULL and stores a message in error unless it is NULL, free it with
+ * bf_free_error.
+ */
+uint8_t *bf_compile(const uint8_t *source, size_t source_len, const bf_options *options,
+                    size_t *object_len, char **error);
+
+/*
+ * Interprets the program in the source_len bytes at source, reading from stdin and writing to
+ * stdout.
+ *
+ * Returns 0 if the program ran to the end, or the exit code of the command line compiler. On
+ * failure, stores a message in error unless it is NULL, free it with bf_free_error.
+ */
+int bf_run(const uint8_t *source, size_t source_len, const bf_options *options, char **error);
+
+/* Frees an object file returned by bf_compile. Does nothing if object is NULL. */
+void bf_free_object(uint8_t *object, size_t object_len);
+
+/* Frees an error message returned by bf_compile or bf_run. Does nothing if error is NULL. */
+void bf_free_error(char *error);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif
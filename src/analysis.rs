@@ -0,0 +1,581 @@
+use std::collections::BTreeMap;
+
+use crate::instruction::{Instruction, InstructionKind};
+
+/// Returns the highest cell index that `instructions` can reach when executed starting at cell 0,
+/// or `None` if it can't be bounded statically, e.g. because of a scan to the right or a loop that
+/// moves the pointer to the right on every iteration, or if an offset doesn't fit into an `isize`.
+///
+/// The result is conservative: the program may never actually reach the returned cell.
+pub fn max_right_reach(instructions: &[Instruction]) -> Option<usize> {
+    relative_reach(instructions).map(|(reach, _)| reach as usize)
+}
+
+/// A run of instructions at the start of a sequence that always executes completely once it is
+/// entered and never moves the pointer left of the cell it started at.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StraightLineRegion {
+    /// The number of instructions in the region.
+    pub length: usize,
+    /// The furthest the pointer moves right of the cell the region started at.
+    pub reach: usize,
+    /// The number of `MoveRight` instructions in the region.
+    pub moves_right: usize,
+}
+
+/// Returns the straight-line region at the start of `instructions`, which ends in front of the
+/// first instruction that may not complete or changes the pointer by an amount that isn't known
+/// statically, in front of the first move left of the cell the region started at, and in front of
+/// the first move to `isize::MAX` cells right of it or beyond, so that the reach can be added to
+/// any cell of a tape without overflowing.
+///
+/// As every move in the region executes, the tape can be grown to `reach` up front. Unless the tape
+/// has a size limit, this doesn't change when the program runs out of tape.
+pub fn straight_line_region(instructions: &[Instruction]) -> StraightLineRegion {
+    let mut region = StraightLineRegion {
+        length: 0,
+        reach: 0,
+        moves_right: 0,
+    };
+    let mut offset = 0usize;
+
+    for instruction in instructions {
+        match &instruction.kind {
+            InstructionKind::MoveRight { amount } => {
+                match offset
+                    .checked_add(*amount)
+                    .filter(|offset| *offset < isize::MAX as usize)
+                {
+                    Some(new_offset) => offset = new_offset,
+                    None => break,
+                }
+
+                region.moves_right += 1;
+            }
+            InstructionKind::MoveLeft { amount } => match offset.checked_sub(*amount) {
+                Some(new_offset) => offset = new_offset,
+                None => break,
+            },
+            InstructionKind::ClearRange { start_offset, len } => {
+                let range_end = (offset as isize)
+                    .checked_add(*start_offset)
+                    .filter(|start| *start >= 0)
+                    .and_then(|start| start.checked_add_unsigned(*len));
+
+                if range_end
+                    .filter(|range_end| *range_end < isize::MAX)
+                    .is_none()
+                {
+                    break;
+                }
+            }
+            InstructionKind::Increment { amount: _ }
+            | InstructionKind::Decrement { amount: _ }
+            | InstructionKind::Output
+            | InstructionKind::Input
+            | InstructionKind::SetToZero
+            | InstructionKind::SetValue { value: _ }
+            | InstructionKind::Nop => {}
+            _ => break,
+        }
+
+        region.length += 1;
+    }
+
+    // The region stays within `isize::MAX` cells right of its start, so its reach is bounded.
+    region.reach = max_right_reach(&instructions[..region.length]).unwrap();
+
+    region
+}
+
+/// Returns whether `instructions` contain an `Output` instruction anywhere, including in loops.
+pub fn has_output(instructions: &[Instruction]) -> bool {
+    instructions
+        .iter()
+        .any(|instruction| match &instruction.kind {
+            InstructionKind::Output | InstructionKind::OutputUntilZero { step_size: _ } => true,
+            InstructionKind::Loop { instructions }
+            | InstructionKind::WithMultiplier { instructions } => has_output(instructions),
+            _ => false,
+        })
+}
+
+/// Returns whether `instructions` contain an `AssertEquals` instruction anywhere, including in
+/// loops.
+pub fn has_assertion(instructions: &[Instruction]) -> bool {
+    instructions
+        .iter()
+        .any(|instruction| match &instruction.kind {
+            InstructionKind::AssertEquals { value: _ } => true,
+            InstructionKind::Loop { instructions }
+            | InstructionKind::WithMultiplier { instructions } => has_assertion(instructions),
+            _ => false,
+        })
+}
+
+/// Returns the number of loops in `instructions`, including the nested ones. A `WithMultiplier`
+/// counts as a loop, as it was one before it was optimized.
+pub fn count_loops(instructions: &[Instruction]) -> usize {
+    instructions
+        .iter()
+        .map(|instruction| match &instruction.kind {
+            InstructionKind::Loop { instructions }
+            | InstructionKind::WithMultiplier { instructions } => 1 + count_loops(instructions),
+            _ => 0,
+        })
+        .sum()
+}
+
+/// Returns the deepest nesting of loops in `instructions`, 0 if there are none. Like in
+/// `count_loops`, a `WithMultiplier` counts as a loop.
+pub fn max_nesting_depth(instructions: &[Instruction]) -> usize {
+    instructions
+        .iter()
+        .map(|instruction| match &instruction.kind {
+            InstructionKind::Loop { instructions }
+            | InstructionKind::WithMultiplier { instructions } => {
+                1 + max_nesting_depth(instructions)
+            }
+            _ => 0,
+        })
+        .max()
+        .unwrap_or(0)
+}
+
+/// Returns the index of the first top-level loop in `instructions` that provably never terminates
+/// when they are executed as a whole program, which makes all instructions after it unreachable.
+///
+/// The analysis is conservative: it follows the values of the cells only until it reaches an
+/// instruction whose effect it can't determine statically, e.g. a loop that is entered with an
+/// unknown cell or a scan, and gives up there.
+pub fn first_infinite_loop(instructions: &[Instruction]) -> Option<usize> {
+    // The values of the cells that were written, `None` if unknown. All other cells are zero.
+    let mut cells = BTreeMap::<usize, Option<u8>>::new();
+    let mut pointer = 0usize;
+
+    for (i, instruction) in instructions.iter().enumerate() {
+        let current = cells.get(&pointer).copied().unwrap_or(Some(0));
+
+        match &instruction.kind {
+            InstructionKind::MoveRight { amount } => pointer += amount,
+            InstructionKind::MoveLeft { amount } => pointer = pointer.checked_sub(*amount)?,
+            InstructionKind::Increment { amount } => {
+                cells.insert(pointer, current.map(|value| value.wrapping_add(*amount)));
+            }
+            InstructionKind::Decrement { amount } => {
+                cells.insert(pointer, current.map(|value| value.wrapping_sub(*amount)));
+            }
+            InstructionKind::Output | InstructionKind::Nop => {}
+            InstructionKind::Input => {
+                cells.insert(pointer, None);
+            }
+            InstructionKind::SetToZero => {
+                cells.insert(pointer, Some(0));
+            }
+            InstructionKind::SetValue { value } => {
+                cells.insert(pointer, Some(*value));
+            }
+            InstructionKind::ClearRange { start_offset, len } => {
+                // A range that starts left of the first cell aborts the program.
+                let start = usize::try_from((pointer as isize).checked_add(*start_offset)?).ok()?;
+
+                for cell in start..start + len {
+                    cells.insert(cell, Some(0));
+                }
+            }
+            InstructionKind::TransferValue { offset } => {
+                // So does a destination left of the first cell, even if the value is zero.
+                let target = usize::try_from((pointer as isize).checked_add(*offset)?).ok()?;
+
+                cells.insert(target, current);
+                cells.insert(pointer, Some(0));
+            }
+            InstructionKind::AssertEquals { value } => {
+                // A failing assertion aborts the program, so nothing after it runs anyway.
+                if current.is_some_and(|current| current != *value) {
+                    return None;
+                }
+
+                cells.insert(pointer, Some(*value));
+            }
+            // All remaining instructions are loops in some form, which do nothing on a zero cell.
+            _ if current == Some(0) => {}
+            InstructionKind::Loop { instructions } => {
+                return (current.is_some() && never_terminates(instructions, pointer)).then_some(i);
+            }
+            InstructionKind::WithMultiplier { instructions } => {
+                let mut cell = pointer as isize;
+
+                for instruction in instructions {
+                    let amount = match &instruction.kind {
+                        InstructionKind::MoveRight { amount } => {
+                            cell += *amount as isize;
+                            continue;
+                        }
+                        InstructionKind::MoveLeft { amount } => {
+                            cell -= *amount as isize;
+                            continue;
+                        }
+                        InstructionKind::Increment { amount } => *amount,
+                        InstructionKind::Decrement { amount } => amount.wrapping_neg(),
+                        _ => return None,
+                    };
+
+                    let cell = usize::try_from(cell).ok()?;
+                    let value = cells.get(&cell).copied().unwrap_or(Some(0));
+                    let change = current.map(|multiplier| amount.wrapping_mul(multiplier));
+
+                    cells.insert(cell, value.zip(change).map(|(a, b)| a.wrapping_add(b)));
+                }
+
+                cells.insert(pointer, Some(0));
+            }
+            InstructionKind::MoveValueRight { amount }
+            | InstructionKind::MoveValueLeft { amount } => {
+                let target =
+                    if let InstructionKind::MoveValueRight { amount: _ } = &instruction.kind {
+                        pointer + amount
+                    } else {
+                        pointer.checked_sub(*amount)?
+                    };
+
+                let value = cells.get(&target).copied().unwrap_or(Some(0));
+                cells.insert(target, value.zip(current).map(|(a, b)| a.wrapping_add(b)));
+                cells.insert(pointer, Some(0));
+            }
+            _ => return None,
+        }
+    }
+
+    None
+}
+
+/// Returns whether a loop with the body `instructions` at the cell `pointer` never terminates once
+/// it is entered, because every iteration returns to that cell without changing it.
+fn never_terminates(instructions: &[Instruction], pointer: usize) -> bool {
+    let mut offset = 0isize;
+    let mut change = 0u8;
+
+    for instruction in instructions {
+        match &instruction.kind {
+            InstructionKind::MoveRight { amount } => offset += *amount as isize,
+            InstructionKind::MoveLeft { amount } => {
+                offset -= *amount as isize;
+
+                // Moving left of the first cell aborts the program, which ends the loop.
+                if (pointer as isize) + offset < 0 {
+                    return false;
+                }
+            }
+            InstructionKind::Increment { amount } if offset == 0 => {
+                change = change.wrapping_add(*amount);
+            }
+            InstructionKind::Decrement { amount } if offset == 0 => {
+                change = change.wrapping_sub(*amount);
+            }
+            InstructionKind::Increment { amount: _ }
+            | InstructionKind::Decrement { amount: _ }
+            | InstructionKind::Output
+            | InstructionKind::Nop => {}
+            _ => return false,
+        }
+    }
+
+    offset == 0 && change == 0
+}
+
+/// Returns the highest offset relative to the starting cell that `instructions` can reach, and an
+/// upper bound for the offset at which they leave the pointer, or `None` if either is unbounded or
+/// doesn't fit into an `isize`.
+fn relative_reach(instructions: &[Instruction]) -> Option<(isize, isize)> {
+    let mut offset = 0isize;
+    let mut reach = 0isize;
+
+    for instruction in instructions {
+        match &instruction.kind {
+            InstructionKind::MoveRight { amount } => {
+                offset = offset.checked_add_unsigned(*amount)?
+            }
+            InstructionKind::MoveLeft { amount } => {
+                offset = offset.checked_sub_unsigned(*amount)?
+            }
+            InstructionKind::MoveRightUntilZero { step_size: _ }
+            | InstructionKind::OutputUntilZero { step_size: _ } => return None,
+            // A scan to the left only ever lowers the pointer, so the current offset stays an
+            // upper bound.
+            InstructionKind::MoveLeftUntilZero { step_size: _ } => {}
+            InstructionKind::MoveValueRight { amount } => {
+                reach = reach.max(offset.checked_add_unsigned(*amount)?);
+            }
+            InstructionKind::ClearRange { start_offset, len } => {
+                let range_end = offset
+                    .checked_add(*start_offset)?
+                    .checked_add_unsigned(*len)?;
+                reach = reach.max(range_end.checked_sub(1)?);
+            }
+            InstructionKind::TransferValue { offset: target } => {
+                reach = reach.max(offset.checked_add(*target)?);
+            }
+            InstructionKind::Loop { instructions }
+            | InstructionKind::WithMultiplier { instructions } => {
+                let (body_reach, body_offset) = relative_reach(instructions)?;
+
+                // If an iteration can end further right than it started, the number of
+                // iterations bounds the reach, which isn't known statically.
+                if body_offset > 0 {
+                    return None;
+                }
+
+                reach = reach.max(offset.checked_add(body_reach)?);
+            }
+            _ => {}
+        }
+
+        reach = reach.max(offset);
+    }
+
+    Some((reach, offset))
+}
+
+/// Tracks which cells have a value known at compile time while instructions are generated in the
+/// order they execute, so that e.g. an `Output` of a known cell can use the value directly.
+///
+/// The tracking is local: everything that isn't followed exactly, like the body of a loop or a
+/// scan, makes all cells unknown again, except for the zero cell it leaves the pointer on.
+#[derive(Debug, Clone)]
+pub struct KnownCells {
+    /// The values of the cells that were written since the tracking started or everything became
+    /// unknown, `None` if unknown, by their offset from the cell the pointer was on then.
+    cells: BTreeMap<isize, Option<u8>>,
+    /// The value of all other cells. This is only known at the start of a program, where the whole
+    /// tape is zero and the pointer is on the first cell.
+    others: Option<u8>,
+    pointer: isize,
+    /// Whether a `MoveLeft` past the first cell stops at it, see `CodeGenOptions::clamp_left`.
+    clamp_left: bool,
+}
+
+impl KnownCells {
+    /// Starts tracking at the start of a program, where every cell is zero.
+    pub fn new(clamp_left: bool) -> Self {
+        Self {
+            cells: BTreeMap::new(),
+            others: Some(0),
+            pointer: 0,
+            clamp_left,
+        }
+    }
+
+    /// Returns the value of the current cell, if it is known.
+    pub fn current(&self) -> Option<u8> {
+        self.cells
+            .get(&self.pointer)
+            .copied()
+            .unwrap_or(self.others)
+    }
+
+    /// Makes every cell unknown, e.g. at the start of the body of a loop.
+    pub fn forget(&mut self) {
+        self.cells.clear();
+        self.others = None;
+        self.pointer = 0;
+    }
+
+    /// Makes every cell but the current one unknown, which is zero, e.g. at the end of a loop.
+    pub fn forget_all_but_zero(&mut self) {
+        self.forget();
+        self.cells.insert(0, Some(0));
+    }
+
+    /// Follows the effect of `instruction`, which is executed at the current cell. The instructions
+    /// in the body of a `WithMultiplier` must not be passed on their own, as they depend on the
+    /// multiplier.
+    pub fn apply(&mut self, instruction: &Instruction) {
+        let current = self.current();
+
+        match &instruction.kind {
+            InstructionKind::MoveRight { amount } => match offset_by(self.pointer, *amount, true) {
+                Some(pointer) => self.pointer = pointer,
+                None => self.forget(),
+            },
+            InstructionKind::MoveLeft { amount } => {
+                match offset_by(self.pointer, *amount, false) {
+                    // Only from the start of a program is it known where the first cell is.
+                    Some(pointer) if self.clamp_left && self.others.is_some() => {
+                        self.pointer = pointer.max(0);
+                    }
+                    // Without clamping, moving left of the first cell aborts the program anyway.
+                    Some(pointer) if !self.clamp_left => self.pointer = pointer,
+                    _ => self.forget(),
+                }
+            }
+            InstructionKind::Increment { amount } => {
+                self.set_current(current.map(|value| value.wrapping_add(*amount)));
+            }
+            InstructionKind::Decrement { amount } => {
+                self.set_current(current.map(|value| value.wrapping_sub(*amount)));
+            }
+            InstructionKind::Output | InstructionKind::Nop => {}
+            InstructionKind::Input => self.set_current(None),
+            InstructionKind::SetToZero => self.set_current(Some(0)),
+            InstructionKind::SetValue { value } | InstructionKind::AssertEquals { value } => {
+                // A failing assertion aborts the program, so the cell holds the value afterwards.
+                self.set_current(Some(*value));
+            }
+            InstructionKind::ClearRange { start_offset, len } => {
+                let start = self.pointer.checked_add(*start_offset);
+
+                match start.zip(start.and_then(|start| offset_by(start, *len, true))) {
+                    Some((start, end)) => {
+                        for cell in start..end {
+                            self.cells.insert(cell, Some(0));
+                        }
+                    }
+                    None => self.forget(),
+                }
+            }
+            InstructionKind::TransferValue { offset } => match self.pointer.checked_add(*offset) {
+                Some(target) => {
+                    self.cells.insert(target, current);
+                    self.set_current(Some(0));
+                }
+                None => self.forget(),
+            },
+            // All remaining instructions are loops in some form, which do nothing on a zero cell.
+            _ if current == Some(0) => {}
+            InstructionKind::WithMultiplier { instructions } => {
+                let mut cell = Some(self.pointer);
+
+                for instruction in instructions {
+                    let amount = match &instruction.kind {
+                        InstructionKind::MoveRight { amount } => {
+                            cell = cell.and_then(|cell| offset_by(cell, *amount, true));
+                            continue;
+                        }
+                        // A clamped move only lands where it is expected from the start of a
+                        // program, as long as it stays right of the first cell.
+                        InstructionKind::MoveLeft { amount } => {
+                            cell = cell
+                                .and_then(|cell| offset_by(cell, *amount, false))
+                                .filter(|cell| {
+                                    !self.clamp_left || (self.others.is_some() && *cell >= 0)
+                                });
+                            continue;
+                        }
+                        InstructionKind::Increment { amount } => *amount,
+                        InstructionKind::Decrement { amount } => amount.wrapping_neg(),
+                        _ => return self.forget_all_but_zero(),
+                    };
+
+                    match cell {
+                        Some(cell) => {
+                            let value = self.cells.get(&cell).copied().unwrap_or(self.others);
+                            let change = current.map(|multiplier| amount.wrapping_mul(multiplier));
+
+                            self.cells
+                                .insert(cell, value.zip(change).map(|(a, b)| a.wrapping_add(b)));
+                        }
+                        None => return self.forget_all_but_zero(),
+                    }
+                }
+
+                self.set_current(Some(0));
+            }
+            InstructionKind::MoveValueRight { amount }
+            | InstructionKind::MoveValueLeft { amount } => {
+                let right = matches!(instruction.kind, InstructionKind::MoveValueRight { .. });
+
+                match offset_by(self.pointer, *amount, right) {
+                    Some(target) => {
+                        let value = self.cells.get(&target).copied().unwrap_or(self.others);
+                        self.cells
+                            .insert(target, value.zip(current).map(|(a, b)| a.wrapping_add(b)));
+                        self.set_current(Some(0));
+                    }
+                    None => self.forget_all_but_zero(),
+                }
+            }
+            _ => self.forget_all_but_zero(),
+        }
+    }
+
+    fn set_current(&mut self, value: Option<u8>) {
+        self.cells.insert(self.pointer, value);
+    }
+}
+
+/// Returns the offset `amount` cells right or left of `offset`, if it doesn't overflow.
+fn offset_by(offset: isize, amount: usize, right: bool) -> Option<isize> {
+    let amount = isize::try_from(amount).ok()?;
+
+    if right {
+        offset.checked_add(amount)
+    } else {
+        offset.checked_sub(amount)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::slice;
+
+    use super::*;
+    use crate::{
+        parser::Parser,
+        tok::{SourceLoc, Tokenizer},
+    };
+
+    fn parse(source: &str) -> Vec<Instruction> {
+        Parser::new(Tokenizer::new(source))
+            .map(Result::unwrap)
+            .collect()
+    }
+
+    #[test]
+    fn max_right_reach_bounded() {
+        assert_eq!(max_right_reach(&parse("")), Some(0));
+        assert_eq!(max_right_reach(&parse(">>+<[>>>-<<<-]")), Some(4));
+        assert_eq!(max_right_reach(&parse("+[<]>>")), Some(2));
+        assert_eq!(max_right_reach(&parse(">>>[<]")), Some(3));
+    }
+
+    #[test]
+    fn max_right_reach_unbounded() {
+        assert_eq!(max_right_reach(&parse("+[>]")), None);
+        assert_eq!(max_right_reach(&parse("+[>+]")), None);
+        assert_eq!(max_right_reach(&parse(">[-]>[[>]<]")), None);
+    }
+
+    #[test]
+    fn max_right_reach_overflow() {
+        let loc = SourceLoc {
+            line: 1,
+            col: 1,
+            offset: 0,
+        };
+        let move_right = Instruction::new(
+            InstructionKind::MoveRight {
+                amount: isize::MAX as usize,
+            },
+            loc,
+            loc,
+        );
+
+        assert_eq!(
+            max_right_reach(slice::from_ref(&move_right)),
+            Some(isize::MAX as usize)
+        );
+        assert_eq!(max_right_reach(&[move_right.clone(), move_right]), None);
+    }
+
+    #[test]
+    fn loops_nested_three_levels() {
+        let instructions = parse("+[>+[>+[-]<-]<[-]-]>[-]");
+
+        assert_eq!(count_loops(&instructions), 5);
+        assert_eq!(max_nesting_depth(&instructions), 3);
+
+        assert_eq!(count_loops(&parse("+>-")), 0);
+        assert_eq!(max_nesting_depth(&parse("+>-")), 0);
+    }
+}
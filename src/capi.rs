@@ -0,0 +1,215 @@
+//! A C interface to the compiler, so that it can be driven from other languages. The declarations
+//! are in `include/brainfuck.h`.
+//!
+//! Every buffer returned by these functions is owned by the caller and has to be freed with the
+//! matching `bf_free_*` function, never with `free`, as it was allocated by Rust.
+
+use std::{
+    ffi::CString,
+    io,
+    os::raw::{c_char, c_int},
+    path::Path,
+    ptr, slice,
+};
+
+use crate::{
+    code_gen::CodeGenOptions,
+    compiler::{self, CompileOptions},
+    error::Error,
+    interpreter,
+    optimizer::DEFAULT_MAX_UNROLL_ITERATIONS,
+    tok::Tokenizer,
+};
+
+/// The name of the module generated for a program, which only exists in memory.
+const MODULE_NAME: &str = "program.bf";
+
+/// The options of `bf_compile` and `bf_run`, a subset of `CompileOptions`.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct BfOptions {
+    pub optimize: bool,
+    /// Whether moving left of the first cell stays on it instead of failing.
+    pub clamp_left: bool,
+    /// Whether `=N` is an assertion that the current cell equals N instead of a comment.
+    pub assertions: bool,
+}
+
+impl BfOptions {
+    fn get_compile_options(&self) -> CompileOptions {
+        CompileOptions {
+            optimize: self.optimize,
+            assertions: self.assertions,
+            code_gen: CodeGenOptions {
+                clamp_left: self.clamp_left,
+                ..CodeGenOptions::default()
+            },
+            ..CompileOptions::default()
+        }
+    }
+}
+
+/// Compiles the program in the `source_len` bytes at `source` into an object file for the host,
+/// which still has to be linked with the helpers.
+///
+/// Returns the object file and stores its length in `object_len`. The object file must be freed
+/// with `bf_free_object`. On failure, `NULL` is returned and, unless `error` is `NULL`, a message
+/// is stored in it, which must be freed with `bf_free_error`.
+///
+/// `options` may be `NULL` to use the defaults, where every option is disabled.
+///
+/// # Safety
+///
+/// `source` must point to `source_len` readable bytes, `options` must be `NULL` or point to a
+/// `BfOptions`, `object_len` must point to a writable `size_t` and `error` must be `NULL` or point
+/// to a writable pointer.
+#[no_mangle]
+pub unsafe extern "C" fn bf_compile(
+    source: *const u8,
+    source_len: usize,
+    options: *const BfOptions,
+    object_len: *mut usize,
+    error: *mut *mut c_char,
+) -> *mut u8 {
+    let source = read_source(source, source_len);
+    let options = options.as_ref().copied().unwrap_or_default();
+
+    let result = compiler::compile(
+        &source,
+        Path::new(MODULE_NAME),
+        &options.get_compile_options(),
+    );
+
+    match result {
+        Ok(artifacts) => {
+            let object = artifacts
+                .object
+                .expect("An object file is always emitted by default")
+                .into_boxed_slice();
+            *object_len = object.len();
+
+            Box::into_raw(object) as *mut u8
+        }
+        Err(compile_error) => {
+            set_error(error, &compile_error);
+            *object_len = 0;
+
+            ptr::null_mut()
+        }
+    }
+}
+
+/// Interprets the program in the `source_len` bytes at `source`, reading its input from stdin and
+/// writing its output to stdout.
+///
+/// Returns 0 if the program ran to the end, or the exit code the command line compiler would fail
+/// with, see `ErrorCode`. On failure, unless `error` is `NULL`, a message is stored in it, which
+/// must be freed with `bf_free_error`.
+///
+/// `options` may be `NULL` to use the defaults, where every option is disabled.
+///
+/// # Safety
+///
+/// `source` must point to `source_len` readable bytes, `options` must be `NULL` or point to a
+/// `BfOptions` and `error` must be `NULL` or point to a writable pointer.
+#[no_mangle]
+pub unsafe extern "C" fn bf_run(
+    source: *const u8,
+    source_len: usize,
+    options: *const BfOptions,
+    error: *mut *mut c_char,
+) -> c_int {
+    let source = read_source(source, source_len);
+    let options = options.as_ref().copied().unwrap_or_default();
+
+    match run(&source, &options) {
+        Ok(()) => 0,
+        Err(run_error) => {
+            set_error(error, &run_error);
+
+            run_error.code() as c_int
+        }
+    }
+}
+
+/// Frees an object file returned by `bf_compile`, together with its length. Does nothing if
+/// `object` is `NULL`.
+///
+/// # Safety
+///
+/// `object` must be `NULL` or have been returned by `bf_compile` with `object_len`, and must not
+/// be used afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn bf_free_object(object: *mut u8, object_len: usize) {
+    if !object.is_null() {
+        drop(Box::from_raw(ptr::slice_from_raw_parts_mut(
+            object, object_len,
+        )));
+    }
+}
+
+/// Frees an error message returned by `bf_compile` or `bf_run`. Does nothing if `error` is `NULL`.
+///
+/// # Safety
+///
+/// `error` must be `NULL` or have been returned by `bf_compile` or `bf_run`, and must not be used
+/// afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn bf_free_error(error: *mut c_char) {
+    if !error.is_null() {
+        drop(CString::from_raw(error));
+    }
+}
+
+/// Reads the source of a program. Bytes that aren't valid UTF-8 can only be part of comments, so
+/// they are replaced instead of being rejected.
+unsafe fn read_source(source: *const u8, source_len: usize) -> String {
+    if source_len == 0 {
+        return String::new();
+    }
+
+    String::from_utf8_lossy(slice::from_raw_parts(source, source_len)).into_owned()
+}
+
+/// Stores the message of `source_error` in `error`, unless the caller isn't interested in it.
+unsafe fn set_error(error: *mut *mut c_char, source_error: &Error) {
+    if error.is_null() {
+        return;
+    }
+
+    // C strings end at the first NUL byte, so any NUL byte in the message is dropped.
+    let message = source_error.to_string().replace('\0', "");
+    *error = CString::new(message).unwrap().into_raw();
+}
+
+fn run(source: &str, options: &BfOptions) -> Result<(), Error> {
+    let tokenizer = Tokenizer::new(source).with_assertions(options.assertions);
+
+    let instructions = compiler::parse(tokenizer)?;
+    let instructions = if options.optimize {
+        compiler::optimize(
+            &instructions,
+            DEFAULT_MAX_UNROLL_ITERATIONS,
+            false,
+            options.clamp_left,
+            false,
+        )
+        .0
+    } else {
+        instructions
+    };
+
+    let code_gen_options = CodeGenOptions {
+        clamp_left: options.clamp_left,
+        ..CodeGenOptions::default()
+    };
+
+    interpreter::interpret_with_options(
+        &instructions,
+        &code_gen_options,
+        io::stdin().lock(),
+        io::stdout().lock(),
+    )?;
+
+    Ok(())
+}
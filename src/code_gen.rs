@@ -1,17 +1,39 @@
-use std::{ffi::OsStr, mem::size_of, path::Path};
+use std::{
+    cell::{Cell, RefCell},
+    collections::hash_map::DefaultHasher,
+    error::Error,
+    ffi::OsStr,
+    fmt::{Display, Formatter, Result as FmtResult},
+    hash::{Hash, Hasher},
+    iter,
+    mem::size_of,
+    path::Path,
+    slice,
+};
+
+use clap::ValueEnum;
 
-use crate::instruction::Instruction;
+use crate::{
+    analysis::{self, KnownCells},
+    instruction::{Instruction, InstructionKind},
+    tok::SourceLoc,
+};
 
 use inkwell::{
     basic_block::BasicBlock,
     builder::Builder,
     context::Context,
     module::{Linkage, Module},
+    targets::{TargetMachine, TargetTriple},
     types::{BasicMetadataTypeEnum, BasicType, IntType, PointerType, VoidType},
-    values::{FunctionValue, GlobalValue, PointerValue},
-    AddressSpace, IntPredicate,
+    values::{BasicValueEnum, FunctionValue, GlobalValue, IntValue, PointerValue},
+    AddressSpace, GlobalVisibility, IntPredicate,
 };
 
+/// The number of cells the tape starts with, before it grows to the next power of two whenever the
+/// pointer moves beyond it.
+pub(crate) const INITIAL_TAPE_LENGTH: usize = 256;
+
 struct Types<'a> {
     void_t: VoidType<'a>,
     bool_t: IntType<'a>,
@@ -37,7 +59,12 @@ impl<'a> Types<'a> {
         let size_t_t = Self::get_int_type::<libc::size_t>(context);
         let size_t_ptr_t = size_t_t.ptr_type(addr_space);
 
-        let file_ptr_t = context.opaque_struct_type("__sFILE").ptr_type(addr_space);
+        // The type is looked up first, as another program may have been compiled into the
+        // context already, and a second opaque type would be named `__sFILE.0`.
+        let file_ptr_t = context
+            .get_struct_type("__sFILE")
+            .unwrap_or_else(|| context.opaque_struct_type("__sFILE"))
+            .ptr_type(addr_space);
 
         Self {
             void_t,
@@ -63,33 +90,122 @@ impl<'a> Types<'a> {
     }
 }
 
+/// Whether `module` is compiled for an Apple platform, whose C library and object files differ
+/// from the other platforms. A module without a target triple is compiled for the host.
+fn targets_apple(module: &Module) -> bool {
+    let triple = module.get_triple();
+    let triple = if triple.as_str().to_bytes().is_empty() {
+        TargetMachine::get_default_triple()
+    } else {
+        triple
+    };
+    let triple = triple.as_str().to_string_lossy();
+
+    triple.contains("-apple-") || triple.contains("darwin") || triple.contains("macos")
+}
+
 struct Globals<'a> {
     stdout_ptr_v: GlobalValue<'a>,
     stderr_ptr_v: GlobalValue<'a>,
+    /// The buffer of `output_buffer_size` and the number of bytes in it, defined by the helpers.
+    output_buffer_ptr_v: GlobalValue<'a>,
+    output_length_v: GlobalValue<'a>,
     error_string_v: GlobalValue<'a>,
 }
 
 impl<'a> Globals<'a> {
-    fn new(context: &'a Context, module: &Module<'a>, types: &Types<'a>) -> Self {
-        let stdout_ptr_v = module.add_global(types.file_ptr_t, None, "__stdoutp");
-        stdout_ptr_v.set_alignment(8);
-        let stderr_ptr_v = module.add_global(types.file_ptr_t, None, "__stderrp");
-        stderr_ptr_v.set_alignment(8);
-
-        let error_string_v = Self::create_string(
+    fn new(
+        context: &'a Context,
+        module: &Module<'a>,
+        types: &Types<'a>,
+        options: &CodeGenOptions,
+    ) -> Self {
+        // The C library of macOS names the standard streams differently from glibc and musl.
+        let (stdout_name, stderr_name) = if targets_apple(module) {
+            ("__stdoutp", "__stderrp")
+        } else {
+            ("stdout", "stderr")
+        };
+
+        // All globals are shared with the programs compiled into the module before.
+        let stdout_ptr_v = module.get_global(stdout_name).unwrap_or_else(|| {
+            let stdout_ptr_v = module.add_global(types.file_ptr_t, None, stdout_name);
+            stdout_ptr_v.set_alignment(8);
+            stdout_ptr_v
+        });
+        let stderr_ptr_v = module.get_global(stderr_name).unwrap_or_else(|| {
+            let stderr_ptr_v = module.add_global(types.file_ptr_t, None, stderr_name);
+            stderr_ptr_v.set_alignment(8);
+            stderr_ptr_v
+        });
+        let output_buffer_ptr_v = module.get_global("bfOutputBuffer").unwrap_or_else(|| {
+            let output_buffer_ptr_v = module.add_global(types.char_ptr_t, None, "bfOutputBuffer");
+            output_buffer_ptr_v.set_alignment(8);
+            output_buffer_ptr_v
+        });
+        let output_length_v = module.get_global("bfOutputLength").unwrap_or_else(|| {
+            let output_length_v = module.add_global(types.size_t_t, None, "bfOutputLength");
+            output_length_v.set_alignment(8);
+            output_length_v
+        });
+
+        let error_string_v = Self::intern_string(
             "Error: Cannot move pointer to negative cell!\n",
-            "errorString",
             context,
             module,
         );
 
+        // Read by the helpers when growing the tape, 0 means unlimited. As there is only one
+        // helpers object per executable, the limit of the first program in a module applies to
+        // all of them.
+        if module.get_global("bfMaxTapeBytes").is_none() {
+            let max_tape_bytes = options.max_tape_bytes.unwrap_or(0) as u64;
+            let max_tape_bytes_v = module.add_global(types.size_t_t, None, "bfMaxTapeBytes");
+            max_tape_bytes_v.set_constant(true);
+            // Only the helpers linked with the module read the limit, so a shared library doesn't
+            // export it.
+            max_tape_bytes_v.set_visibility(GlobalVisibility::Hidden);
+            max_tape_bytes_v.set_initializer(&types.size_t_t.const_int(max_tape_bytes, false));
+        }
+
+        // Read by the helpers when allocating, growing and freeing the tape, the allocation of the
+        // first program in a module applies to all of them just like the limit.
+        if module.get_global("bfMapTape").is_none() {
+            let map_tape = (options.tape_allocation == TapeAllocation::Mmap) as u64;
+            let map_tape_v = module.add_global(types.char_t, None, "bfMapTape");
+            map_tape_v.set_constant(true);
+            map_tape_v.set_visibility(GlobalVisibility::Hidden);
+            map_tape_v.set_initializer(&types.char_t.const_int(map_tape, false));
+        }
+
         Self {
             stdout_ptr_v,
             stderr_ptr_v,
+            output_buffer_ptr_v,
+            output_length_v,
             error_string_v,
         }
     }
 
+    /// Returns the global holding the null-terminated string `value`, adding it to `module` if it
+    /// doesn't contain it yet.
+    ///
+    /// The name of the global is derived from a hash of `value`, so that every string is only
+    /// stored once, also when several programs are compiled into the module.
+    fn intern_string<'b>(
+        value: &str,
+        context: &'b Context,
+        module: &Module<'b>,
+    ) -> GlobalValue<'b> {
+        let mut hasher = DefaultHasher::new();
+        value.hash(&mut hasher);
+        let name = format!("string.{:016x}", hasher.finish());
+
+        module
+            .get_global(&name)
+            .unwrap_or_else(|| Self::create_string(value, &name, context, module))
+    }
+
     fn create_string<'b>(
         value: &str,
         name: &str,
@@ -109,27 +225,56 @@ impl<'a> Globals<'a> {
 }
 
 struct Functions<'a> {
-    calloc_f: FunctionValue<'a>,
+    allocate_cells_f: FunctionValue<'a>,
+    free_cells_f: FunctionValue<'a>,
     free_f: FunctionValue<'a>,
     fputs_f: FunctionValue<'a>,
+    fwrite_f: FunctionValue<'a>,
+    strcmp_f: FunctionValue<'a>,
     putchar_f: FunctionValue<'a>,
+    fputc_f: FunctionValue<'a>,
     fflush_f: FunctionValue<'a>,
+    open_output_f: FunctionValue<'a>,
+    allocate_output_buffer_f: FunctionValue<'a>,
+    flush_output_buffer_f: FunctionValue<'a>,
+    free_output_buffer_f: FunctionValue<'a>,
+    unbuffer_output_f: FunctionValue<'a>,
     move_right_f: FunctionValue<'a>,
+    reserve_cells_f: FunctionValue<'a>,
+    allocate_input_buffer_f: FunctionValue<'a>,
+    set_input_argument_f: FunctionValue<'a>,
     input_f: FunctionValue<'a>,
     move_right_until_zero_f: FunctionValue<'a>,
     move_left_until_zero_f: FunctionValue<'a>,
+    output_until_zero_f: FunctionValue<'a>,
     move_value_right_f: FunctionValue<'a>,
     move_value_left_f: FunctionValue<'a>,
+    clear_range_f: FunctionValue<'a>,
+    transfer_value_f: FunctionValue<'a>,
+    report_tape_usage_f: FunctionValue<'a>,
+    assertion_failed_f: FunctionValue<'a>,
+    out_of_bounds_f: FunctionValue<'a>,
     main_f: FunctionValue<'a>,
 }
 
 impl<'a> Functions<'a> {
-    fn new(module: &Module<'a>, types: &Types<'a>) -> Self {
-        let calloc_f = Self::declare_function(
+    fn new(
+        module: &Module<'a>,
+        types: &Types<'a>,
+        entry_name: &str,
+        options: &CodeGenOptions,
+    ) -> Self {
+        let allocate_cells_f = Self::declare_function(
             &types.char_ptr_t,
-            &[types.size_t_t.into(), types.size_t_t.into()],
-            "calloc",
+            &[types.size_t_t.into()],
+            "allocateCells",
+            module,
+        );
+        let free_cells_f = Self::declare_void_function(
+            &[types.char_ptr_t.into(), types.size_t_t.into()],
+            "freeCells",
             module,
+            types,
         );
         let free_f = Self::declare_void_function(&[types.char_ptr_t.into()], "free", module, types);
         let fputs_f = Self::declare_function(
@@ -138,10 +283,55 @@ impl<'a> Functions<'a> {
             "fputs",
             module,
         );
+        let fwrite_f = Self::declare_function(
+            &types.size_t_t,
+            &[
+                types.char_ptr_t.into(),
+                types.size_t_t.into(),
+                types.size_t_t.into(),
+                types.file_ptr_t.into(),
+            ],
+            "fwrite",
+            module,
+        );
+        let strcmp_f = Self::declare_function(
+            &types.int_t,
+            &[types.char_ptr_t.into(), types.char_ptr_t.into()],
+            "strcmp",
+            module,
+        );
         let putchar_f =
             Self::declare_function(&types.int_t, &[types.int_t.into()], "putchar", module);
+        let fputc_f = Self::declare_function(
+            &types.int_t,
+            &[types.int_t.into(), types.file_ptr_t.into()],
+            "fputc",
+            module,
+        );
         let fflush_f =
             Self::declare_function(&types.int_t, &[types.file_ptr_t.into()], "fflush", module);
+        let open_output_f = Self::declare_function(
+            &types.file_ptr_t,
+            &[types.int_t.into()],
+            "openOutput",
+            module,
+        );
+        let allocate_output_buffer_f = Self::declare_void_function(
+            &[types.size_t_t.into(), types.file_ptr_t.into()],
+            "allocateOutputBuffer",
+            module,
+            types,
+        );
+        let flush_output_buffer_f =
+            Self::declare_void_function(&[], "flushOutputBuffer", module, types);
+        let free_output_buffer_f =
+            Self::declare_void_function(&[], "freeOutputBuffer", module, types);
+        let unbuffer_output_f = Self::declare_void_function(
+            &[types.file_ptr_t.into()],
+            "unbufferOutput",
+            module,
+            types,
+        );
 
         let move_right_f = Self::declare_void_function(
             &[
@@ -154,6 +344,28 @@ impl<'a> Functions<'a> {
             module,
             types,
         );
+        let reserve_cells_f = Self::declare_void_function(
+            &[
+                types.char_ptr_ptr_t.into(),
+                types.size_t_ptr_t.into(),
+                types.size_t_t.into(),
+            ],
+            "reserveCells",
+            module,
+            types,
+        );
+        let allocate_input_buffer_f = Self::declare_void_function(
+            &[types.char_ptr_ptr_t.into(), types.size_t_t.into()],
+            "allocateInputBuffer",
+            module,
+            types,
+        );
+        let set_input_argument_f = Self::declare_void_function(
+            &[types.int_t.into(), types.char_ptr_ptr_t.into()],
+            "setInputArgument",
+            module,
+            types,
+        );
         let input_f = Self::declare_void_function(
             &[
                 types.char_ptr_t.into(),
@@ -185,6 +397,18 @@ impl<'a> Functions<'a> {
             "moveLeftUntilZero",
             module,
         );
+        let output_until_zero_f = Self::declare_void_function(
+            &[
+                types.char_ptr_ptr_t.into(),
+                types.size_t_ptr_t.into(),
+                types.size_t_ptr_t.into(),
+                types.size_t_t.into(),
+                types.file_ptr_t.into(),
+            ],
+            "outputUntilZero",
+            module,
+            types,
+        );
 
         let move_value_right_f = Self::declare_void_function(
             &[
@@ -209,20 +433,114 @@ impl<'a> Functions<'a> {
             module,
         );
 
-        let main_f = Self::declare_function(&types.int_t, &[], "main", module);
+        let clear_range_f = Self::declare_function(
+            &types.bool_t,
+            &[
+                types.char_ptr_ptr_t.into(),
+                types.size_t_ptr_t.into(),
+                types.size_t_t.into(),
+                types.size_t_t.into(),
+                types.size_t_t.into(),
+            ],
+            "clearRange",
+            module,
+        );
+
+        let transfer_value_f = Self::declare_function(
+            &types.bool_t,
+            &[
+                types.char_ptr_ptr_t.into(),
+                types.size_t_ptr_t.into(),
+                types.size_t_t.into(),
+                types.size_t_t.into(),
+            ],
+            "transferValue",
+            module,
+        );
+
+        let report_tape_usage_f =
+            Self::declare_void_function(&[types.size_t_t.into()], "reportTapeUsage", module, types);
+        let assertion_failed_f = Self::declare_void_function(
+            &[
+                types.size_t_t.into(),
+                types.char_t.into(),
+                types.char_t.into(),
+            ],
+            "assertionFailed",
+            module,
+            types,
+        );
+        let out_of_bounds_f = Self::declare_void_function(
+            &[
+                types.char_ptr_t.into(),
+                types.size_t_t.into(),
+                types.size_t_t.into(),
+            ],
+            "outOfBounds",
+            module,
+            types,
+        );
+
+        // The entry is exported, so that other programs can call it when the module is linked
+        // into a shared library.
+        let takes_arguments = options.print_source_option || options.argv_input;
+        let main_param_types = if takes_arguments {
+            vec![types.int_t.into(), types.char_ptr_ptr_t.into()]
+        } else {
+            Vec::new()
+        };
+        let main_f = module.add_function(
+            entry_name,
+            types.int_t.fn_type(&main_param_types, false),
+            Some(Linkage::External),
+        );
+        main_f
+            .as_global_value()
+            .set_visibility(GlobalVisibility::Default);
+
+        if takes_arguments {
+            main_f
+                .get_nth_param(0)
+                .unwrap()
+                .into_int_value()
+                .set_name("argc");
+            main_f
+                .get_nth_param(1)
+                .unwrap()
+                .into_pointer_value()
+                .set_name("argv");
+        }
 
         Self {
-            calloc_f,
+            allocate_cells_f,
+            free_cells_f,
             free_f,
             fputs_f,
+            fwrite_f,
+            strcmp_f,
             putchar_f,
+            fputc_f,
             fflush_f,
+            open_output_f,
+            allocate_output_buffer_f,
+            flush_output_buffer_f,
+            free_output_buffer_f,
+            unbuffer_output_f,
             move_right_f,
+            reserve_cells_f,
+            allocate_input_buffer_f,
+            set_input_argument_f,
             input_f,
             move_right_until_zero_f,
             move_left_until_zero_f,
+            output_until_zero_f,
             move_value_right_f,
             move_value_left_f,
+            clear_range_f,
+            transfer_value_f,
+            report_tape_usage_f,
+            assertion_failed_f,
+            out_of_bounds_f,
             main_f,
         }
     }
@@ -236,8 +554,11 @@ impl<'a> Functions<'a> {
     where
         Type: BasicType<'a>,
     {
-        let function_type = return_type.fn_type(param_types, false);
-        module.add_function(name, function_type, None)
+        // Declarations are shared with the programs compiled into the module before.
+        module.get_function(name).unwrap_or_else(|| {
+            let function_type = return_type.fn_type(param_types, false);
+            module.add_function(name, function_type, None)
+        })
     }
 
     fn declare_void_function(
@@ -246,13 +567,143 @@ impl<'a> Functions<'a> {
         module: &Module<'a>,
         types: &Types<'a>,
     ) -> FunctionValue<'a> {
-        let function_type = types.void_t.fn_type(param_types, false);
-        module.add_function(name, function_type, None)
+        module.get_function(name).unwrap_or_else(|| {
+            let function_type = types.void_t.fn_type(param_types, false);
+            module.add_function(name, function_type, None)
+        })
+    }
+}
+
+/// How the helpers allocate the tape.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum TapeAllocation {
+    /// With `calloc`, growing it with `realloc`.
+    Calloc,
+    /// With anonymous `mmap` mappings, whose pages are only zeroed and committed when they are first
+    /// touched. Growing the tape maps a new region and copies the cells over.
+    Mmap,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct CodeGenOptions {
+    /// Whether the tape and input buffer are freed before `main` returns.
+    pub free_memory: bool,
+    pub tape_allocation: TapeAllocation,
+    /// The size of the buffer the program collects its output in, allocated at program start.
+    /// `Some(0)` makes stdout unbuffered instead. If this is `None`, stdout is flushed after every
+    /// output.
+    pub output_buffer_size: Option<usize>,
+    /// The size the input buffer is allocated with at program start, so that input with lines up
+    /// to this length doesn't have to grow it. If this is `None`, it is allocated by the first
+    /// input instead.
+    pub input_buffer_size: Option<usize>,
+    /// The maximum size the tape may grow to in bytes, enforced by the helpers.
+    pub max_tape_bytes: Option<usize>,
+    /// Whether a `MoveLeft` past the first cell stops at it instead of aborting the program. Scans
+    /// and value moves to the left still abort, which is why the program has to be optimized with
+    /// `Optimizer::new_with_clamp_left`, so that it keeps such loops as they are.
+    pub clamp_left: bool,
+    /// Whether the program reports its peak tape usage on stderr before exiting.
+    pub report_tape_usage: bool,
+    /// Whether output is written with `fputc(c, stdout)` instead of `putchar(c)`.
+    pub use_fputc: bool,
+    /// The file descriptor output is written to with `fputc` instead of stdout, opened with
+    /// `fdopen` by the helpers. The buffering of `output_buffer_size` applies to it instead.
+    pub output_fd: Option<i32>,
+    /// Whether `main` takes `argc` and `argv`, and prints the source embedded with
+    /// `CodeGen::embed_source` instead of running the program if its first argument is
+    /// `--print-source`.
+    pub print_source_option: bool,
+    /// Whether `main` takes `argc` and `argv`, and `,` reads the bytes of its first argument
+    /// instead of stdin. After them, or without an argument, it reads 0 like at the end of stdin.
+    pub argv_input: bool,
+    /// Whether every access of the current cell is checked to be on the tape first, reporting the
+    /// instruction and exiting otherwise. This is only meant for debugging the generated code, as
+    /// it slows the program down considerably.
+    pub debug_bounds: bool,
+}
+
+impl Default for CodeGenOptions {
+    fn default() -> Self {
+        Self {
+            free_memory: true,
+            tape_allocation: TapeAllocation::Calloc,
+            output_buffer_size: None,
+            input_buffer_size: None,
+            max_tape_bytes: None,
+            clamp_left: false,
+            report_tape_usage: false,
+            use_fputc: false,
+            output_fd: None,
+            print_source_option: false,
+            argv_input: false,
+            debug_bounds: false,
+        }
+    }
+}
+
+/// The basic blocks generated for a top-level instruction, an entry of the map returned by
+/// `CodeGen::generate_module_with_map`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InstructionBlocks {
+    /// The index of the instruction among the top-level instructions.
+    pub index: usize,
+    /// The instruction and its location, see `Instruction::summary`.
+    pub summary: String,
+    /// The block the code of the instruction starts in, followed by the blocks created for it.
+    pub blocks: Vec<String>,
+}
+
+/// Where the code of a top-level instruction starts, recorded while generating it.
+struct InstructionStart {
+    summary: String,
+    block: String,
+    /// The position of the first block created for the instruction in the function.
+    first_new_block: usize,
+}
+
+/// The reasons code generation can fail, mostly invariants that the optimizer guarantees, but that
+/// instructions built by hand might violate:
+///
+/// - The body of a `WithMultiplier` only consists of moves, increments and decrements.
+/// - The body of a `WithMultiplier` returns the pointer to the cell it started at, as the
+///   multiplier cell is cleared afterwards.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CodeGenError {
+    InvalidMultiplierBody(SourceLoc),
+    UnbalancedMultiplierBody(SourceLoc),
+    /// The module passed to `CodeGen::with_module` already contains a function with the entry name.
+    DuplicateEntry(String),
+    /// LLVM rejected the generated module, which indicates a bug in the code generator.
+    Verification(String),
+}
+
+impl Display for CodeGenError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        match self {
+            Self::InvalidMultiplierBody(loc) => f.write_fmt(format_args!(
+                "multiplier loop at {} may only contain moves, increments and decrements",
+                loc
+            )),
+            Self::UnbalancedMultiplierBody(loc) => f.write_fmt(format_args!(
+                "multiplier loop at {} doesn't return to the cell it started at",
+                loc
+            )),
+            Self::DuplicateEntry(name) => f.write_fmt(format_args!(
+                "the module already contains a function {}",
+                name
+            )),
+            Self::Verification(message) => {
+                f.write_fmt(format_args!("invalid module generated: {}", message))
+            }
+        }
     }
 }
 
+impl Error for CodeGenError {}
+
 pub struct CodeGen<'a> {
-    instructions: Vec<Instruction>,
+    options: CodeGenOptions,
     context: &'a Context,
     module: Module<'a>,
     builder: Builder<'a>,
@@ -260,22 +711,94 @@ pub struct CodeGen<'a> {
     globals: Globals<'a>,
     functions: Functions<'a>,
     main_error_block: BasicBlock<'a>,
+    /// The number appended to the names of the next blocks, so that they are unique.
+    next_block_number: Cell<usize>,
+    /// The cells whose values are known at the instruction that is generated next.
+    known_cells: RefCell<KnownCells>,
     cells_alloca: PointerValue<'a>,
     cells_length_alloca: PointerValue<'a>,
     current_cell_alloca: PointerValue<'a>,
     input_buffer_alloca: PointerValue<'a>,
     multiplier_alloca: PointerValue<'a>,
+    /// The stream opened for `output_fd`, if it is set.
+    output_stream_alloca: PointerValue<'a>,
 }
 
 impl<'a> CodeGen<'a> {
-    pub fn new(instructions: Vec<Instruction>, input_file: &Path, context: &'a Context) -> Self {
+    pub fn new(input_file: &Path, options: CodeGenOptions, context: &'a Context) -> Self {
+        Self::with_entry_name(input_file, "main", options, context)
+    }
+
+    /// Creates a code generator like `new`, but names the module and its source file `name` instead
+    /// of deriving them from the path of an input file, e.g. for a program that only exists in
+    /// memory.
+    pub fn with_name(name: &str, options: CodeGenOptions, context: &'a Context) -> Self {
+        let module = context.create_module(name);
+        module.set_source_file_name(name);
+
+        Self::new_in_module(module, "main", options, context)
+    }
+
+    /// Creates a code generator like `new`, but names the function that executes the program
+    /// `entry_name` instead of `main`, e.g. to call it from other programs when it is linked into a
+    /// shared library.
+    pub fn with_entry_name(
+        input_file: &Path,
+        entry_name: &str,
+        options: CodeGenOptions,
+        context: &'a Context,
+    ) -> Self {
+        let triple = TargetMachine::get_default_triple();
+
+        Self::with_target(input_file, entry_name, &triple, options, context)
+    }
+
+    /// Creates a code generator like `with_entry_name`, but for a program that is compiled for
+    /// `triple` instead of the host, whose C library decides how the standard streams are named.
+    pub fn with_target(
+        input_file: &Path,
+        entry_name: &str,
+        triple: &TargetTriple,
+        options: CodeGenOptions,
+        context: &'a Context,
+    ) -> Self {
         let module = context.create_module(input_file.file_stem().and_then(OsStr::to_str).unwrap());
         module.set_source_file_name(input_file.file_name().and_then(OsStr::to_str).unwrap());
+        module.set_triple(triple);
+
+        Self::new_in_module(module, entry_name, options, context)
+    }
+
+    /// Creates a code generator that adds the program to an existing `module` as a function named
+    /// `entry_name`, which takes no arguments and returns the exit code like `main`.
+    ///
+    /// Several programs can be compiled into one module this way by passing it on with
+    /// `into_module`, as long as their entry names differ. They share the declarations of the
+    /// helpers and their globals, so the tape limit of the first program applies to all of them.
+    pub fn with_module(
+        module: Module<'a>,
+        entry_name: &str,
+        options: CodeGenOptions,
+        context: &'a Context,
+    ) -> Result<Self, CodeGenError> {
+        if module.get_function(entry_name).is_some() {
+            return Err(CodeGenError::DuplicateEntry(entry_name.to_owned()));
+        }
+
+        Ok(Self::new_in_module(module, entry_name, options, context))
+    }
+
+    fn new_in_module(
+        module: Module<'a>,
+        entry_name: &str,
+        options: CodeGenOptions,
+        context: &'a Context,
+    ) -> Self {
         let builder = context.create_builder();
 
         let types = Types::new(context);
-        let globals = Globals::new(context, &module, &types);
-        let functions = Functions::new(&module, &types);
+        let globals = Globals::new(context, &module, &types, &options);
+        let functions = Functions::new(&module, &types, entry_name, &options);
 
         let main_entry_block = context.append_basic_block(functions.main_f, "entry");
         let main_error_block = context.append_basic_block(functions.main_f, "error");
@@ -287,9 +810,10 @@ impl<'a> CodeGen<'a> {
         let current_cell_alloca = builder.build_alloca(types.size_t_t, "currentCell");
         let input_buffer_alloca = builder.build_alloca(types.char_ptr_t, "inputBuffer");
         let multiplier_alloca = builder.build_alloca(types.char_t, "multiplier");
+        let output_stream_alloca = builder.build_alloca(types.file_ptr_t, "outputStream");
 
         Self {
-            instructions,
+            options,
             context,
             module,
             builder,
@@ -297,155 +821,1023 @@ impl<'a> CodeGen<'a> {
             globals,
             functions,
             main_error_block,
+            next_block_number: Cell::new(0),
+            known_cells: RefCell::new(KnownCells::new(options.clamp_left)),
             cells_alloca,
             cells_length_alloca,
             current_cell_alloca,
             input_buffer_alloca,
             multiplier_alloca,
+            output_stream_alloca,
         }
     }
 
-    pub fn generate_module(&self) -> &Module<'a> {
-        let args = &[
-            self.types.size_t_t.const_int(256, false).into(),
-            self.types.size_t_t.const_int(1, false).into(),
-        ];
-        let cells = self
+    /// Generates a verified module whose `main` only executes `instruction`, for targeted tests
+    /// of the lowering of a single instruction. Unlike `generate_module`, the tape always starts
+    /// with the initial length, so that the instruction is lowered like anywhere in a program.
+    #[cfg(test)]
+    pub(crate) fn generate_instruction_module(
+        instruction: Instruction,
+        context: &'a Context,
+    ) -> Module<'a> {
+        let code_gen = Self::with_name("instruction.bf", CodeGenOptions::default(), context);
+        code_gen.generate_prologue(INITIAL_TAPE_LENGTH);
+        code_gen.generate_instructions(&[instruction], false, false, None);
+        code_gen.generate_epilogue().unwrap();
+
+        code_gen.module
+    }
+
+    /// Embeds `source` into the module as the constant `bfSource`, which is placed in its own
+    /// section, `__TEXT,__bf_source` for macOS and `.bf_source` elsewhere, so that it can be
+    /// extracted from the object file or the executable later.
+    ///
+    /// The program only reads it if `print_source_option` is set, which makes `main` check its
+    /// arguments first. This has to be called before the program is generated then.
+    ///
+    /// If several programs are compiled into one module, every call adds another global, named
+    /// `bfSource.1` and so on.
+    pub fn embed_source(&self, source: &[u8]) {
+        let section = if targets_apple(&self.module) {
+            "__TEXT,__bf_source"
+        } else {
+            ".bf_source"
+        };
+
+        // Unlike the private strings, the global is external, so that neither the LLVM passes nor
+        // the linker remove it as unused.
+        let source_constant = self.context.const_string(source, false);
+        let source_v = self
+            .module
+            .add_global(source_constant.get_type(), None, "bfSource");
+        source_v.set_constant(true);
+        source_v.set_initializer(&source_constant);
+        source_v.set_section(Some(section));
+        source_v.set_alignment(1);
+
+        if self.options.print_source_option {
+            self.generate_print_source_option(source_v, source.len());
+        }
+    }
+
+    /// Generates the check at the start of `main` whether its first argument is `--print-source`,
+    /// in which case it writes the `length` bytes of `source_v` to stdout and returns 0 without
+    /// running the program.
+    fn generate_print_source_option(&self, source_v: GlobalValue<'a>, length: usize) {
+        let [check_block, print_block, run_block] =
+            self.prepend_blocks(["checkArgument", "printSource", "run"]);
+
+        let argc = self
+            .functions
+            .main_f
+            .get_nth_param(0)
+            .unwrap()
+            .into_int_value();
+        let argv = self
+            .functions
+            .main_f
+            .get_nth_param(1)
+            .unwrap()
+            .into_pointer_value();
+
+        let has_argument = self.builder.build_int_compare(
+            IntPredicate::SGT,
+            argc,
+            self.types.int_t.const_int(1, false),
+            "hasArgument",
+        );
+        self.builder
+            .build_conditional_branch(has_argument, check_block, run_block);
+
+        self.builder.position_at_end(check_block);
+
+        let argument_ptr = unsafe {
+            self.builder.build_gep(
+                argv,
+                &[self.types.size_t_t.const_int(1, false)],
+                "argumentPtr",
+            )
+        };
+        let argument = self.builder.build_load(argument_ptr, "load");
+        let option_string_v = Globals::intern_string("--print-source", self.context, &self.module);
+        let option_string =
+            self.builder
+                .build_bitcast(option_string_v, self.types.char_ptr_t, "optionString");
+        let comparison = self
             .builder
-            .build_call(self.functions.calloc_f, args, "initialCells")
+            .build_call(
+                self.functions.strcmp_f,
+                &[argument.into(), option_string.into()],
+                "comparison",
+            )
             .try_as_basic_value()
             .left()
-            .unwrap();
-
-        self.builder.build_store(self.cells_alloca, cells);
-        self.builder.build_store(
-            self.cells_length_alloca,
-            self.types.size_t_t.const_int(256, false),
+            .unwrap()
+            .into_int_value();
+        let is_option = self.builder.build_int_compare(
+            IntPredicate::EQ,
+            comparison,
+            self.types.int_t.const_zero(),
+            "isOption",
         );
         self.builder
-            .build_store(self.current_cell_alloca, self.types.size_t_t.const_zero());
-        self.builder
-            .build_store(self.input_buffer_alloca, self.types.char_ptr_t.const_null());
-
-        self.generate_instructions(&self.instructions, false);
-
-        let return_block = self
-            .context
-            .append_basic_block(self.functions.main_f, "return");
-        self.builder.build_unconditional_branch(return_block);
-
-        let last_block = self.builder.get_insert_block().unwrap();
+            .build_conditional_branch(is_option, print_block, run_block);
 
-        self.builder.position_at_end(self.main_error_block);
+        self.builder.position_at_end(print_block);
 
-        let casted_error_string = self.builder.build_bitcast(
-            self.globals.error_string_v,
-            self.types.char_ptr_t,
-            "errorString",
-        );
-        let stderr_v = self
+        let source = self
             .builder
-            .build_load(self.globals.stderr_ptr_v.as_pointer_value(), "load");
+            .build_bitcast(source_v, self.types.char_ptr_t, "source");
+        let stdout_v = self
+            .builder
+            .build_load(self.globals.stdout_ptr_v.as_pointer_value(), "load");
         self.builder.build_call(
-            self.functions.fputs_f,
-            &[casted_error_string.into(), stderr_v.into()],
+            self.functions.fwrite_f,
+            &[
+                source.into(),
+                self.types.size_t_t.const_int(1, false).into(),
+                self.types.size_t_t.const_int(length as u64, false).into(),
+                stdout_v.into(),
+            ],
             "",
         );
+        self.builder
+            .build_return(Some(&self.types.int_t.const_zero()));
 
-        self.builder.build_unconditional_branch(return_block);
-
-        self.builder.position_at_end(return_block);
+        self.builder.position_at_end(run_block);
+    }
 
-        let phi = self.builder.build_phi(self.types.int_t, "returnValue");
-        phi.add_incoming(&[
-            (&self.types.int_t.const_int(0, false), last_block),
-            (&self.types.int_t.const_int(1, false), self.main_error_block),
-        ]);
+    /// Returns the module the program was generated into.
+    pub fn into_module(self) -> Module<'a> {
+        self.module
+    }
 
-        let cells = self.builder.build_load(self.cells_alloca, "load");
-        self.builder
-            .build_call(self.functions.free_f, &[cells.into()], "");
+    /// Generates a verified module whose `main` executes `instructions`. If they have no effect at
+    /// all, `main` only returns 0, without allocating a tape.
+    pub fn generate_module(
+        &self,
+        instructions: &[Instruction],
+    ) -> Result<&Module<'a>, CodeGenError> {
+        Self::validate(instructions)?;
 
-        let input_buffer = self.builder.build_load(self.input_buffer_alloca, "load");
-        self.builder
-            .build_call(self.functions.free_f, &[input_buffer.into()], "");
+        if self.has_no_effect(instructions) {
+            return self.generate_empty_main();
+        }
 
-        self.builder.build_return(Some(&phi.as_basic_value()));
+        let tape_length = self.loop_free_tape_length(instructions);
+        self.generate_prologue(tape_length.unwrap_or(INITIAL_TAPE_LENGTH));
+        self.generate_instructions(instructions, false, tape_length.is_some(), None);
+        self.generate_epilogue()
+    }
 
-        if !self.functions.main_f.verify(true) {
-            panic!("Could not verify main function")
+    /// Generates a verified module like `generate_module`, and returns which basic blocks were
+    /// generated for every top-level instruction. The blocks are named as before any LLVM passes,
+    /// which may merge or remove them.
+    pub fn generate_module_with_map(
+        &self,
+        instructions: &[Instruction],
+    ) -> Result<(&Module<'a>, Vec<InstructionBlocks>), CodeGenError> {
+        Self::validate(instructions)?;
+
+        if self.has_no_effect(instructions) {
+            // No code is generated for any of the instructions, so they all start in the block
+            // that `main` returns from.
+            let block = self.builder.get_insert_block().unwrap();
+            let block = block.get_name().to_string_lossy();
+            let map = instructions
+                .iter()
+                .enumerate()
+                .map(|(index, instruction)| InstructionBlocks {
+                    index,
+                    summary: instruction.summary(),
+                    blocks: vec![block.clone().into_owned()],
+                })
+                .collect();
+
+            return Ok((self.generate_empty_main()?, map));
         }
 
-        self.module.verify().unwrap();
+        let tape_length = self.loop_free_tape_length(instructions);
+        self.generate_prologue(tape_length.unwrap_or(INITIAL_TAPE_LENGTH));
+
+        let mut starts = Vec::new();
+        self.generate_instructions(
+            instructions,
+            false,
+            tape_length.is_some(),
+            Some(&mut starts),
+        );
+
+        // New blocks are always inserted in front of the error block, so the blocks of every
+        // instruction directly follow the ones of the instruction before it.
+        let blocks = self.functions.main_f.get_basic_blocks();
+        let error_block = blocks.len() - 1;
+
+        let map = starts
+            .iter()
+            .enumerate()
+            .map(|(index, start)| {
+                let end = starts
+                    .get(index + 1)
+                    .map_or(error_block, |next| next.first_new_block);
+                let new_blocks = blocks[start.first_new_block..end]
+                    .iter()
+                    .map(|block| block.get_name().to_string_lossy().into_owned());
+
+                InstructionBlocks {
+                    index,
+                    summary: start.summary.clone(),
+                    blocks: iter::once(start.block.clone()).chain(new_blocks).collect(),
+                }
+            })
+            .collect();
 
-        &self.module
+        Ok((self.generate_epilogue()?, map))
     }
 
-    fn generate_instructions(&self, instructions: &[Instruction], has_multiplier: bool) {
-        for instruction in instructions.iter() {
-            self.generate_instruction(instruction, has_multiplier);
+    /// Generates a verified module like `generate_module`, but validates and generates every
+    /// top-level instruction as soon as `instructions` yields it, so that the program is never
+    /// held in memory as a whole. Only loops are buffered, as they are single instructions.
+    pub fn generate_module_streaming(
+        &self,
+        instructions: impl IntoIterator<Item = Instruction>,
+    ) -> Result<&Module<'a>, CodeGenError> {
+        self.generate_prologue(INITIAL_TAPE_LENGTH);
+
+        for instruction in instructions {
+            let instruction = slice::from_ref(&instruction);
+
+            Self::validate(instruction)?;
+            self.generate_instructions(instruction, false, false, None);
         }
+
+        self.generate_epilogue()
+    }
+
+    /// Returns whether `instructions` have no effect at all, so that the program doesn't need a
+    /// tape. This is only the case if they neither input nor output anything, nor touch or move to
+    /// any cell, which leaves only `Nop`s, and the tape usage isn't reported either.
+    fn has_no_effect(&self, instructions: &[Instruction]) -> bool {
+        !self.options.report_tape_usage
+            && instructions
+                .iter()
+                .all(|instruction| matches!(instruction.kind, InstructionKind::Nop))
+    }
+
+    /// Returns the exact length of the tape that `instructions` needs, if they don't contain any
+    /// loops and never move left of the first cell, i.e. form a single straight-line region from
+    /// the start of the tape. Such programs get a tape of exactly this length up front, so that none
+    /// of their moves have to check the tape length or for negative cells.
+    ///
+    /// Returns `None` for all other programs and if the tape would exceed `max_tape_bytes`, in which
+    /// case the tape has to grow, so that the program fails when it reaches the limit.
+    fn loop_free_tape_length(&self, instructions: &[Instruction]) -> Option<usize> {
+        let region = analysis::straight_line_region(instructions);
+        let tape_length = region.reach + 1;
+        let max_tape_bytes = self.options.max_tape_bytes.unwrap_or(usize::MAX);
+
+        (region.length == instructions.len() && tape_length <= max_tape_bytes)
+            .then_some(tape_length)
     }
 
-    fn generate_instruction(&self, instruction: &Instruction, has_multiplier: bool) {
-        match instruction {
-            Instruction::MoveRight { amount } => {
+    /// Allocates a tape of `tape_length` cells and sets up the output stream at the start of `main`.
+    fn generate_prologue(&self, tape_length: usize) {
+        let tape_length = self.types.size_t_t.const_int(tape_length as u64, false);
+
+        let cells = self
+            .builder
+            .build_call(
+                self.functions.allocate_cells_f,
+                &[tape_length.into()],
+                "initialCells",
+            )
+            .try_as_basic_value()
+            .left()
+            .unwrap();
+
+        self.builder.build_store(self.cells_alloca, cells);
+        self.builder
+            .build_store(self.cells_length_alloca, tape_length);
+        self.builder
+            .build_store(self.current_cell_alloca, self.types.size_t_t.const_zero());
+
+        match self.options.input_buffer_size {
+            Some(input_buffer_size) => {
                 self.builder.build_call(
-                    self.functions.move_right_f,
+                    self.functions.allocate_input_buffer_f,
                     &[
-                        self.cells_alloca.into(),
-                        self.cells_length_alloca.into(),
-                        self.current_cell_alloca.into(),
-                        self.types.size_t_t.const_int(*amount as u64, false).into(),
+                        self.input_buffer_alloca.into(),
+                        self.types
+                            .size_t_t
+                            .const_int(input_buffer_size as u64, false)
+                            .into(),
                     ],
                     "",
                 );
             }
-            Instruction::MoveLeft { amount } => {
-                let current_cell = self
-                    .builder
-                    .build_load(self.current_cell_alloca, "load")
-                    .into_int_value();
+            None => {
+                self.builder
+                    .build_store(self.input_buffer_alloca, self.types.char_ptr_t.const_null());
+            }
+        }
 
-                let current_cell = self.builder.build_int_sub(
-                    current_cell,
-                    self.types.size_t_t.const_int(*amount as u64, false),
-                    "decrementedCurrentCell",
-                );
+        if self.options.argv_input {
+            let argc = self.functions.main_f.get_nth_param(0).unwrap();
+            let argv = self.functions.main_f.get_nth_param(1).unwrap();
 
-                let return_with_error = self.builder.build_int_compare(
-                    IntPredicate::SLT,
-                    current_cell,
-                    self.types.size_t_t.const_zero(),
-                    "returnWithError",
-                );
+            self.builder.build_call(
+                self.functions.set_input_argument_f,
+                &[argc.into(), argv.into()],
+                "",
+            );
+        }
 
-                let move_left_block = self
-                    .context
-                    .prepend_basic_block(self.main_error_block, "moveLeft");
+        if let Some(output_fd) = self.options.output_fd {
+            let output_stream = self
+                .builder
+                .build_call(
+                    self.functions.open_output_f,
+                    &[self.types.int_t.const_int(output_fd as u64, false).into()],
+                    "outputStream",
+                )
+                .try_as_basic_value()
+                .left()
+                .unwrap();
+
+            self.builder
+                .build_store(self.output_stream_alloca, output_stream);
+        }
 
-                self.builder.build_conditional_branch(
-                    return_with_error,
-                    self.main_error_block,
-                    move_left_block,
-                );
-                self.builder.position_at_end(move_left_block);
+        match self.options.output_buffer_size {
+            // The mode is passed to `setvbuf` by the helper, as its value depends on the C library
+            // of the target.
+            Some(0) => {
+                let output_stream = self.build_load_output_stream();
 
-                self.builder
-                    .build_store(self.current_cell_alloca, current_cell);
+                self.builder.build_call(
+                    self.functions.unbuffer_output_f,
+                    &[output_stream.into()],
+                    "",
+                );
             }
-            Instruction::Increment { amount } | Instruction::Decrement { amount } => {
-                let cells = self
-                    .builder
-                    .build_load(self.cells_alloca, "load")
-                    .into_pointer_value();
-                let current_cell = self
-                    .builder
-                    .build_load(self.current_cell_alloca, "load")
-                    .into_int_value();
+            // The program fills the buffer itself instead of the stream, see
+            // `generate_buffered_output`.
+            Some(output_buffer_size) => {
+                let output_stream = self.build_load_output_stream();
 
-                let current_cell_ptr = unsafe {
-                    self.builder
+                self.builder.build_call(
+                    self.functions.allocate_output_buffer_f,
+                    &[
+                        self.types
+                            .size_t_t
+                            .const_int(output_buffer_size as u64, false)
+                            .into(),
+                        output_stream.into(),
+                    ],
+                    "",
+                );
+            }
+            None => {}
+        }
+    }
+
+    /// Frees the tape, returns from `main` and verifies the finished module.
+    fn generate_epilogue(&self) -> Result<&Module<'a>, CodeGenError> {
+        let return_block = self
+            .context
+            .append_basic_block(self.functions.main_f, "return");
+        self.builder.build_unconditional_branch(return_block);
+
+        let last_block = self.builder.get_insert_block().unwrap();
+
+        // Programs that never move left, e.g. empty ones, never branch to the error block, so it is
+        // removed instead of being left without predecessors.
+        let has_error_block = self.main_error_block.get_first_use().is_some();
+
+        if has_error_block {
+            self.builder.position_at_end(self.main_error_block);
+
+            let casted_error_string = self.builder.build_bitcast(
+                self.globals.error_string_v,
+                self.types.char_ptr_t,
+                "errorString",
+            );
+            let stderr_v = self
+                .builder
+                .build_load(self.globals.stderr_ptr_v.as_pointer_value(), "load");
+            self.builder.build_call(
+                self.functions.fputs_f,
+                &[casted_error_string.into(), stderr_v.into()],
+                "",
+            );
+
+            self.builder.build_unconditional_branch(return_block);
+        } else {
+            unsafe { self.main_error_block.delete() }.unwrap();
+        }
+
+        self.builder.position_at_end(return_block);
+
+        let phi = self.builder.build_phi(self.types.int_t, "returnValue");
+        phi.add_incoming(&[(&self.types.int_t.const_int(0, false), last_block)]);
+
+        if has_error_block {
+            phi.add_incoming(&[(&self.types.int_t.const_int(1, false), self.main_error_block)]);
+        }
+
+        // Programs compiled with `--shared` return without exiting, so the buffer has to be written
+        // before returning.
+        if self.has_output_buffer() {
+            self.builder
+                .build_call(self.functions.flush_output_buffer_f, &[], "");
+        }
+
+        if self.options.report_tape_usage {
+            let cells_length = self.builder.build_load(self.cells_length_alloca, "load");
+            self.builder.build_call(
+                self.functions.report_tape_usage_f,
+                &[cells_length.into()],
+                "",
+            );
+        }
+
+        if self.options.free_memory {
+            let cells = self.builder.build_load(self.cells_alloca, "load");
+            let cells_length = self.builder.build_load(self.cells_length_alloca, "load");
+            self.builder.build_call(
+                self.functions.free_cells_f,
+                &[cells.into(), cells_length.into()],
+                "",
+            );
+
+            let input_buffer = self.builder.build_load(self.input_buffer_alloca, "load");
+            self.builder
+                .build_call(self.functions.free_f, &[input_buffer.into()], "");
+
+            if self.has_output_buffer() {
+                self.builder
+                    .build_call(self.functions.free_output_buffer_f, &[], "");
+            }
+        }
+
+        self.builder.build_return(Some(&phi.as_basic_value()));
+
+        self.verify()
+    }
+
+    /// Returns 0 from `main` without allocating a tape or setting up the output stream, for a
+    /// program that has no effect, and verifies the finished module.
+    fn generate_empty_main(&self) -> Result<&Module<'a>, CodeGenError> {
+        // Nothing can fail, so nothing branches to the error block.
+        unsafe { self.main_error_block.delete() }.unwrap();
+
+        self.builder
+            .build_return(Some(&self.types.int_t.const_zero()));
+
+        self.verify()
+    }
+
+    fn verify(&self) -> Result<&Module<'a>, CodeGenError> {
+        if !self.functions.main_f.verify(true) {
+            return Err(CodeGenError::Verification(
+                "could not verify main function".to_string(),
+            ));
+        }
+
+        self.module
+            .verify()
+            .map_err(|error| CodeGenError::Verification(error.to_string()))?;
+
+        Ok(&self.module)
+    }
+
+    /// Checks the invariants listed on `CodeGenError` before any code is generated.
+    ///
+    /// Loops are walked with an explicit stack like in `generate_instructions`, so that deeply
+    /// nested programs don't overflow the stack before any code is generated.
+    fn validate(instructions: &[Instruction]) -> Result<(), CodeGenError> {
+        let mut stack = vec![instructions.iter()];
+
+        while let Some(instructions) = stack.last_mut() {
+            let instruction = match instructions.next() {
+                Some(instruction) => instruction,
+                None => {
+                    stack.pop();
+                    continue;
+                }
+            };
+
+            match &instruction.kind {
+                InstructionKind::Loop { instructions } => stack.push(instructions.iter()),
+                InstructionKind::WithMultiplier { instructions } => {
+                    let mut offset = Some(0isize);
+
+                    for body_instruction in instructions {
+                        offset = match &body_instruction.kind {
+                            InstructionKind::MoveRight { amount } => {
+                                offset.and_then(|offset| offset.checked_add_unsigned(*amount))
+                            }
+                            InstructionKind::MoveLeft { amount } => {
+                                offset.and_then(|offset| offset.checked_sub_unsigned(*amount))
+                            }
+                            InstructionKind::Increment { amount: _ }
+                            | InstructionKind::Decrement { amount: _ } => offset,
+                            _ => return Err(CodeGenError::InvalidMultiplierBody(instruction.loc)),
+                        };
+                    }
+
+                    // A body whose offset overflows can't return to its start either.
+                    if offset != Some(0) {
+                        return Err(CodeGenError::UnbalancedMultiplierBody(instruction.loc));
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Generates code for `instructions`.
+    ///
+    /// Loops are handled with an explicit stack instead of recursion, so that arbitrarily deeply
+    /// nested programs don't overflow the stack.
+    ///
+    /// Next to the instructions, every entry of the stack tracks the remaining instructions of the
+    /// current straight-line region and whether its tape was reserved up front. Entries of loops
+    /// also hold the blocks of the loop and its location, which the branch back to the condition is
+    /// annotated with, and the cells known after the loop.
+    ///
+    /// If `reserved` is set, `instructions` form a single straight-line region whose tape was
+    /// already allocated. If `starts` is given, where the code of every instruction in
+    /// `instructions` starts is recorded in it.
+    fn generate_instructions(
+        &self,
+        instructions: &[Instruction],
+        has_multiplier: bool,
+        reserved: bool,
+        mut starts: Option<&mut Vec<InstructionStart>>,
+    ) {
+        let region = if reserved {
+            (instructions.len(), true)
+        } else {
+            (0, false)
+        };
+        let mut stack = vec![(instructions.iter(), has_multiplier, region, None)];
+
+        while let Some((instructions, has_multiplier, region, loop_blocks)) = stack.last_mut() {
+            // Only the outermost entry of the stack isn't a loop.
+            if let (Some(starts), Some(instruction), None) = (
+                starts.as_deref_mut(),
+                instructions.as_slice().first(),
+                loop_blocks,
+            ) {
+                let block = self.builder.get_insert_block().unwrap();
+
+                starts.push(InstructionStart {
+                    summary: instruction.summary(),
+                    block: block.get_name().to_string_lossy().into_owned(),
+                    first_new_block: self.functions.main_f.count_basic_blocks() as usize - 1,
+                });
+            }
+
+            if region.0 == 0 {
+                *region = self.generate_region_reservation(instructions.as_slice());
+            }
+
+            match instructions.next() {
+                Some(
+                    instruction @ Instruction {
+                        kind: InstructionKind::Loop { instructions },
+                        loc,
+                        end: _,
+                    },
+                ) => {
+                    let loop_blocks =
+                        self.with_location(*loc, || self.generate_loop_start(instruction));
+
+                    // Nothing is known about the cells in the body, which may run any number of
+                    // times, but what follows the loop only depends on the cells before it.
+                    let mut known_cells = self.known_cells.borrow_mut();
+                    let mut known_cells_after = known_cells.clone();
+                    known_cells_after.apply(instruction);
+                    known_cells.forget();
+
+                    stack.push((
+                        instructions.iter(),
+                        false,
+                        (0, false),
+                        Some((loop_blocks, *loc, known_cells_after)),
+                    ));
+                }
+                Some(instruction) => {
+                    let (remaining, reserved) = region;
+                    *remaining = remaining.saturating_sub(1);
+
+                    self.with_location(instruction.loc, || {
+                        self.generate_instruction(instruction, *has_multiplier, *reserved)
+                    });
+
+                    // The body of a `WithMultiplier` is followed as a whole.
+                    if !*has_multiplier {
+                        self.known_cells.borrow_mut().apply(instruction);
+                    }
+                }
+                None => {
+                    if let Some((_, _, _, Some(((loop_block, merge_block), loc, known_cells)))) =
+                        stack.pop()
+                    {
+                        self.with_location(loc, || {
+                            self.builder.build_unconditional_branch(loop_block);
+                        });
+                        self.builder.position_at_end(merge_block);
+
+                        *self.known_cells.borrow_mut() = known_cells;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Checks that `current_cell` is on the tape before `instruction` accesses it, if
+    /// `CodeGenOptions::debug_bounds` is set. Otherwise, the program reports the cell and the
+    /// instruction and exits.
+    fn generate_bounds_check(&self, current_cell: IntValue<'a>, instruction: &Instruction) {
+        if !self.options.debug_bounds {
+            return;
+        }
+
+        let cells_length = self
+            .builder
+            .build_load(self.cells_length_alloca, "load")
+            .into_int_value();
+
+        // A cell left of the first one wrapped around, so it is out of bounds as well.
+        let in_bounds = self.builder.build_int_compare(
+            IntPredicate::ULT,
+            current_cell,
+            cells_length,
+            "inBounds",
+        );
+
+        let [out_of_bounds_block, continue_block] =
+            self.prepend_blocks(["outOfBounds", "continue"]);
+
+        self.builder
+            .build_conditional_branch(in_bounds, continue_block, out_of_bounds_block);
+
+        // The helper reports the access and exits, so the block never falls through.
+        self.builder.position_at_end(out_of_bounds_block);
+
+        let summary_v = Globals::intern_string(&instruction.summary(), self.context, &self.module);
+        let summary = self
+            .builder
+            .build_bitcast(summary_v, self.types.char_ptr_t, "instruction");
+
+        self.builder.build_call(
+            self.functions.out_of_bounds_f,
+            &[summary.into(), current_cell.into(), cells_length.into()],
+            "",
+        );
+        self.builder.build_unreachable();
+
+        self.builder.position_at_end(continue_block);
+    }
+
+    /// Grows the tape up front for the straight-line region at the start of `instructions`, if it
+    /// moves right more than once, so that the moves in it don't have to check the tape length.
+    /// With `max_tape_bytes`, the tape only grows as far as the moves get instead, so that a program
+    /// reaching the limit still runs everything in front of the move that reaches it.
+    ///
+    /// Returns the length of the region and whether the tape was reserved for it.
+    fn generate_region_reservation(&self, instructions: &[Instruction]) -> (usize, bool) {
+        let region = analysis::straight_line_region(instructions);
+
+        // A single move right checks the tape length only once anyway.
+        if region.moves_right < 2 || self.options.max_tape_bytes.is_some() {
+            return (region.length, false);
+        }
+
+        let current_cell = self
+            .builder
+            .build_load(self.current_cell_alloca, "load")
+            .into_int_value();
+
+        let minimum_cells_length = self.builder.build_int_add(
+            current_cell,
+            self.types
+                .size_t_t
+                .const_int(region.reach as u64 + 1, false),
+            "minimumCellsLength",
+        );
+
+        self.builder.build_call(
+            self.functions.reserve_cells_f,
+            &[
+                self.cells_alloca.into(),
+                self.cells_length_alloca.into(),
+                minimum_cells_length.into(),
+            ],
+            "",
+        );
+
+        (region.length, true)
+    }
+
+    /// Whether the program collects its output in a buffer of `output_buffer_size` bytes, which
+    /// isn't the case for an unbuffered or unset size.
+    fn has_output_buffer(&self) -> bool {
+        !matches!(self.options.output_buffer_size, None | Some(0))
+    }
+
+    /// Appends the byte `value` to the output buffer, and writes the buffer once it is full. The
+    /// buffer is written with a single `fwrite` by the `flushOutputBuffer` helper, which is much
+    /// faster for large outputs than writing every byte on its own.
+    fn generate_buffered_output(&self, value: IntValue<'a>) {
+        let output_buffer = self
+            .builder
+            .build_load(self.globals.output_buffer_ptr_v.as_pointer_value(), "load")
+            .into_pointer_value();
+        let output_length = self
+            .builder
+            .build_load(self.globals.output_length_v.as_pointer_value(), "load")
+            .into_int_value();
+
+        let output_ptr = unsafe {
+            self.builder
+                .build_gep(output_buffer, &[output_length], "outputPtr")
+        };
+        let byte = self
+            .builder
+            .build_int_truncate(value, self.types.char_t, "outputByte");
+        self.builder.build_store(output_ptr, byte);
+
+        let output_length = self.builder.build_int_add(
+            output_length,
+            self.types.size_t_t.const_int(1, false),
+            "incrementedOutputLength",
+        );
+        self.builder.build_store(
+            self.globals.output_length_v.as_pointer_value(),
+            output_length,
+        );
+
+        let buffer_is_full = self.builder.build_int_compare(
+            IntPredicate::EQ,
+            output_length,
+            self.types
+                .size_t_t
+                .const_int(self.options.output_buffer_size.unwrap() as u64, false),
+            "bufferIsFull",
+        );
+
+        let [flush_block, continue_block] = self.prepend_blocks(["flushOutput", "continue"]);
+
+        self.builder
+            .build_conditional_branch(buffer_is_full, flush_block, continue_block);
+
+        self.builder.position_at_end(flush_block);
+        self.builder
+            .build_call(self.functions.flush_output_buffer_f, &[], "");
+        self.builder.build_unconditional_branch(continue_block);
+
+        self.builder.position_at_end(continue_block);
+    }
+
+    /// Loads the stream the program writes its output to, the one opened for `output_fd` or
+    /// stdout.
+    fn build_load_output_stream(&self) -> BasicValueEnum<'a> {
+        let output_stream_ptr = if self.options.output_fd.is_some() {
+            self.output_stream_alloca
+        } else {
+            self.globals.stdout_ptr_v.as_pointer_value()
+        };
+
+        self.builder.build_load(output_stream_ptr, "load")
+    }
+
+    /// Inserts blocks named `names` in front of the error block, numbering them with the same number
+    /// that counts up with every call. This keeps the names of the blocks generated for the same
+    /// instruction together and unique, and they are the same on every compilation.
+    fn prepend_blocks<const N: usize>(&self, names: [&str; N]) -> [BasicBlock<'a>; N] {
+        let number = self.next_block_number.get();
+        self.next_block_number.set(number + 1);
+
+        names.map(|name| {
+            self.context
+                .prepend_basic_block(self.main_error_block, &format!("{}.{}", name, number))
+        })
+    }
+
+    /// Runs `generate` and attaches `!bf.loc` metadata with the line and column of `loc` to every
+    /// LLVM instruction it generated, so that IR dumps show where the code came from. Instructions
+    /// that already carry a location, e.g. the ones of the body of a `WithMultiplier`, keep it.
+    ///
+    /// The generated instructions are the ones added to the current block and the ones in the
+    /// blocks created meanwhile, which are always inserted in front of the error block.
+    fn with_location<T>(&self, loc: SourceLoc, generate: impl FnOnce() -> T) -> T {
+        let block = self.builder.get_insert_block().unwrap();
+        let last_instruction = block.get_last_instruction();
+        // The entry block always precedes the error block.
+        let last_block = self.main_error_block.get_previous_basic_block().unwrap();
+
+        let result = generate();
+
+        let first_instruction = match last_instruction {
+            Some(instruction) => instruction.get_next_instruction(),
+            None => block.get_first_instruction(),
+        };
+        let new_blocks = iter::successors(last_block.get_next_basic_block(), |block| {
+            block.get_next_basic_block()
+        })
+        .take_while(|block| *block != self.main_error_block);
+
+        let instructions = iter::successors(first_instruction, |instruction| {
+            instruction.get_next_instruction()
+        })
+        .chain(new_blocks.flat_map(|block| {
+            iter::successors(block.get_first_instruction(), |instruction| {
+                instruction.get_next_instruction()
+            })
+        }));
+
+        let location = self.context.metadata_node(&[
+            self.types.size_t_t.const_int(loc.line as u64, false).into(),
+            self.types.size_t_t.const_int(loc.col as u64, false).into(),
+        ]);
+        let kind_id = self.context.get_kind_id("bf.loc");
+
+        for instruction in instructions {
+            if instruction.get_metadata(kind_id).is_none() {
+                instruction.set_metadata(location, kind_id).unwrap();
+            }
+        }
+
+        result
+    }
+
+    /// Generates the condition of the loop `instruction` and positions the builder at the start of
+    /// its body.
+    ///
+    /// Returns the block containing the condition and the block following the loop.
+    fn generate_loop_start(&self, instruction: &Instruction) -> (BasicBlock<'a>, BasicBlock<'a>) {
+        let [loop_block, then_block, merge_block] = self.prepend_blocks(["loop", "then", "merge"]);
+
+        self.builder.build_unconditional_branch(loop_block);
+        self.builder.position_at_end(loop_block);
+
+        let cells = self
+            .builder
+            .build_load(self.cells_alloca, "load")
+            .into_pointer_value();
+        let current_cell = self
+            .builder
+            .build_load(self.current_cell_alloca, "load")
+            .into_int_value();
+
+        self.generate_bounds_check(current_cell, instruction);
+
+        let current_cell_ptr = unsafe {
+            self.builder
+                .build_gep(cells, &[current_cell], "currentCellPtr")
+        };
+
+        let current_cell_value = self
+            .builder
+            .build_load(current_cell_ptr, "load")
+            .into_int_value();
+
+        let continue_loop = self.builder.build_int_compare(
+            IntPredicate::NE,
+            current_cell_value,
+            self.types.char_t.const_int(0, false),
+            "breakLoop",
+        );
+
+        self.builder
+            .build_conditional_branch(continue_loop, then_block, merge_block);
+
+        self.builder.position_at_end(then_block);
+
+        (loop_block, merge_block)
+    }
+
+    /// Generates code for a single `instruction`. If `reserved` is set, it is part of a region whose
+    /// tape was reserved up front, so moves neither grow the tape nor check for negative cells.
+    fn generate_instruction(
+        &self,
+        instruction: &Instruction,
+        has_multiplier: bool,
+        reserved: bool,
+    ) {
+        match &instruction.kind {
+            InstructionKind::MoveRight { amount } | InstructionKind::MoveLeft { amount }
+                if reserved =>
+            {
+                let current_cell = self
+                    .builder
+                    .build_load(self.current_cell_alloca, "load")
+                    .into_int_value();
+
+                let amount = self.types.size_t_t.const_int(*amount as u64, false);
+
+                let current_cell =
+                    if let InstructionKind::MoveRight { amount: _ } = &instruction.kind {
+                        self.builder
+                            .build_int_add(current_cell, amount, "incrementedCurrentCell")
+                    } else {
+                        self.builder
+                            .build_int_sub(current_cell, amount, "decrementedCurrentCell")
+                    };
+
+                self.builder
+                    .build_store(self.current_cell_alloca, current_cell);
+            }
+            InstructionKind::MoveRight { amount } => {
+                self.builder.build_call(
+                    self.functions.move_right_f,
+                    &[
+                        self.cells_alloca.into(),
+                        self.cells_length_alloca.into(),
+                        self.current_cell_alloca.into(),
+                        self.types.size_t_t.const_int(*amount as u64, false).into(),
+                    ],
+                    "",
+                );
+            }
+            InstructionKind::MoveLeft { amount } if self.options.clamp_left => {
+                let current_cell = self
+                    .builder
+                    .build_load(self.current_cell_alloca, "load")
+                    .into_int_value();
+
+                let amount = self.types.size_t_t.const_int(*amount as u64, false);
+
+                let clamp = self.builder.build_int_compare(
+                    IntPredicate::ULT,
+                    current_cell,
+                    amount,
+                    "clamp",
+                );
+                let decremented_current_cell =
+                    self.builder
+                        .build_int_sub(current_cell, amount, "decrementedCurrentCell");
+
+                let current_cell = self.builder.build_select(
+                    clamp,
+                    self.types.size_t_t.const_zero(),
+                    decremented_current_cell,
+                    "clampedCurrentCell",
+                );
+
+                self.builder
+                    .build_store(self.current_cell_alloca, current_cell);
+            }
+            InstructionKind::MoveLeft { amount } => {
+                let current_cell = self
+                    .builder
+                    .build_load(self.current_cell_alloca, "load")
+                    .into_int_value();
+
+                let current_cell = self.builder.build_int_sub(
+                    current_cell,
+                    self.types.size_t_t.const_int(*amount as u64, false),
+                    "decrementedCurrentCell",
+                );
+
+                let return_with_error = self.builder.build_int_compare(
+                    IntPredicate::SLT,
+                    current_cell,
+                    self.types.size_t_t.const_zero(),
+                    "returnWithError",
+                );
+
+                let [move_left_block] = self.prepend_blocks(["moveLeft"]);
+
+                self.builder.build_conditional_branch(
+                    return_with_error,
+                    self.main_error_block,
+                    move_left_block,
+                );
+                self.builder.position_at_end(move_left_block);
+
+                self.builder
+                    .build_store(self.current_cell_alloca, current_cell);
+            }
+            InstructionKind::Increment { amount } | InstructionKind::Decrement { amount } => {
+                let cells = self
+                    .builder
+                    .build_load(self.cells_alloca, "load")
+                    .into_pointer_value();
+                let current_cell = self
+                    .builder
+                    .build_load(self.current_cell_alloca, "load")
+                    .into_int_value();
+
+                self.generate_bounds_check(current_cell, instruction);
+
+                let current_cell_ptr = unsafe {
+                    self.builder
                         .build_gep(cells, &[current_cell], "currentCellPtr")
                 };
 
@@ -467,7 +1859,9 @@ impl<'a> CodeGen<'a> {
                         .build_int_mul(amount, multiplier, "multipliedAmount");
                 }
 
-                let current_cell_value = if let Instruction::Increment { amount: _ } = instruction {
+                let current_cell_value = if let InstructionKind::Increment { amount: _ } =
+                    &instruction.kind
+                {
                     self.builder
                         .build_int_add(current_cell_value, amount, "incrementedCurrentCell")
                 } else {
@@ -478,43 +1872,67 @@ impl<'a> CodeGen<'a> {
                 self.builder
                     .build_store(current_cell_ptr, current_cell_value);
             }
-            Instruction::Output => {
-                let cells = self
-                    .builder
-                    .build_load(self.cells_alloca, "load")
-                    .into_pointer_value();
-                let current_cell = self
-                    .builder
-                    .build_load(self.current_cell_alloca, "load")
-                    .into_int_value();
-
-                let current_cell_ptr = unsafe {
-                    self.builder
-                        .build_gep(cells, &[current_cell], "currentCellPtr")
+            InstructionKind::Output => {
+                // A cell whose value is known is output as a constant, without loading it.
+                let known_value = self.known_cells.borrow().current();
+                let current_cell_value = match known_value {
+                    Some(value) => self.types.int_t.const_int(value as u64, false),
+                    None => {
+                        let cells = self
+                            .builder
+                            .build_load(self.cells_alloca, "load")
+                            .into_pointer_value();
+                        let current_cell = self
+                            .builder
+                            .build_load(self.current_cell_alloca, "load")
+                            .into_int_value();
+
+                        self.generate_bounds_check(current_cell, instruction);
+
+                        let current_cell_ptr = unsafe {
+                            self.builder
+                                .build_gep(cells, &[current_cell], "currentCellPtr")
+                        };
+
+                        let current_cell_value = self
+                            .builder
+                            .build_load(current_cell_ptr, "load")
+                            .into_int_value();
+
+                        self.builder.build_int_z_extend(
+                            current_cell_value,
+                            self.types.int_t,
+                            "extendedCurrentCellValue",
+                        )
+                    }
                 };
 
-                let current_cell_value = self
-                    .builder
-                    .build_load(current_cell_ptr, "load")
-                    .into_int_value();
-
-                let current_cell_value = self.builder.build_int_z_extend(
-                    current_cell_value,
-                    self.types.int_t,
-                    "extendedCurrentCellValue",
-                );
+                if self.has_output_buffer() {
+                    self.generate_buffered_output(current_cell_value);
+                } else if self.options.use_fputc || self.options.output_fd.is_some() {
+                    let output_stream = self.build_load_output_stream();
 
-                self.builder
-                    .build_call(self.functions.putchar_f, &[current_cell_value.into()], "");
+                    self.builder.build_call(
+                        self.functions.fputc_f,
+                        &[current_cell_value.into(), output_stream.into()],
+                        "",
+                    );
+                } else {
+                    self.builder.build_call(
+                        self.functions.putchar_f,
+                        &[current_cell_value.into()],
+                        "",
+                    );
+                }
 
-                let stdout = self
-                    .builder
-                    .build_load(self.globals.stdout_ptr_v.as_pointer_value(), "load");
+                if self.options.output_buffer_size.is_none() {
+                    let output_stream = self.build_load_output_stream();
 
-                self.builder
-                    .build_call(self.functions.fflush_f, &[stdout.into()], "");
+                    self.builder
+                        .build_call(self.functions.fflush_f, &[output_stream.into()], "");
+                }
             }
-            Instruction::Input => {
+            InstructionKind::Input => {
                 let cells = self
                     .builder
                     .build_load(self.cells_alloca, "load")
@@ -524,6 +1942,9 @@ impl<'a> CodeGen<'a> {
                     .build_load(self.current_cell_alloca, "load")
                     .into_int_value();
 
+                // The helper stores the input in the current cell.
+                self.generate_bounds_check(current_cell, instruction);
+
                 let args = &[
                     cells.into(),
                     current_cell.into(),
@@ -531,57 +1952,15 @@ impl<'a> CodeGen<'a> {
                 ];
                 self.builder.build_call(self.functions.input_f, args, "");
             }
-            Instruction::Loop { instructions } => {
-                let loop_block = self
-                    .context
-                    .prepend_basic_block(self.main_error_block, "loop");
-                let then_block = self
-                    .context
-                    .prepend_basic_block(self.main_error_block, "then");
-                let merge_block = self
-                    .context
-                    .prepend_basic_block(self.main_error_block, "merge");
-
-                self.builder.build_unconditional_branch(loop_block);
-                self.builder.position_at_end(loop_block);
-
-                let cells = self
-                    .builder
-                    .build_load(self.cells_alloca, "load")
-                    .into_pointer_value();
-                let current_cell = self
-                    .builder
-                    .build_load(self.current_cell_alloca, "load")
-                    .into_int_value();
-
-                let current_cell_ptr = unsafe {
-                    self.builder
-                        .build_gep(cells, &[current_cell], "currentCellPtr")
-                };
-
-                let current_cell_value = self
-                    .builder
-                    .build_load(current_cell_ptr, "load")
-                    .into_int_value();
-
-                let continue_loop = self.builder.build_int_compare(
-                    IntPredicate::NE,
-                    current_cell_value,
-                    self.types.char_t.const_int(0, false),
-                    "breakLoop",
+            InstructionKind::Loop { instructions: _ } => {
+                self.generate_instructions(
+                    slice::from_ref(instruction),
+                    has_multiplier,
+                    false,
+                    None,
                 );
-
-                self.builder
-                    .build_conditional_branch(continue_loop, then_block, merge_block);
-
-                self.builder.position_at_end(then_block);
-
-                self.generate_instructions(instructions, false);
-
-                self.builder.build_unconditional_branch(loop_block);
-                self.builder.position_at_end(merge_block);
             }
-            Instruction::MoveRightUntilZero { step_size } => {
+            InstructionKind::MoveRightUntilZero { step_size } => {
                 self.builder.build_call(
                     self.functions.move_right_until_zero_f,
                     &[
@@ -596,7 +1975,7 @@ impl<'a> CodeGen<'a> {
                     "",
                 );
             }
-            Instruction::MoveLeftUntilZero { step_size } => {
+            InstructionKind::MoveLeftUntilZero { step_size } => {
                 let cells = self.builder.build_load(self.cells_alloca, "load");
 
                 let return_with_error = self
@@ -618,9 +1997,7 @@ impl<'a> CodeGen<'a> {
                     .unwrap()
                     .into_int_value();
 
-                let continue_block = self
-                    .context
-                    .prepend_basic_block(self.main_error_block, "continue");
+                let [continue_block] = self.prepend_blocks(["continue"]);
 
                 self.builder.build_conditional_branch(
                     return_with_error,
@@ -629,7 +2006,33 @@ impl<'a> CodeGen<'a> {
                 );
                 self.builder.position_at_end(continue_block);
             }
-            Instruction::SetToZero => {
+            InstructionKind::OutputUntilZero { step_size } => {
+                let output_stream = self.build_load_output_stream();
+
+                self.builder.build_call(
+                    self.functions.output_until_zero_f,
+                    &[
+                        self.cells_alloca.into(),
+                        self.cells_length_alloca.into(),
+                        self.current_cell_alloca.into(),
+                        self.types
+                            .size_t_t
+                            .const_int(*step_size as u64, false)
+                            .into(),
+                        output_stream.into(),
+                    ],
+                    "",
+                );
+
+                // The whole string is flushed at once instead of every character of it.
+                if self.options.output_buffer_size.is_none() {
+                    let output_stream = self.build_load_output_stream();
+
+                    self.builder
+                        .build_call(self.functions.fflush_f, &[output_stream.into()], "");
+                }
+            }
+            InstructionKind::SetToZero | InstructionKind::SetValue { value: _ } => {
                 let cells = self
                     .builder
                     .build_load(self.cells_alloca, "load")
@@ -639,15 +2042,56 @@ impl<'a> CodeGen<'a> {
                     .build_load(self.current_cell_alloca, "load")
                     .into_int_value();
 
+                self.generate_bounds_check(current_cell, instruction);
+
                 let current_cell_ptr = unsafe {
                     self.builder
                         .build_gep(cells, &[current_cell], "currentCellPtr")
                 };
 
-                self.builder
-                    .build_store(current_cell_ptr, self.types.char_t.const_zero());
+                let value = if let InstructionKind::SetValue { value } = &instruction.kind {
+                    self.types.char_t.const_int(*value as u64, false)
+                } else {
+                    self.types.char_t.const_zero()
+                };
+
+                self.builder.build_store(current_cell_ptr, value);
+            }
+            InstructionKind::ClearRange { start_offset, len } => {
+                let current_cell = self.builder.build_load(self.current_cell_alloca, "load");
+
+                // The offset is passed as a `ptrdiff_t`, which has the same bits as a `size_t`.
+                let return_with_error = self
+                    .builder
+                    .build_call(
+                        self.functions.clear_range_f,
+                        &[
+                            self.cells_alloca.into(),
+                            self.cells_length_alloca.into(),
+                            current_cell.into(),
+                            self.types
+                                .size_t_t
+                                .const_int(*start_offset as u64, true)
+                                .into(),
+                            self.types.size_t_t.const_int(*len as u64, false).into(),
+                        ],
+                        "",
+                    )
+                    .try_as_basic_value()
+                    .left()
+                    .unwrap()
+                    .into_int_value();
+
+                let [continue_block] = self.prepend_blocks(["continue"]);
+
+                self.builder.build_conditional_branch(
+                    return_with_error,
+                    self.main_error_block,
+                    continue_block,
+                );
+                self.builder.position_at_end(continue_block);
             }
-            Instruction::WithMultiplier { instructions } => {
+            InstructionKind::WithMultiplier { instructions } => {
                 let cells = self
                     .builder
                     .build_load(self.cells_alloca, "load")
@@ -658,6 +2102,8 @@ impl<'a> CodeGen<'a> {
                     .build_load(self.current_cell_alloca, "load")
                     .into_int_value();
 
+                self.generate_bounds_check(current_cell, instruction);
+
                 let current_cell_ptr = unsafe {
                     self.builder
                         .build_gep(cells, &[current_cell], "currentCellPtr")
@@ -675,13 +2121,8 @@ impl<'a> CodeGen<'a> {
                     "multiplierIsZero",
                 );
 
-                let with_multiplier_block = self
-                    .context
-                    .prepend_basic_block(self.main_error_block, "withMultiplier");
-
-                let continue_block = self
-                    .context
-                    .prepend_basic_block(self.main_error_block, "continue");
+                let [with_multiplier_block, continue_block] =
+                    self.prepend_blocks(["withMultiplier", "continue"]);
 
                 self.builder.build_conditional_branch(
                     multiplier_is_zero,
@@ -693,7 +2134,7 @@ impl<'a> CodeGen<'a> {
 
                 self.builder.build_store(self.multiplier_alloca, multiplier);
 
-                self.generate_instructions(instructions, true);
+                self.generate_instructions(instructions, true, false, None);
 
                 let cells = self
                     .builder
@@ -705,6 +2146,8 @@ impl<'a> CodeGen<'a> {
                     .build_load(self.current_cell_alloca, "load")
                     .into_int_value();
 
+                self.generate_bounds_check(current_cell, instruction);
+
                 let current_cell_ptr = unsafe {
                     self.builder
                         .build_gep(cells, &[current_cell], "currentCellPtr")
@@ -717,8 +2160,14 @@ impl<'a> CodeGen<'a> {
 
                 self.builder.position_at_end(continue_block);
             }
-            Instruction::MoveValueRight { amount } => {
-                let current_cell = self.builder.build_load(self.current_cell_alloca, "load");
+            InstructionKind::MoveValueRight { amount } => {
+                let current_cell = self
+                    .builder
+                    .build_load(self.current_cell_alloca, "load")
+                    .into_int_value();
+
+                // The helper reads the current cell before it grows the tape for the target.
+                self.generate_bounds_check(current_cell, instruction);
 
                 self.builder.build_call(
                     self.functions.move_value_right_f,
@@ -731,10 +2180,15 @@ impl<'a> CodeGen<'a> {
                     "",
                 );
             }
-            Instruction::MoveValueLeft { amount } => {
+            InstructionKind::MoveValueLeft { amount } => {
                 let cells = self.builder.build_load(self.cells_alloca, "load");
 
-                let current_cell = self.builder.build_load(self.current_cell_alloca, "load");
+                let current_cell = self
+                    .builder
+                    .build_load(self.current_cell_alloca, "load")
+                    .into_int_value();
+
+                self.generate_bounds_check(current_cell, instruction);
 
                 let return_with_error = self
                     .builder
@@ -752,9 +2206,7 @@ impl<'a> CodeGen<'a> {
                     .unwrap()
                     .into_int_value();
 
-                let continue_block = self
-                    .context
-                    .prepend_basic_block(self.main_error_block, "continue");
+                let [continue_block] = self.prepend_blocks(["continue"]);
 
                 self.builder.build_conditional_branch(
                     return_with_error,
@@ -763,6 +2215,514 @@ impl<'a> CodeGen<'a> {
                 );
                 self.builder.position_at_end(continue_block);
             }
+            InstructionKind::TransferValue { offset } => {
+                let current_cell = self
+                    .builder
+                    .build_load(self.current_cell_alloca, "load")
+                    .into_int_value();
+
+                // The helper reads the current cell before it grows the tape for the destination.
+                self.generate_bounds_check(current_cell, instruction);
+
+                // The offset is passed as a `ptrdiff_t`, which has the same bits as a `size_t`.
+                let return_with_error = self
+                    .builder
+                    .build_call(
+                        self.functions.transfer_value_f,
+                        &[
+                            self.cells_alloca.into(),
+                            self.cells_length_alloca.into(),
+                            current_cell.into(),
+                            self.types.size_t_t.const_int(*offset as u64, true).into(),
+                        ],
+                        "",
+                    )
+                    .try_as_basic_value()
+                    .left()
+                    .unwrap()
+                    .into_int_value();
+
+                let [continue_block] = self.prepend_blocks(["continue"]);
+
+                self.builder.build_conditional_branch(
+                    return_with_error,
+                    self.main_error_block,
+                    continue_block,
+                );
+                self.builder.position_at_end(continue_block);
+            }
+            InstructionKind::AssertEquals { value } => {
+                let cells = self
+                    .builder
+                    .build_load(self.cells_alloca, "load")
+                    .into_pointer_value();
+                let current_cell = self
+                    .builder
+                    .build_load(self.current_cell_alloca, "load")
+                    .into_int_value();
+
+                self.generate_bounds_check(current_cell, instruction);
+
+                let current_cell_ptr = unsafe {
+                    self.builder
+                        .build_gep(cells, &[current_cell], "currentCellPtr")
+                };
+
+                let current_cell_value = self
+                    .builder
+                    .build_load(current_cell_ptr, "load")
+                    .into_int_value();
+
+                let expected_value = self.types.char_t.const_int(*value as u64, false);
+
+                let assertion_holds = self.builder.build_int_compare(
+                    IntPredicate::EQ,
+                    current_cell_value,
+                    expected_value,
+                    "assertionHolds",
+                );
+
+                let [assertion_failed_block, continue_block] =
+                    self.prepend_blocks(["assertionFailed", "continue"]);
+
+                self.builder.build_conditional_branch(
+                    assertion_holds,
+                    continue_block,
+                    assertion_failed_block,
+                );
+
+                // The helper reports the failure and exits, so the block never falls through.
+                self.builder.position_at_end(assertion_failed_block);
+                self.builder.build_call(
+                    self.functions.assertion_failed_f,
+                    &[
+                        current_cell.into(),
+                        expected_value.into(),
+                        current_cell_value.into(),
+                    ],
+                    "",
+                );
+                self.builder.build_unreachable();
+
+                self.builder.position_at_end(continue_block);
+            }
+            InstructionKind::Nop => {}
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use inkwell::values::AnyValue;
+
+    use super::*;
+    use crate::{parser::Parser, tok::Tokenizer};
+
+    const LOC: SourceLoc = SourceLoc {
+        line: 1,
+        col: 1,
+        offset: 0,
+    };
+
+    /// Returns the IR of `main` in a module that only executes an instruction of `kind`.
+    fn instruction_ir(kind: InstructionKind) -> String {
+        let context = Context::create();
+        let instruction = Instruction::new(kind, LOC, LOC);
+        let module = CodeGen::generate_instruction_module(instruction, &context);
+
+        module
+            .get_function("main")
+            .unwrap()
+            .print_to_string()
+            .to_string()
+    }
+
+    /// Returns the IR of the module generated for `source` with `options`.
+    fn program_ir(source: &str, options: CodeGenOptions) -> String {
+        let context = Context::create();
+        let code_gen = CodeGen::with_name("test.bf", options, &context);
+        let instructions = Parser::new(Tokenizer::new(source))
+            .map(Result::unwrap)
+            .collect::<Vec<_>>();
+
+        code_gen
+            .generate_module(&instructions)
+            .unwrap()
+            .print_to_string()
+            .to_string()
+    }
+
+    #[test]
+    fn set_to_zero_stores_zero() {
+        let ir = instruction_ir(InstructionKind::SetToZero);
+
+        assert_eq!(ir.matches("store i8 0,").count(), 1);
+    }
+
+    #[test]
+    fn no_free_memory_skips_frees() {
+        let options = CodeGenOptions {
+            free_memory: false,
+            ..CodeGenOptions::default()
+        };
+        let ir = program_ir(",.", options);
+
+        assert!(!ir.contains("call void @freeCells("));
+        assert!(!ir.contains("call void @free("));
+        assert!(program_ir(",.", CodeGenOptions::default()).contains("call void @freeCells("));
+    }
+
+    #[test]
+    fn move_right_calls_helper() {
+        let ir = instruction_ir(InstructionKind::MoveRight { amount: 4 });
+
+        assert!(ir
+            .lines()
+            .any(|line| line.contains("call void @moveRight(") && line.contains(" i64 4)")));
+    }
+
+    #[test]
+    fn increment_adds_amount() {
+        let ir = instruction_ir(InstructionKind::Increment { amount: 3 });
+
+        assert!(ir
+            .lines()
+            .any(|line| line.contains("= add i8 ") && line.contains(", 3, ")));
+    }
+
+    #[test]
+    fn output_buffer_size() {
+        let ir = program_ir(
+            ".",
+            CodeGenOptions {
+                output_buffer_size: Some(4096),
+                ..CodeGenOptions::default()
+            },
+        );
+
+        assert!(ir
+            .lines()
+            .any(|line| line.contains("call void @allocateOutputBuffer(")
+                && line.contains(" 4096, ")));
+
+        let ir = program_ir(
+            ".",
+            CodeGenOptions {
+                output_buffer_size: Some(0),
+                ..CodeGenOptions::default()
+            },
+        );
+
+        assert!(ir.contains("call void @unbufferOutput("));
+        assert!(!ir.contains("call void @allocateOutputBuffer("));
+    }
+
+    #[test]
+    fn input_buffer_size() {
+        let ir = program_ir(
+            ",",
+            CodeGenOptions {
+                input_buffer_size: Some(8192),
+                ..CodeGenOptions::default()
+            },
+        );
+
+        assert!(ir.lines().any(
+            |line| line.contains("call void @allocateInputBuffer(") && line.contains(" 8192)")
+        ));
+        assert!(!ir.contains("store i8* null, i8** %inputBuffer"));
+
+        let ir = program_ir(",", CodeGenOptions::default());
+
+        assert!(ir.contains("store i8* null, i8** %inputBuffer"));
+        assert!(!ir.contains("call void @allocateInputBuffer("));
+    }
+
+    #[test]
+    fn unbalanced_multiplier_is_rejected() {
+        let body = [
+            InstructionKind::MoveRight { amount: 2 },
+            InstructionKind::Increment { amount: 1 },
+            InstructionKind::MoveLeft { amount: 1 },
+        ]
+        .into_iter()
+        .map(|kind| Instruction::new(kind, LOC, LOC))
+        .collect();
+        let multiplier_loc = SourceLoc {
+            line: 1,
+            col: 2,
+            offset: 1,
+        };
+        let multiplier = Instruction::new(
+            InstructionKind::WithMultiplier { instructions: body },
+            multiplier_loc,
+            multiplier_loc,
+        );
+        let instructions = [Instruction::new(
+            InstructionKind::Loop {
+                instructions: vec![multiplier],
+            },
+            LOC,
+            LOC,
+        )];
+
+        let context = Context::create();
+        let code_gen = CodeGen::with_name("test.bf", CodeGenOptions::default(), &context);
+
+        assert_eq!(
+            code_gen.generate_module(&instructions).unwrap_err(),
+            CodeGenError::UnbalancedMultiplierBody(multiplier_loc)
+        );
+    }
+
+    #[test]
+    fn region_grows_tape_once() {
+        let count_calls = |ir: &str, helper| {
+            ir.lines()
+                .filter(|line| line.contains(&format!("call void @{}(", helper)))
+                .count()
+        };
+
+        // The loop body is a straight-line region that moves right four times.
+        let source = ",[>+>+>+>+<<<<-]";
+
+        let ir = program_ir(source, CodeGenOptions::default());
+        assert_eq!(count_calls(&ir, "reserveCells"), 1);
+        assert_eq!(count_calls(&ir, "moveRight"), 0);
+
+        // With a limit, every move grows the tape on its own, so that the program fails at the
+        // same move.
+        let options = CodeGenOptions {
+            max_tape_bytes: Some(1024),
+            ..CodeGenOptions::default()
+        };
+        let ir = program_ir(source, options);
+        assert_eq!(count_calls(&ir, "reserveCells"), 0);
+        assert_eq!(count_calls(&ir, "moveRight"), 4);
+    }
+
+    #[test]
+    fn empty_program_has_no_tape() {
+        let allocates = |ir: &str| {
+            ir.lines()
+                .any(|line| line.contains("call ") && line.contains("@allocateCells("))
+        };
+
+        let ir = program_ir("only a comment", CodeGenOptions::default());
+        assert!(!allocates(&ir));
+        assert!(!ir.contains("calloc"));
+
+        let context = Context::create();
+        let code_gen = CodeGen::with_name("test.bf", CodeGenOptions::default(), &context);
+        let (module, map) = code_gen.generate_module_with_map(&[]).unwrap();
+        assert!(!allocates(&module.print_to_string().to_string()));
+        assert!(map.is_empty());
+    }
+
+    #[test]
+    fn use_fputc_writes_to_stdout() {
+        let options = CodeGenOptions {
+            use_fputc: true,
+            ..CodeGenOptions::default()
+        };
+        let ir = program_ir(",.", options);
+
+        assert!(ir.contains("call i32 @fputc("));
+        assert!(ir.contains("** @stdout"));
+        assert!(!ir.contains("call i32 @putchar("));
+    }
+
+    #[test]
+    fn two_programs_in_one_module() {
+        let context = Context::create();
+        let mut module = context.create_module("programs");
+
+        for (entry_name, source) in [("hello", "+++."), ("echo", ",[.,]")] {
+            let code_gen =
+                CodeGen::with_module(module, entry_name, CodeGenOptions::default(), &context)
+                    .unwrap();
+            let instructions = Parser::new(Tokenizer::new(source))
+                .map(Result::unwrap)
+                .collect::<Vec<_>>();
+            code_gen.generate_module(&instructions).unwrap();
+
+            module = code_gen.into_module();
+        }
+
+        let hello_f = module.get_function("hello").unwrap();
+        let echo_f = module.get_function("echo").unwrap();
+        assert_ne!(hello_f, echo_f);
+        assert!(hello_f.count_basic_blocks() > 0);
+        assert!(echo_f.count_basic_blocks() > 0);
+        assert!(module.verify().is_ok());
+
+        assert!(matches!(
+            CodeGen::with_module(module, "echo", CodeGenOptions::default(), &context),
+            Err(CodeGenError::DuplicateEntry(entry_name)) if entry_name == "echo"
+        ));
+    }
+
+    #[test]
+    fn map_lists_loop_blocks() {
+        let context = Context::create();
+        let code_gen = CodeGen::with_name("test.bf", CodeGenOptions::default(), &context);
+        let instructions = Parser::new(Tokenizer::new(",[->+<]."))
+            .map(Result::unwrap)
+            .collect::<Vec<_>>();
+
+        let (_, map) = code_gen.generate_module_with_map(&instructions).unwrap();
+
+        assert_eq!(map.len(), 3);
+        assert_eq!(map[1].summary, "Loop at 1:2");
+        assert_eq!(map[1].blocks[..4], ["entry", "loop.0", "then.0", "merge.0"]);
+        assert_eq!(map[2].blocks, ["merge.0"]);
+    }
+
+    #[test]
+    fn loop_free_program_has_no_branches() {
+        let ir = program_ir(",>>+<.>>,.", CodeGenOptions::default());
+
+        assert!(!ir.contains("br i1"));
+        assert!(!ir.contains("call void @moveRight("));
+        assert!(ir.contains("call i8* @allocateCells(i64 4)"));
+
+        assert!(program_ir(",[>,]", CodeGenOptions::default()).contains("br i1"));
+    }
+
+    #[test]
+    fn mmap_tape_allocation_sets_map_tape() {
+        let options = CodeGenOptions {
+            tape_allocation: TapeAllocation::Mmap,
+            ..CodeGenOptions::default()
+        };
+
+        assert!(program_ir(",.", options).contains("@bfMapTape = hidden constant i8 1"));
+        assert!(program_ir(",.", CodeGenOptions::default())
+            .contains("@bfMapTape = hidden constant i8 0"));
+    }
+
+    #[test]
+    fn move_right_has_location() {
+        let context = Context::create();
+        let loc = SourceLoc {
+            line: 3,
+            col: 7,
+            offset: 20,
+        };
+        let instruction = Instruction::new(InstructionKind::MoveRight { amount: 4 }, loc, loc);
+        let ir = CodeGen::generate_instruction_module(instruction, &context)
+            .print_to_string()
+            .to_string();
+
+        let call = ir
+            .lines()
+            .find(|line| line.contains("call void @moveRight("))
+            .unwrap();
+        let (_, node) = call.split_once(", !bf.loc ").unwrap();
+        assert!(ir.contains(&format!("{} = !{{i64 3, i64 7}}", node)));
+    }
+
+    #[test]
+    fn equal_strings_share_a_global() {
+        let context = Context::create();
+        let module = context.create_module("strings");
+
+        let hello_v = Globals::intern_string("hello", &context, &module);
+        let world_v = Globals::intern_string("world", &context, &module);
+        let hello_again_v = Globals::intern_string("hello", &context, &module);
+
+        assert_ne!(hello_v, world_v);
+        assert_ne!(hello_v.get_name(), world_v.get_name());
+        assert_eq!(hello_v, hello_again_v);
+        let globals =
+            iter::successors(module.get_first_global(), |global| global.get_next_global());
+        assert_eq!(globals.count(), 2);
+    }
+
+    #[test]
+    fn print_source_option_writes_embedded_source() {
+        let context = Context::create();
+        let options = CodeGenOptions {
+            print_source_option: true,
+            ..CodeGenOptions::default()
+        };
+        let code_gen = CodeGen::with_name("test.bf", options, &context);
+        let instructions = Parser::new(Tokenizer::new(",."))
+            .map(Result::unwrap)
+            .collect::<Vec<_>>();
+        code_gen.embed_source(b",.");
+        let ir = code_gen
+            .generate_module(&instructions)
+            .unwrap()
+            .print_to_string()
+            .to_string();
+
+        assert!(ir.contains("c\"--print-source\\00\""));
+        assert!(ir.contains("@bfSource = constant [2 x i8] c\",.\""));
+        assert!(ir
+            .lines()
+            .any(|line| line.contains("%argumentPtr = getelementptr") && line.contains(" i64 1")));
+        assert!(ir
+            .lines()
+            .any(|line| line.contains("call i32 @strcmp(") && line.contains("%load")));
+        assert!(ir.lines().any(|line| line.contains("call i64 @fwrite(")
+            && line.contains("@bfSource")
+            && line.contains("i64 1, i64 2, ")));
+    }
+
+    #[test]
+    fn output_of_known_cell_is_constant() {
+        let ir = program_ir("+++.", CodeGenOptions::default());
+
+        assert!(ir.contains("call i32 @putchar(i32 3)"));
+        // Only the increment loads the cell, the output doesn't.
+        assert_eq!(ir.matches("load i8,").count(), 1);
+        assert!(!ir.contains("zext i8 "));
+        assert!(program_ir(",.", CodeGenOptions::default()).contains("load i8,"));
+    }
+
+    #[test]
+    fn target_decides_stream_names_and_section() {
+        let ir = |triple: &str| {
+            let context = Context::create();
+            let code_gen = CodeGen::with_target(
+                Path::new("test.bf"),
+                "main",
+                &TargetTriple::create(triple),
+                CodeGenOptions::default(),
+                &context,
+            );
+            code_gen.embed_source(b".");
+            let instructions = [Instruction::new(InstructionKind::Output, LOC, LOC)];
+
+            code_gen
+                .generate_module(&instructions)
+                .unwrap()
+                .print_to_string()
+                .to_string()
+        };
+
+        let ir_for_macos = ir("x86_64-apple-macosx10.15.0");
+        assert!(ir_for_macos.contains("@__stdoutp = external global"));
+        assert!(ir_for_macos.contains("section \"__TEXT,__bf_source\""));
+
+        let ir_for_musl = ir("x86_64-unknown-linux-musl");
+        assert!(ir_for_musl.contains("@stdout = external global"));
+        assert!(ir_for_musl.contains("section \".bf_source\""));
+    }
+
+    #[test]
+    fn argv_input_passes_arguments_to_input() {
+        let options = CodeGenOptions {
+            argv_input: true,
+            ..CodeGenOptions::default()
+        };
+        let ir = program_ir(",.", options);
+
+        assert!(ir.contains("define i32 @main(i32 %argc, i8** %argv)"));
+        assert!(ir.contains("call void @setInputArgument(i32 %argc, i8** %argv)"));
+        assert!(
+            !program_ir(",.", CodeGenOptions::default()).contains("call void @setInputArgument(")
+        );
+    }
+}
@@ -0,0 +1,583 @@
+use std::{
+    fmt::{Display, Formatter, Result as FmtResult},
+    path::Path,
+};
+
+use inkwell::{
+    context::Context,
+    module::Module,
+    passes::PassBuilderOptions,
+    targets::{
+        CodeModel, FileType, InitializationConfig, RelocMode, Target, TargetMachine, TargetTriple,
+    },
+    OptimizationLevel,
+};
+use log::{log_enabled, trace, Level};
+
+use crate::{
+    analysis,
+    code_gen::{CodeGen, CodeGenOptions, InstructionBlocks},
+    error::Error,
+    instruction::{self, Instruction},
+    optimizer::{self, Explanation, Optimizer, OptimizerWarning, DEFAULT_MAX_UNROLL_ITERATIONS},
+    parser::Parser,
+    rust_gen::RustGen,
+    stats::{ProgramStats, Timings},
+    tok::{Grid, SourceLoc, TokenType, Tokenizer},
+};
+
+/// The options of `compile`, which mirror the command line options of the compiler.
+#[derive(Debug, Clone)]
+pub struct CompileOptions {
+    /// Whether the program and the generated module are optimized.
+    pub optimize: bool,
+    /// The maximum number of iterations of a loop that the optimizer unrolls completely.
+    pub max_unroll_iterations: usize,
+    /// Whether the optimizer works on the parts of the program between top-level loops in
+    /// parallel. Requires `optimize`.
+    pub parallel_optimize: bool,
+    /// Whether an explanation of every loop the optimizer rewrites is returned. Requires
+    /// `optimize`.
+    pub explain: bool,
+    /// Whether the loops the optimizer doesn't rewrite are kept as they were parsed, see
+    /// `Optimizer::with_preserve_loops`. Requires `optimize`.
+    pub preserve_loops: bool,
+    /// Whether the instructions after a loop that provably never terminates are removed.
+    pub prune_unreachable: bool,
+    /// Whether code is generated for every top-level instruction as soon as it is parsed. This
+    /// can't be combined with `parallel_optimize`, `prune_unreachable`, `stats` or `emit_rust`.
+    pub stream: bool,
+    /// Whether `=N` is an assertion that the current cell equals N instead of a comment.
+    pub assertions: bool,
+    /// The grid that `^` and `v` move up and down on, if they aren't comments.
+    pub grid: Option<Grid>,
+    /// The character that starts comment lines, which may contain commands.
+    pub comment_prefix: Option<char>,
+    /// Whether runs of `+` or `-` whose amount wraps around are reported, see
+    /// `CompileWarning::WrappingRun`.
+    pub warn_wrap: bool,
+    /// Whether the stats of the program are collected.
+    pub stats: bool,
+    /// Whether the source is stored in a section of the object file.
+    pub embed_source: bool,
+    /// Whether every instruction of the Rust program is commented with its source location.
+    pub annotate: bool,
+    /// Whether the basic blocks generated for every top-level instruction are returned. This is
+    /// ignored with `stream`.
+    pub map: bool,
+    /// Whether a listing of the instructions is returned, see `instruction::listing`. This is
+    /// ignored with `stream`.
+    pub list: bool,
+    /// Whether the instructions are returned as S-expressions, see `instruction::sexpr`. This is
+    /// ignored with `stream`.
+    pub emit_sexpr: bool,
+    pub emit_rust: bool,
+    pub emit_llvm: bool,
+    pub emit_asm: bool,
+    pub emit_object: bool,
+    /// The name of the function that executes the program.
+    pub entry_name: String,
+    /// The target triple the program is compiled for instead of the host, e.g.
+    /// `x86_64-unknown-linux-musl`. The code is generated for a generic CPU of the target then.
+    pub target: Option<String>,
+    pub code_gen: CodeGenOptions,
+}
+
+impl Default for CompileOptions {
+    fn default() -> Self {
+        Self {
+            optimize: false,
+            max_unroll_iterations: DEFAULT_MAX_UNROLL_ITERATIONS,
+            parallel_optimize: false,
+            explain: false,
+            preserve_loops: false,
+            prune_unreachable: false,
+            stream: false,
+            assertions: false,
+            grid: None,
+            comment_prefix: None,
+            warn_wrap: false,
+            stats: false,
+            embed_source: false,
+            annotate: false,
+            map: false,
+            list: false,
+            emit_sexpr: false,
+            emit_rust: false,
+            emit_llvm: false,
+            emit_asm: false,
+            emit_object: true,
+            entry_name: "main".to_owned(),
+            target: None,
+            code_gen: CodeGenOptions::default(),
+        }
+    }
+}
+
+/// Warnings about a program found while compiling it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CompileWarning {
+    Optimizer(OptimizerWarning),
+    /// The code starting at `code` is never executed, as the loop at `infinite_loop` never
+    /// terminates.
+    Unreachable {
+        code: SourceLoc,
+        infinite_loop: SourceLoc,
+    },
+    /// The program has no output, assertions or tape statistics, so running it has no visible
+    /// effect.
+    NoOutput,
+    /// The run of `count` increments or decrements starting at `loc` is folded into a single one
+    /// by `count % 256`, as cells wrap around, e.g. 256 `+` into `Increment(0)`.
+    WrappingRun {
+        loc: SourceLoc,
+        count: usize,
+        increment: bool,
+    },
+}
+
+impl Display for CompileWarning {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        match self {
+            Self::Optimizer(warning) => f.write_fmt(format_args!("{}", warning)),
+            Self::Unreachable {
+                code,
+                infinite_loop,
+            } => f.write_fmt(format_args!(
+                "the code from {} on is unreachable, as the loop at {} never terminates",
+                code, infinite_loop
+            )),
+            Self::NoOutput => f.write_str("the program never outputs anything"),
+            Self::WrappingRun {
+                loc,
+                count,
+                increment: true,
+            } => f.write_fmt(format_args!(
+                "the run of {} `+` at {} adds {} to the cell, as cells wrap around at 256",
+                count,
+                loc,
+                count % 256
+            )),
+            Self::WrappingRun {
+                loc,
+                count,
+                increment: false,
+            } => f.write_fmt(format_args!(
+                "the run of {} `-` at {} subtracts {} from the cell, as cells wrap around at 256",
+                count,
+                loc,
+                count % 256
+            )),
+        }
+    }
+}
+
+impl CompileWarning {
+    /// Returns the location the warning is about, if it is about a single one.
+    pub fn loc(&self) -> Option<SourceLoc> {
+        match self {
+            Self::Unreachable {
+                code,
+                infinite_loop: _,
+            } => Some(*code),
+            Self::WrappingRun {
+                loc,
+                count: _,
+                increment: _,
+            } => Some(*loc),
+            Self::Optimizer(_) | Self::NoOutput => None,
+        }
+    }
+}
+
+/// Everything `compile` generated, only containing the outputs requested in the options.
+#[derive(Debug, Clone)]
+pub struct CompileArtifacts {
+    pub rust: Option<String>,
+    pub llvm_ir: Option<String>,
+    pub assembly: Option<String>,
+    /// The object file, which still has to be linked with the helpers into an executable.
+    pub object: Option<Vec<u8>>,
+    /// The basic blocks generated for every top-level instruction, if `map` was requested.
+    pub map: Option<Vec<InstructionBlocks>>,
+    /// The listing of the instructions that code is generated for, if `list` was requested.
+    pub listing: Option<String>,
+    /// The instructions that code is generated for as S-expressions, if `emit_sexpr` was
+    /// requested.
+    pub sexpr: Option<String>,
+    pub warnings: Vec<CompileWarning>,
+    /// The loops the optimizer rewrote, if `explain` was requested.
+    pub explanations: Vec<Explanation>,
+    pub stats: Option<ProgramStats>,
+    /// The time spent in each phase, which can be continued by the caller, e.g. for linking.
+    pub timings: Timings,
+}
+
+/// Compiles the program in `source` for the host, or the `target` of `options`, into the outputs
+/// requested in `options`, without touching the file system. `input_file` only names the generated
+/// module.
+///
+/// Compiling is deterministic: the same source, options and name of the input file always produce
+/// byte for byte the same outputs on the same host. The object file and the assembly depend on the
+/// CPU of the host, as they are generated for it and its features, unless a `target` is given.
+pub fn compile(
+    source: &str,
+    input_file: &Path,
+    options: &CompileOptions,
+) -> Result<CompileArtifacts, Error> {
+    let mut artifacts = CompileArtifacts {
+        rust: None,
+        llvm_ir: None,
+        assembly: None,
+        object: None,
+        map: None,
+        listing: None,
+        sexpr: None,
+        warnings: Vec::new(),
+        explanations: Vec::new(),
+        stats: None,
+        timings: Timings::start(),
+    };
+
+    let context = Context::create();
+    let code_gen = CodeGen::with_target(
+        input_file,
+        &options.entry_name,
+        &target_triple(options),
+        options.code_gen,
+        &context,
+    );
+
+    if options.embed_source {
+        code_gen.embed_source(source.as_bytes());
+    }
+
+    if options.warn_wrap {
+        artifacts
+            .warnings
+            .extend(wrapping_runs(tokenizer(source, options)));
+    }
+
+    if options.stream {
+        let module = stream_program(source, options, &code_gen, &mut artifacts)?;
+        artifacts.timings.record("stream");
+
+        emit_module(module, options, &mut artifacts)?;
+
+        return Ok(artifacts);
+    }
+
+    let parsed_instructions = parse(tokenizer(source, options))?;
+    artifacts.timings.record("parse");
+
+    let (mut instructions, warnings, explanations) = if options.optimize {
+        let optimized = if options.parallel_optimize {
+            optimizer::optimize_parallel(
+                &parsed_instructions,
+                options.max_unroll_iterations,
+                explains(options),
+                options.code_gen.clamp_left,
+                options.preserve_loops,
+            )
+        } else {
+            optimize(
+                &parsed_instructions,
+                options.max_unroll_iterations,
+                explains(options),
+                options.code_gen.clamp_left,
+                options.preserve_loops,
+            )
+        };
+        artifacts.timings.record("optimize");
+
+        optimized
+    } else {
+        (parsed_instructions.clone(), Vec::new(), Vec::new())
+    };
+
+    artifacts
+        .warnings
+        .extend(warnings.into_iter().map(CompileWarning::Optimizer));
+    artifacts.explanations = take_explanations(explanations, options);
+
+    let infinite_loop = analysis::first_infinite_loop(&instructions)
+        .filter(|infinite_loop| infinite_loop + 1 < instructions.len());
+
+    if let Some(infinite_loop) = infinite_loop {
+        artifacts.warnings.push(CompileWarning::Unreachable {
+            code: instructions[infinite_loop + 1].loc,
+            infinite_loop: instructions[infinite_loop].loc,
+        });
+
+        if options.prune_unreachable {
+            instructions.truncate(infinite_loop + 1);
+        }
+    }
+
+    artifacts.stats = options
+        .stats
+        .then(|| ProgramStats::new(&parsed_instructions, &instructions));
+
+    // Assertions and tape statistics make a program's result visible without any output.
+    if !analysis::has_assertion(&instructions)
+        && !options.code_gen.report_tape_usage
+        && !analysis::has_output(&instructions)
+    {
+        artifacts.warnings.push(CompileWarning::NoOutput);
+    }
+
+    artifacts.listing = options.list.then(|| instruction::listing(&instructions));
+    artifacts.sexpr = options
+        .emit_sexpr
+        .then(|| instruction::sexpr(&instructions));
+
+    if options.emit_rust {
+        artifacts.rust = Some(
+            RustGen::new(instructions.clone())
+                .with_annotations(options.annotate)
+                .with_clamp_left(options.code_gen.clamp_left)
+                .generate_source(),
+        );
+        artifacts.timings.record("emit_rust");
+    }
+
+    if options.emit_llvm || options.emit_asm || options.emit_object {
+        let module = if options.map {
+            let (module, map) = code_gen
+                .generate_module_with_map(&instructions)
+                .map_err(|error| Error::CodeGen(error.to_string()))?;
+            artifacts.map = Some(map);
+
+            module
+        } else {
+            code_gen
+                .generate_module(&instructions)
+                .map_err(|error| Error::CodeGen(error.to_string()))?
+        };
+        artifacts.timings.record("codegen");
+
+        emit_module(module, options, &mut artifacts)?;
+    }
+
+    Ok(artifacts)
+}
+
+/// Parses the program that `tokenizer` splits into tokens.
+pub fn parse(tokenizer: Tokenizer) -> Result<Vec<Instruction>, Error> {
+    let instructions = Parser::new(tokenizer).collect::<Result<Vec<_>, _>>()?;
+
+    Ok(instructions)
+}
+
+/// Returns a tokenizer for `source` with the directives enabled in `options`.
+fn tokenizer<'a>(source: &'a str, options: &CompileOptions) -> Tokenizer<'a> {
+    Tokenizer::new(source)
+        .with_assertions(options.assertions)
+        .with_grid(options.grid)
+        .with_comment_prefix(options.comment_prefix)
+}
+
+/// Returns a warning for every run of `+` or `-` in `tokenizer` that is longer than 255, which the
+/// parser folds into an instruction whose amount differs from the length of the run.
+fn wrapping_runs(tokenizer: Tokenizer) -> Vec<CompileWarning> {
+    let mut warnings = Vec::new();
+    let mut tokens = tokenizer.peekable();
+
+    while let Some(token) = tokens.next() {
+        if !matches!(
+            token.token_type,
+            TokenType::Increment | TokenType::Decrement
+        ) {
+            continue;
+        }
+
+        // The parser folds exactly the same runs, see `InstructionKind::parse_change_cell`.
+        let mut count = 1;
+        while tokens
+            .next_if(|next| next.token_type == token.token_type)
+            .is_some()
+        {
+            count += 1;
+        }
+
+        if count > u8::MAX as usize {
+            warnings.push(CompileWarning::WrappingRun {
+                loc: token.loc,
+                count,
+                increment: token.token_type == TokenType::Increment,
+            });
+        }
+    }
+
+    warnings
+}
+
+/// Optimizes `instructions`, returning the optimized instructions together with the warnings the
+/// optimizer found and, if `explain` is set, the loops it rewrote. `clamp_left` must match the
+/// option of the same name the program is run with, see `CodeGenOptions::clamp_left`. With
+/// `preserve_loops`, the loops that aren't rewritten keep their bodies as they were parsed.
+pub fn optimize(
+    instructions: &[Instruction],
+    max_unroll_iterations: usize,
+    explain: bool,
+    clamp_left: bool,
+    preserve_loops: bool,
+) -> (Vec<Instruction>, Vec<OptimizerWarning>, Vec<Explanation>) {
+    let mut optimizer = Optimizer::new_with_clamp_left(instructions.iter().cloned(), clamp_left)
+        .with_max_unroll_iterations(max_unroll_iterations)
+        .with_explanations(explain)
+        .with_preserve_loops(preserve_loops);
+    let instructions = optimizer.by_ref().collect();
+
+    (
+        instructions,
+        optimizer.warnings().to_vec(),
+        optimizer.explanations().to_vec(),
+    )
+}
+
+/// Whether the optimizer records the loops it rewrites, which are logged at the trace level as
+/// well.
+fn explains(options: &CompileOptions) -> bool {
+    options.explain || log_enabled!(Level::Trace)
+}
+
+/// Logs the loops the optimizer rewrote and returns them if `explain` was requested.
+fn take_explanations(explanations: Vec<Explanation>, options: &CompileOptions) -> Vec<Explanation> {
+    for explanation in &explanations {
+        trace!("rewrote {}", explanation);
+    }
+
+    if options.explain {
+        explanations
+    } else {
+        Vec::new()
+    }
+}
+
+/// Parses, optimizes and generates code for the program in a single pass with `stream`.
+fn stream_program<'a, 'b>(
+    source: &str,
+    options: &CompileOptions,
+    code_gen: &'b CodeGen<'a>,
+    artifacts: &mut CompileArtifacts,
+) -> Result<&'b Module<'a>, Error> {
+    // Code generation stops at the first parse error, which is reported instead of the module.
+    let mut parse_error = None;
+    let instructions = Parser::new(tokenizer(source, options))
+        .map_while(|result| result.map_err(|error| parse_error = Some(error)).ok());
+
+    let module = if options.optimize {
+        let mut optimizer =
+            Optimizer::new_with_clamp_left(instructions, options.code_gen.clamp_left)
+                .with_max_unroll_iterations(options.max_unroll_iterations)
+                .with_explanations(explains(options))
+                .with_preserve_loops(options.preserve_loops);
+        let module = code_gen.generate_module_streaming(optimizer.by_ref());
+
+        artifacts.warnings.extend(
+            optimizer
+                .warnings()
+                .iter()
+                .copied()
+                .map(CompileWarning::Optimizer),
+        );
+        artifacts.explanations = take_explanations(optimizer.explanations().to_vec(), options);
+
+        module
+    } else {
+        code_gen.generate_module_streaming(instructions)
+    };
+
+    if let Some(error) = parse_error {
+        return Err(error.into());
+    }
+
+    module.map_err(|error| Error::CodeGen(error.to_string()))
+}
+
+/// Returns the triple of the `target` of `options`, or the one of the host without it.
+fn target_triple(options: &CompileOptions) -> TargetTriple {
+    options
+        .target
+        .as_deref()
+        .map_or_else(TargetMachine::get_default_triple, TargetTriple::create)
+}
+
+/// Runs the LLVM passes on `module` and adds the requested outputs generated from it to
+/// `artifacts`.
+fn emit_module(
+    module: &Module,
+    options: &CompileOptions,
+    artifacts: &mut CompileArtifacts,
+) -> Result<(), Error> {
+    let triple = target_triple(options);
+    let (cpu, features) = match &options.target {
+        Some(_) => {
+            Target::initialize_all(&InitializationConfig::default());
+
+            ("generic".to_owned(), String::new())
+        }
+        None => {
+            Target::initialize_native(&InitializationConfig::default()).map_err(Error::CodeGen)?;
+
+            (
+                TargetMachine::get_host_cpu_name().to_string(),
+                TargetMachine::get_host_cpu_features().to_string(),
+            )
+        }
+    };
+
+    let (optimization_level, passes) = if options.optimize {
+        (OptimizationLevel::Default, "default<O2>")
+    } else {
+        (OptimizationLevel::None, "default<O0>")
+    };
+
+    let target = Target::from_triple(&triple).map_err(|error| Error::CodeGen(error.to_string()))?;
+    let target_machine = target
+        .create_target_machine(
+            &triple,
+            &cpu,
+            &features,
+            optimization_level,
+            RelocMode::PIC,
+            CodeModel::Default,
+        )
+        .ok_or_else(|| {
+            Error::CodeGen(format!(
+                "unsupported target {}",
+                triple.as_str().to_string_lossy()
+            ))
+        })?;
+
+    module
+        .run_passes(passes, &target_machine, PassBuilderOptions::create())
+        .map_err(|error| Error::CodeGen(error.to_string()))?;
+    artifacts.timings.record("llvm_passes");
+
+    let write_to_memory = |file_type| {
+        target_machine
+            .write_to_memory_buffer(module, file_type)
+            .map(|buffer| buffer.as_slice().to_vec())
+            .map_err(|error| Error::CodeGen(error.to_string()))
+    };
+
+    if options.emit_llvm {
+        artifacts.llvm_ir = Some(module.print_to_string().to_string());
+        artifacts.timings.record("emit_llvm");
+    }
+
+    if options.emit_asm {
+        let assembly = write_to_memory(FileType::Assembly)?;
+        artifacts.assembly = Some(String::from_utf8_lossy(&assembly).into_owned());
+        artifacts.timings.record("emit_asm");
+    }
+
+    if options.emit_object {
+        artifacts.object = Some(write_to_memory(FileType::Object)?);
+        artifacts.timings.record("emit_object");
+    }
+
+    Ok(())
+}
@@ -0,0 +1,231 @@
+use std::{
+    env,
+    fmt::Display,
+    io::{self, IsTerminal},
+};
+
+use clap::ValueEnum;
+
+use crate::{stats::json_string, tok::SourceLoc};
+
+const RESET: &str = "\x1b[0m";
+const BOLD_RED: &str = "\x1b[1;31m";
+const BOLD_YELLOW: &str = "\x1b[1;33m";
+const BOLD_CYAN: &str = "\x1b[1;36m";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum ColorChoice {
+    Auto,
+    Always,
+    Never,
+}
+
+impl ColorChoice {
+    /// Decides whether diagnostics are colored.
+    ///
+    /// `Auto` colors them only if stderr is a terminal and the `NO_COLOR` environment variable
+    /// isn't set to a non-empty value, see <https://no-color.org>.
+    pub fn use_color(self) -> bool {
+        match self {
+            Self::Auto => {
+                let no_color = env::var_os("NO_COLOR").is_some_and(|value| !value.is_empty());
+
+                !no_color && io::stderr().is_terminal()
+            }
+            Self::Always => true,
+            Self::Never => false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    Error,
+    Warning,
+    Note,
+}
+
+impl Severity {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Error => "error",
+            Self::Warning => "warning",
+            Self::Note => "note",
+        }
+    }
+
+    fn color(self) -> &'static str {
+        match self {
+            Self::Error => BOLD_RED,
+            Self::Warning => BOLD_YELLOW,
+            Self::Note => BOLD_CYAN,
+        }
+    }
+}
+
+/// An error, warning or note about a program or the compiler's invocation.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub message: String,
+    /// Where in the program the diagnostic applies, if anywhere. The message names the location as
+    /// well, so that it reads the same without it.
+    pub loc: Option<SourceLoc>,
+    /// The location right after the part of the program the diagnostic applies to, if it applies
+    /// to a span starting at `loc` instead of a single location.
+    pub end: Option<SourceLoc>,
+}
+
+impl Diagnostic {
+    pub fn new(severity: Severity, message: impl Display) -> Self {
+        Self {
+            severity,
+            message: message.to_string(),
+            loc: None,
+            end: None,
+        }
+    }
+
+    pub fn error(message: impl Display) -> Self {
+        Self::new(Severity::Error, message)
+    }
+
+    pub fn warning(message: impl Display) -> Self {
+        Self::new(Severity::Warning, message)
+    }
+
+    pub fn note(message: impl Display) -> Self {
+        Self::new(Severity::Note, message)
+    }
+
+    pub fn with_loc(mut self, loc: Option<SourceLoc>) -> Self {
+        self.loc = loc;
+        self
+    }
+
+    /// Sets the span from `loc` up to `end` that the diagnostic applies to.
+    pub fn with_span(mut self, loc: SourceLoc, end: SourceLoc) -> Self {
+        self.loc = Some(loc);
+        self.end = Some(end);
+        self
+    }
+
+    /// Returns the diagnostic as a single-line JSON object with the keys `severity`, `message`,
+    /// `line` and `col`, which are `null` without a location, and `end_line` and `end_col`, which
+    /// are `null` without a span.
+    pub fn to_json(&self) -> String {
+        let (line, col) = json_loc(self.loc);
+        let (end_line, end_col) = json_loc(self.end);
+
+        format!(
+            "{{\"severity\":{},\"message\":{},\"line\":{},\"col\":{},\"end_line\":{},\"end_col\":{}}}",
+            json_string(self.severity.as_str()),
+            json_string(&self.message),
+            line,
+            col,
+            end_line,
+            end_col
+        )
+    }
+}
+
+/// Returns the line and column of `loc` as JSON values, which are `null` without it.
+fn json_loc(loc: Option<SourceLoc>) -> (String, String) {
+    match loc {
+        Some(loc) => (loc.line.to_string(), loc.col.to_string()),
+        None => ("null".to_owned(), "null".to_owned()),
+    }
+}
+
+/// Receives the diagnostics found while compiling or running a program, so that every diagnostic
+/// takes the same way, whether it is printed or collected.
+pub trait DiagnosticsSink {
+    fn emit(&mut self, diagnostic: Diagnostic);
+}
+
+impl DiagnosticsSink for Vec<Diagnostic> {
+    fn emit(&mut self, diagnostic: Diagnostic) {
+        self.push(diagnostic);
+    }
+}
+
+/// Prints errors, warnings and notes to stderr, highlighting their level with ANSI colors if enabled,
+/// or as one JSON object per line.
+#[derive(Debug, Clone, Copy)]
+pub struct Diagnostics {
+    color: bool,
+    json: bool,
+}
+
+impl Diagnostics {
+    pub fn new(color: bool) -> Self {
+        Self { color, json: false }
+    }
+
+    /// Prints every diagnostic as a JSON object on its own line, see `Diagnostic::to_json`, which
+    /// is never colored.
+    pub fn with_json(mut self, json: bool) -> Self {
+        self.json = json;
+        self
+    }
+
+    pub fn error(&self, message: impl Display) {
+        self.print(&Diagnostic::error(message));
+    }
+
+    pub fn warning(&self, message: impl Display) {
+        self.print(&Diagnostic::warning(message));
+    }
+
+    pub fn note(&self, message: impl Display) {
+        self.print(&Diagnostic::note(message));
+    }
+
+    pub fn print(&self, diagnostic: &Diagnostic) {
+        eprintln!("{}", self.render(diagnostic));
+    }
+
+    fn render(&self, diagnostic: &Diagnostic) -> String {
+        let severity = diagnostic.severity;
+
+        if self.json {
+            diagnostic.to_json()
+        } else if self.color {
+            format!(
+                "{}{}{}: {}",
+                severity.color(),
+                severity.as_str(),
+                RESET,
+                diagnostic.message
+            )
+        } else {
+            format!("{}: {}", severity.as_str(), diagnostic.message)
+        }
+    }
+}
+
+impl DiagnosticsSink for Diagnostics {
+    fn emit(&mut self, diagnostic: Diagnostic) {
+        self.print(&diagnostic);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn color_choice() {
+        let diagnostic = Diagnostic::error("unexpected loop end at 1:2");
+
+        let colored = Diagnostics::new(ColorChoice::Always.use_color()).render(&diagnostic);
+        assert_eq!(
+            colored,
+            "\x1b[1;31merror\x1b[0m: unexpected loop end at 1:2"
+        );
+
+        let plain = Diagnostics::new(ColorChoice::Never.use_color()).render(&diagnostic);
+        assert_eq!(plain, "error: unexpected loop end at 1:2");
+        assert!(!plain.contains('\x1b'));
+    }
+}
@@ -0,0 +1,140 @@
+use std::{
+    error::Error as StdError,
+    fmt::{Display, Formatter, Result as FmtResult},
+    io,
+    path::PathBuf,
+    process::ExitCode,
+};
+
+use crate::{
+    interpreter::RuntimeError, parser::ParseError, preprocessor::PreprocessError, tok::SourceLoc,
+};
+
+/// The exit codes the compiler uses to report failures, so that scripts can tell the different
+/// kinds of failures apart.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorCode {
+    /// A file couldn't be read or written.
+    Io = 1,
+    /// The input isn't a valid Brainfuck program, or its directives can't be expanded.
+    Parse = 2,
+    /// Compiling the helpers or linking the executable failed.
+    Link = 3,
+    /// LLVM failed to generate code for the target.
+    CodeGen = 4,
+    /// The interpreted program failed at runtime.
+    Runtime = 5,
+}
+
+impl From<ErrorCode> for ExitCode {
+    fn from(code: ErrorCode) -> Self {
+        ExitCode::from(code as u8)
+    }
+}
+
+#[derive(Debug)]
+pub enum Error {
+    Io(PathBuf, io::Error),
+    Parse(ParseError),
+    Preprocess(PreprocessError),
+    Link(String),
+    CodeGen(String),
+    Runtime(RuntimeError),
+    /// Some of the input files compiled with `--keep-going` failed, `code` being the first
+    /// failure's.
+    Batch {
+        failed: usize,
+        total: usize,
+        code: ErrorCode,
+    },
+}
+
+impl Error {
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            Self::Io(_, _) => ErrorCode::Io,
+            Self::Parse(_) | Self::Preprocess(_) => ErrorCode::Parse,
+            Self::Link(_) => ErrorCode::Link,
+            Self::CodeGen(_) => ErrorCode::CodeGen,
+            Self::Runtime(_) => ErrorCode::Runtime,
+            Self::Batch { code, .. } => *code,
+        }
+    }
+
+    /// Returns where in the program the error occurred, if it is about a location in it.
+    pub fn loc(&self) -> Option<SourceLoc> {
+        match self {
+            Self::Parse(error) => Some(error.loc()),
+            _ => None,
+        }
+    }
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        match self {
+            Self::Io(path, error) => f.write_fmt(format_args!("{}: {}", path.display(), error)),
+            Self::Parse(error) => f.write_fmt(format_args!("{}", error)),
+            Self::Preprocess(error) => f.write_fmt(format_args!("{}", error)),
+            Self::Link(message) => f.write_fmt(format_args!("linking failed: {}", message)),
+            Self::CodeGen(message) => {
+                f.write_fmt(format_args!("code generation failed: {}", message))
+            }
+            Self::Runtime(error) => f.write_fmt(format_args!("{}", error)),
+            Self::Batch { failed, total, .. } => f.write_fmt(format_args!(
+                "{} of {} input files failed to compile",
+                failed, total
+            )),
+        }
+    }
+}
+
+impl StdError for Error {}
+
+impl From<ParseError> for Error {
+    fn from(error: ParseError) -> Self {
+        Self::Parse(error)
+    }
+}
+
+impl From<PreprocessError> for Error {
+    fn from(error: PreprocessError) -> Self {
+        match error {
+            PreprocessError::Io(path, error) => Self::Io(path, error),
+            error => Self::Preprocess(error),
+        }
+    }
+}
+
+impl From<RuntimeError> for Error {
+    fn from(error: RuntimeError) -> Self {
+        Self::Runtime(error)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LOC: SourceLoc = SourceLoc {
+        line: 1,
+        col: 1,
+        offset: 0,
+    };
+
+    #[test]
+    fn parse_error_code() {
+        let error = Error::from(ParseError::UnexpectedLoopEnd(LOC));
+
+        assert_eq!(error.code(), ErrorCode::Parse);
+        assert_eq!(error.code() as u8, 2);
+    }
+
+    #[test]
+    fn link_error_code() {
+        let error = Error::Link("clang exited with exit status: 1".to_owned());
+
+        assert_eq!(error.code(), ErrorCode::Link);
+        assert_eq!(error.code() as u8, 3);
+    }
+}
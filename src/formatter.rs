@@ -0,0 +1,109 @@
+use crate::tok::{TokenType, Tokenizer};
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct FormatOptions {
+    /// The column at which lines are wrapped.
+    pub width: Option<usize>,
+    /// If set, loop brackets are put on their own lines and loop bodies are indented by this many
+    /// spaces per nesting level.
+    pub indent: Option<usize>,
+}
+
+/// Re-emits a program as canonical Brainfuck, stripping all comments.
+pub struct Formatter<'a> {
+    tokenizer: Tokenizer<'a>,
+    options: FormatOptions,
+}
+
+impl<'a> Formatter<'a> {
+    pub fn new(tokenizer: Tokenizer<'a>, options: FormatOptions) -> Self {
+        Self { tokenizer, options }
+    }
+
+    pub fn format(self) -> String {
+        let mut output = String::new();
+        let mut line = String::new();
+        let mut depth = 0;
+
+        for token in self.tokenizer {
+            match (self.options.indent, token.token_type) {
+                (Some(_), TokenType::LoopStart) => {
+                    self.options.finish_line(&mut output, &mut line, depth);
+                    line.push('[');
+                    self.options.finish_line(&mut output, &mut line, depth);
+
+                    depth += 1;
+                }
+                (Some(_), TokenType::LoopEnd) => {
+                    self.options.finish_line(&mut output, &mut line, depth);
+
+                    depth = depth.saturating_sub(1);
+
+                    line.push(']');
+                    self.options.finish_line(&mut output, &mut line, depth);
+                }
+                (_, token_type) => {
+                    line.push_str(&token_type.to_string());
+
+                    if let Some(width) = self.options.width {
+                        if line.len() + self.options.indentation(depth) >= width {
+                            self.options.finish_line(&mut output, &mut line, depth);
+                        }
+                    }
+                }
+            }
+        }
+
+        self.options.finish_line(&mut output, &mut line, depth);
+
+        output
+    }
+}
+
+impl FormatOptions {
+    fn indentation(&self, depth: usize) -> usize {
+        self.indent.unwrap_or(0) * depth
+    }
+
+    fn finish_line(&self, output: &mut String, line: &mut String, depth: usize) {
+        if line.is_empty() {
+            return;
+        }
+
+        output.push_str(&" ".repeat(self.indentation(depth)));
+        output.push_str(line);
+        output.push('\n');
+
+        line.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn format(source: &str, width: Option<usize>, indent: Option<usize>) -> String {
+        Formatter::new(Tokenizer::new(source), FormatOptions { width, indent }).format()
+    }
+
+    #[test]
+    fn comments_are_stripped() {
+        assert_eq!(
+            format("+ + [ > ] comment\nmore. text", None, None),
+            "++[>].\n"
+        );
+    }
+
+    #[test]
+    fn lines_wrap_at_width() {
+        assert_eq!(format("+++++++++++", Some(4), None), "++++\n++++\n+++\n");
+    }
+
+    #[test]
+    fn loops_are_indented() {
+        assert_eq!(
+            format("++[>+[-<]>]..", None, Some(2)),
+            "++\n[\n  >+\n  [\n    -<\n  ]\n  >\n]\n..\n"
+        );
+    }
+}
@@ -1,23 +1,239 @@
-use std::fmt::{Debug, Formatter, Result as FmtResult};
+use std::{
+    fmt::{Debug, Display, Formatter, Result as FmtResult, Write},
+    ops::Range,
+};
+
+use crate::tok::SourceLoc;
 
 #[derive(Clone, PartialEq, Eq)]
-pub enum Instruction {
-    MoveRight { amount: usize },
-    MoveLeft { amount: usize },
-    Increment { amount: u8 },
-    Decrement { amount: u8 },
+pub struct Instruction {
+    pub kind: InstructionKind,
+    /// Where the instruction starts in the source. Instructions the optimizer creates from others
+    /// take over the location of the first instruction they replace, e.g. the `[` of a loop.
+    pub loc: SourceLoc,
+    /// The location right after the last character of the instruction in the source. Instructions
+    /// the optimizer creates from others end where the last instruction they replace ends, e.g.
+    /// after the `]` of a loop.
+    pub end: SourceLoc,
+}
+
+impl Instruction {
+    pub fn new(kind: InstructionKind, loc: SourceLoc, end: SourceLoc) -> Self {
+        Self { kind, loc, end }
+    }
+
+    /// Returns the range of bytes of the source the instruction was created from, e.g. to
+    /// annotate it with the optimized instruction in an editor.
+    pub fn span(&self) -> Range<usize> {
+        self.loc.offset..self.end.offset
+    }
+
+    /// Returns a one-line description of the instruction and its location, e.g.
+    /// `MoveRight(3) at 1:1`. The bodies of loops are left out.
+    pub fn summary(&self) -> String {
+        match &self.kind {
+            InstructionKind::Loop { instructions: _ } => format!("Loop at {}", self.loc),
+            InstructionKind::WithMultiplier { instructions: _ } => {
+                format!("WithMultiplier at {}", self.loc)
+            }
+            kind => format!("{:?} at {}", kind, self.loc),
+        }
+    }
+}
+
+impl Debug for Instruction {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        self.kind.fmt(f)
+    }
+}
+
+#[derive(Clone, PartialEq, Eq)]
+pub enum InstructionKind {
+    MoveRight {
+        amount: usize,
+    },
+    MoveLeft {
+        amount: usize,
+    },
+    Increment {
+        amount: u8,
+    },
+    Decrement {
+        amount: u8,
+    },
     Output,
     Input,
-    Loop { instructions: Vec<Instruction> },
-    MoveRightUntilZero { step_size: usize },
-    MoveLeftUntilZero { step_size: usize },
+    Loop {
+        instructions: Vec<Instruction>,
+    },
+    MoveRightUntilZero {
+        step_size: usize,
+    },
+    MoveLeftUntilZero {
+        step_size: usize,
+    },
+    /// Outputs the current cell and moves right by `step_size` until the current cell is zero, e.g.
+    /// to print a null-terminated string.
+    OutputUntilZero {
+        step_size: usize,
+    },
     SetToZero,
-    WithMultiplier { instructions: Vec<Instruction> },
-    MoveValueRight { amount: usize },
-    MoveValueLeft { amount: usize },
+    SetValue {
+        value: u8,
+    },
+    /// Clears the `len` cells starting `start_offset` cells right of the current one without moving
+    /// the pointer, e.g. the cells of `[-]>[-]>[-]<<`. The program fails like a move left of the
+    /// first cell if the range starts there.
+    ClearRange {
+        start_offset: isize,
+        len: usize,
+    },
+    WithMultiplier {
+        instructions: Vec<Instruction>,
+    },
+    MoveValueRight {
+        amount: usize,
+    },
+    MoveValueLeft {
+        amount: usize,
+    },
+    /// Sets the cell `offset` cells right of the current one to the current cell and clears the
+    /// current cell without moving the pointer, e.g. `>[-]<[->+<]`. Unlike `MoveValueRight`, it
+    /// doesn't add to the destination, so the program fails like a move left of the first cell
+    /// whenever the destination is there, even if the current cell is zero.
+    TransferValue {
+        offset: isize,
+    },
+    AssertEquals {
+        value: u8,
+    },
+    /// Does nothing. Optimizer passes replace instructions by it to delete them, and the optimizer
+    /// strips it from its output, so it never reaches code generation.
+    Nop,
 }
 
-impl Debug for Instruction {
+/// Returns an assembler-style listing of `instructions` with one instruction per line: its address,
+/// i.e. its position when walking the program including the bodies of loops, the instruction and
+/// its location. The bodies of loops follow the loop, indented by two spaces per level, e.g.
+///
+/// ```text
+/// 0000  Increment(2)                    1:1
+/// 0001  Loop                            1:3
+/// 0002    Decrement(1)                  1:4
+/// ```
+pub fn listing(instructions: &[Instruction]) -> String {
+    let mut listing = String::new();
+    push_listing(&mut listing, instructions, 0, &mut 0);
+
+    listing
+}
+
+fn push_listing(
+    listing: &mut String,
+    instructions: &[Instruction],
+    depth: usize,
+    address: &mut usize,
+) {
+    for instruction in instructions {
+        let (name, body) = match &instruction.kind {
+            InstructionKind::Loop { instructions } => ("Loop".to_owned(), Some(instructions)),
+            InstructionKind::WithMultiplier { instructions } => {
+                ("WithMultiplier".to_owned(), Some(instructions))
+            }
+            kind => (format!("{:?}", kind), None),
+        };
+
+        let indented_name = format!("{}{}", "  ".repeat(depth), name);
+        writeln!(
+            listing,
+            "{:04}  {:<31} {}",
+            address, indented_name, instruction.loc
+        )
+        .unwrap();
+        *address += 1;
+
+        if let Some(body) = body {
+            push_listing(listing, body, depth + 1, address);
+        }
+    }
+}
+
+/// Returns `instructions` as S-expressions, one line per top-level instruction, with the bodies of
+/// loops nested inside them, e.g. `(loop (move-value-right 1) (set-zero))`. Locations are left
+/// out, so that the result only changes if the instructions do.
+///
+/// This format is stable. Every instruction is a list of its name and its arguments, which are
+/// decimal integers:
+///
+/// ```text
+/// (move-right AMOUNT)            (move-left AMOUNT)
+/// (increment AMOUNT)             (decrement AMOUNT)
+/// (output)                       (input)
+/// (loop BODY...)                 (with-multiplier BODY...)
+/// (move-right-until-zero STEP)   (move-left-until-zero STEP)
+/// (output-until-zero STEP)       (set-zero)
+/// (set-value VALUE)              (clear-range START-OFFSET LENGTH)
+/// (move-value-right AMOUNT)      (move-value-left AMOUNT)
+/// (transfer-value OFFSET)        (assert-equals VALUE)
+/// (nop)
+/// ```
+pub fn sexpr(instructions: &[Instruction]) -> String {
+    let mut sexpr = String::new();
+
+    for instruction in instructions {
+        push_sexpr(&mut sexpr, instruction);
+        sexpr.push('\n');
+    }
+
+    sexpr
+}
+
+fn push_sexpr(sexpr: &mut String, instruction: &Instruction) {
+    let (name, arguments): (_, &[&dyn Display]) = match &instruction.kind {
+        InstructionKind::Loop { instructions } => return push_body(sexpr, "loop", instructions),
+        InstructionKind::WithMultiplier { instructions } => {
+            return push_body(sexpr, "with-multiplier", instructions)
+        }
+        InstructionKind::MoveRight { amount } => ("move-right", &[amount]),
+        InstructionKind::MoveLeft { amount } => ("move-left", &[amount]),
+        InstructionKind::Increment { amount } => ("increment", &[amount]),
+        InstructionKind::Decrement { amount } => ("decrement", &[amount]),
+        InstructionKind::Output => ("output", &[]),
+        InstructionKind::Input => ("input", &[]),
+        InstructionKind::MoveRightUntilZero { step_size } => {
+            ("move-right-until-zero", &[step_size])
+        }
+        InstructionKind::MoveLeftUntilZero { step_size } => ("move-left-until-zero", &[step_size]),
+        InstructionKind::OutputUntilZero { step_size } => ("output-until-zero", &[step_size]),
+        InstructionKind::SetToZero => ("set-zero", &[]),
+        InstructionKind::SetValue { value } => ("set-value", &[value]),
+        InstructionKind::ClearRange { start_offset, len } => ("clear-range", &[start_offset, len]),
+        InstructionKind::MoveValueRight { amount } => ("move-value-right", &[amount]),
+        InstructionKind::MoveValueLeft { amount } => ("move-value-left", &[amount]),
+        InstructionKind::TransferValue { offset } => ("transfer-value", &[offset]),
+        InstructionKind::AssertEquals { value } => ("assert-equals", &[value]),
+        InstructionKind::Nop => ("nop", &[]),
+    };
+
+    sexpr.push('(');
+    sexpr.push_str(name);
+    for argument in arguments {
+        write!(sexpr, " {}", argument).unwrap();
+    }
+    sexpr.push(')');
+}
+
+fn push_body(sexpr: &mut String, name: &str, body: &[Instruction]) {
+    sexpr.push('(');
+    sexpr.push_str(name);
+    for instruction in body {
+        sexpr.push(' ');
+        push_sexpr(sexpr, instruction);
+    }
+    sexpr.push(')');
+}
+
+impl Debug for InstructionKind {
     fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
         match self {
             Self::MoveRight { amount } => f.write_fmt(format_args!("MoveRight({})", amount)),
@@ -33,7 +249,14 @@ impl Debug for Instruction {
             Self::MoveLeftUntilZero { step_size } => {
                 f.write_fmt(format_args!("MoveLeftUntilZero({})", step_size))
             }
+            Self::OutputUntilZero { step_size } => {
+                f.write_fmt(format_args!("OutputUntilZero({})", step_size))
+            }
             Self::SetToZero => f.write_str("SetToZero"),
+            Self::SetValue { value } => f.write_fmt(format_args!("SetValue({})", value)),
+            Self::ClearRange { start_offset, len } => {
+                f.write_fmt(format_args!("ClearRange({}, {})", start_offset, len))
+            }
             Self::WithMultiplier { instructions } => {
                 f.write_fmt(format_args!("WithMultiplier({:#?})", instructions))
             }
@@ -43,6 +266,11 @@ impl Debug for Instruction {
             Self::MoveValueLeft { amount } => {
                 f.write_fmt(format_args!("MoveValueLeft({})", amount))
             }
+            Self::TransferValue { offset } => {
+                f.write_fmt(format_args!("TransferValue({})", offset))
+            }
+            Self::AssertEquals { value } => f.write_fmt(format_args!("AssertEquals({})", value)),
+            Self::Nop => f.write_str("Nop"),
         }
     }
 }
@@ -0,0 +1,407 @@
+use std::{
+    error::Error,
+    fmt::{Display, Formatter, Result as FmtResult},
+    io::{self, Read, Write},
+};
+
+use crate::{
+    code_gen::{CodeGenOptions, INITIAL_TAPE_LENGTH},
+    instruction::{Instruction, InstructionKind},
+};
+
+#[derive(Debug)]
+pub enum RuntimeError {
+    NegativeCell,
+    AssertionFailed {
+        cell: usize,
+        expected: u8,
+        actual: u8,
+    },
+    TapeLimitExceeded {
+        limit: usize,
+    },
+    /// The tape couldn't grow to this many bytes, as it doesn't fit into memory.
+    TapeAllocationFailed {
+        bytes: usize,
+    },
+    Io(io::Error),
+}
+
+impl Display for RuntimeError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        match self {
+            Self::NegativeCell => f.write_str("cannot move pointer to negative cell"),
+            Self::AssertionFailed {
+                cell,
+                expected,
+                actual,
+            } => f.write_fmt(format_args!(
+                "assertion failed at cell {}: expected {}, found {}",
+                cell, expected, actual
+            )),
+            Self::TapeLimitExceeded { limit } => f.write_fmt(format_args!(
+                "tape would grow beyond the limit of {} bytes",
+                limit
+            )),
+            Self::TapeAllocationFailed { bytes } => {
+                f.write_fmt(format_args!("cannot allocate a tape of {} bytes", bytes))
+            }
+            Self::Io(error) => f.write_fmt(format_args!("I/O error: {}", error)),
+        }
+    }
+}
+
+impl Error for RuntimeError {}
+
+impl From<io::Error> for RuntimeError {
+    fn from(error: io::Error) -> Self {
+        Self::Io(error)
+    }
+}
+
+/// Interprets `instructions` with the given input, returning everything the program outputs.
+pub fn interpret(instructions: &[Instruction], input: &[u8]) -> Result<Vec<u8>, RuntimeError> {
+    let mut output = Vec::new();
+    interpret_with_io(instructions, input, &mut output)?;
+
+    Ok(output)
+}
+
+/// Interprets `instructions`, reading from `input` for every `,` and writing to `output` for every
+/// `.`.
+///
+/// The semantics match the compiled program: the tape starts with 256 cells and grows to the next
+/// power of two of cells to the right as needed, moving left of the first cell is an error, and
+/// reading at the end of the input stores 0 in the current cell.
+pub fn interpret_with_io(
+    instructions: &[Instruction],
+    input: impl Read,
+    output: impl Write,
+) -> Result<(), RuntimeError> {
+    interpret_with_options(instructions, &CodeGenOptions::default(), input, output)
+}
+
+/// Interprets `instructions` like `interpret_with_io`, but matches the program compiled with
+/// `options`, so that both can be compared directly.
+///
+/// Only `max_tape_bytes` and `clamp_left` change what a program does. The other options only affect
+/// how the compiled program writes its output and cleans up, which isn't observable here.
+pub fn interpret_with_options(
+    instructions: &[Instruction],
+    options: &CodeGenOptions,
+    input: impl Read,
+    output: impl Write,
+) -> Result<(), RuntimeError> {
+    let mut interpreter = Interpreter {
+        cells: vec![0; INITIAL_TAPE_LENGTH],
+        current_cell: 0,
+        max_tape_bytes: options.max_tape_bytes,
+        clamp_left: options.clamp_left,
+        input,
+        output,
+    };
+
+    interpreter.run(instructions, None)
+}
+
+struct Interpreter<R, W> {
+    cells: Vec<u8>,
+    current_cell: usize,
+    max_tape_bytes: Option<usize>,
+    clamp_left: bool,
+    input: R,
+    output: W,
+}
+
+impl<R, W> Interpreter<R, W>
+where
+    R: Read,
+    W: Write,
+{
+    fn run(
+        &mut self,
+        instructions: &[Instruction],
+        multiplier: Option<u8>,
+    ) -> Result<(), RuntimeError> {
+        for instruction in instructions {
+            self.run_instruction(instruction, multiplier)?;
+        }
+
+        Ok(())
+    }
+
+    fn run_instruction(
+        &mut self,
+        instruction: &Instruction,
+        multiplier: Option<u8>,
+    ) -> Result<(), RuntimeError> {
+        match &instruction.kind {
+            InstructionKind::MoveRight { amount } => self.move_right(*amount)?,
+            InstructionKind::MoveLeft { amount } if self.clamp_left => {
+                self.current_cell = self.current_cell.saturating_sub(*amount)
+            }
+            InstructionKind::MoveLeft { amount } => self.move_left(*amount)?,
+            InstructionKind::Increment { amount } => {
+                let amount = amount.wrapping_mul(multiplier.unwrap_or(1));
+                self.set(self.get().wrapping_add(amount));
+            }
+            InstructionKind::Decrement { amount } => {
+                let amount = amount.wrapping_mul(multiplier.unwrap_or(1));
+                self.set(self.get().wrapping_sub(amount));
+            }
+            InstructionKind::Output => {
+                self.output.write_all(&[self.get()])?;
+                self.output.flush()?;
+            }
+            InstructionKind::OutputUntilZero { step_size } => {
+                while self.get() != 0 {
+                    self.output.write_all(&[self.get()])?;
+                    self.move_right(*step_size)?;
+                }
+
+                self.output.flush()?;
+            }
+            InstructionKind::Input => {
+                let mut byte = [0];
+                let value = match self.input.read(&mut byte)? {
+                    0 => 0,
+                    _ => byte[0],
+                };
+
+                self.set(value);
+            }
+            InstructionKind::Loop { instructions } => {
+                while self.get() != 0 {
+                    self.run(instructions, None)?;
+                }
+            }
+            InstructionKind::MoveRightUntilZero { step_size } => {
+                while self.get() != 0 {
+                    self.move_right(*step_size)?;
+                }
+            }
+            InstructionKind::MoveLeftUntilZero { step_size } => {
+                while self.get() != 0 {
+                    self.move_left(*step_size)?;
+                }
+            }
+            InstructionKind::SetToZero => self.set(0),
+            InstructionKind::SetValue { value } => self.set(*value),
+            InstructionKind::ClearRange { start_offset, len } => {
+                self.clear_range(*start_offset, *len)?
+            }
+            InstructionKind::WithMultiplier { instructions } => {
+                let multiplier = self.get();
+
+                if multiplier != 0 {
+                    self.run(instructions, Some(multiplier))?;
+                    self.set(0);
+                }
+            }
+            InstructionKind::MoveValueRight { amount } => {
+                let value = self.get();
+
+                if value != 0 {
+                    self.set(0);
+                    self.move_right(*amount)?;
+                    self.set(self.get().wrapping_add(value));
+                    self.move_left(*amount)?;
+                }
+            }
+            InstructionKind::MoveValueLeft { amount } => {
+                let value = self.get();
+
+                if value != 0 {
+                    self.set(0);
+                    self.move_left(*amount)?;
+                    self.set(self.get().wrapping_add(value));
+                    self.move_right(*amount)?;
+                }
+            }
+            InstructionKind::TransferValue { offset } => self.transfer_value(*offset)?,
+            InstructionKind::AssertEquals { value } => {
+                if self.get() != *value {
+                    return Err(RuntimeError::AssertionFailed {
+                        cell: self.current_cell,
+                        expected: *value,
+                        actual: self.get(),
+                    });
+                }
+            }
+            InstructionKind::Nop => {}
+        }
+
+        Ok(())
+    }
+
+    fn get(&self) -> u8 {
+        self.cells[self.current_cell]
+    }
+
+    fn set(&mut self, value: u8) {
+        self.cells[self.current_cell] = value;
+    }
+
+    /// Moves the pointer to the right, growing the tape like the `moveRight` helper of the
+    /// compiled program does.
+    fn move_right(&mut self, amount: usize) -> Result<(), RuntimeError> {
+        self.current_cell += amount;
+
+        if self.current_cell >= self.cells.len() {
+            self.grow_cells(self.current_cell + 1)?;
+        }
+
+        Ok(())
+    }
+
+    /// Grows the tape to the next power of two of `minimum_length` cells, or to the limit if that
+    /// is smaller, like the `growCells` helper. Instead of aborting, a tape that doesn't fit into
+    /// memory is an error, just like one beyond the limit.
+    fn grow_cells(&mut self, minimum_length: usize) -> Result<(), RuntimeError> {
+        // No tape can be larger than `isize::MAX` bytes, and the next power of two of a larger
+        // length doesn't fit into a `usize`.
+        if minimum_length > isize::MAX as usize {
+            return Err(RuntimeError::TapeAllocationFailed {
+                bytes: minimum_length,
+            });
+        }
+
+        let mut length = minimum_length.next_power_of_two();
+
+        // A limit of 0 means unlimited, like in the compiled program.
+        if let Some(limit) = self.max_tape_bytes.filter(|limit| *limit != 0) {
+            if minimum_length > limit {
+                return Err(RuntimeError::TapeLimitExceeded { limit });
+            }
+
+            length = length.min(limit);
+        }
+
+        self.cells
+            .try_reserve_exact(length - self.cells.len())
+            .map_err(|_| RuntimeError::TapeAllocationFailed { bytes: length })?;
+        self.cells.resize(length, 0);
+
+        Ok(())
+    }
+
+    /// Clears `len` cells starting `start_offset` cells right of the current one, growing the tape
+    /// like the `clearRange` helper does.
+    fn clear_range(&mut self, start_offset: isize, len: usize) -> Result<(), RuntimeError> {
+        let start = if start_offset < 0 {
+            self.current_cell
+                .checked_sub(start_offset.unsigned_abs())
+                .ok_or(RuntimeError::NegativeCell)?
+        } else {
+            self.current_cell + start_offset as usize
+        };
+
+        if start + len > self.cells.len() {
+            self.grow_cells(start + len)?;
+        }
+
+        self.cells[start..start + len].fill(0);
+
+        Ok(())
+    }
+
+    /// Sets the cell `offset` cells right of the current one to the current cell and clears the
+    /// current cell, like the `transferValue` helper does.
+    fn transfer_value(&mut self, offset: isize) -> Result<(), RuntimeError> {
+        let value = self.get();
+
+        // Clearing the destination first checks that it is on the tape and grows the tape to it.
+        self.clear_range(offset, 1)?;
+        self.set(0);
+        self.cells[self.current_cell.wrapping_add_signed(offset)] = value;
+
+        Ok(())
+    }
+
+    fn move_left(&mut self, amount: usize) -> Result<(), RuntimeError> {
+        self.current_cell = self
+            .current_cell
+            .checked_sub(amount)
+            .ok_or(RuntimeError::NegativeCell)?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{parser::Parser, tok::Tokenizer};
+
+    fn parse(source: &str) -> Vec<Instruction> {
+        Parser::new(Tokenizer::new(source))
+            .map(Result::unwrap)
+            .collect()
+    }
+
+    #[test]
+    fn output_is_written_to_sink() {
+        let mut output = Vec::new();
+        interpret_with_io(
+            &parse("++++++++[>++++++++<-]>+.+.,+."),
+            &b"a"[..],
+            &mut output,
+        )
+        .unwrap();
+
+        assert_eq!(output, b"ABb");
+    }
+
+    #[test]
+    fn growing_past_tape_limit_fails() {
+        let options = CodeGenOptions {
+            max_tape_bytes: Some(512),
+            ..CodeGenOptions::default()
+        };
+        let instructions = parse(&format!("{}+", ">".repeat(600)));
+
+        let result = interpret_with_options(&instructions, &options, &b""[..], Vec::new());
+        assert!(matches!(
+            result,
+            Err(RuntimeError::TapeLimitExceeded { limit: 512 })
+        ));
+
+        let instructions = parse(&format!("{}+", ">".repeat(500)));
+        assert!(interpret_with_options(&instructions, &options, &b""[..], Vec::new()).is_ok());
+    }
+
+    #[test]
+    fn input_at_end_stores_zero() {
+        // Like the `input` helper of the compiled program, which stores 0 at the end of the input.
+        assert_eq!(interpret(&parse(",."), b"").unwrap(), b"\0");
+        assert_eq!(interpret(&parse("+,."), b"").unwrap(), b"\0");
+        assert_eq!(interpret(&parse(",.,."), b"a").unwrap(), b"a\0");
+    }
+
+    #[test]
+    fn clamp_left_stays_at_first_cell() {
+        let options = CodeGenOptions {
+            clamp_left: true,
+            ..CodeGenOptions::default()
+        };
+        let mut output = Vec::new();
+        interpret_with_options(&parse("+<<<+.>+.<."), &options, &b""[..], &mut output).unwrap();
+
+        assert_eq!(output, [2, 1, 2]);
+        assert!(matches!(
+            interpret(&parse("+<"), b""),
+            Err(RuntimeError::NegativeCell)
+        ));
+    }
+
+    #[test]
+    fn grown_cells_are_zero() {
+        let source = format!(
+            "+{}.<.{}.",
+            ">".repeat(INITIAL_TAPE_LENGTH * 3),
+            ">".repeat(5000)
+        );
+
+        assert_eq!(interpret(&parse(&source), b"").unwrap(), [0, 0, 0]);
+    }
+}
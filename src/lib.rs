@@ -0,0 +1,16 @@
+pub mod analysis;
+#[cfg(feature = "capi")]
+pub mod capi;
+pub mod code_gen;
+pub mod compiler;
+pub mod diagnostics;
+pub mod error;
+pub mod formatter;
+pub mod instruction;
+pub mod interpreter;
+pub mod optimizer;
+pub mod parser;
+pub mod preprocessor;
+pub mod rust_gen;
+pub mod stats;
+pub mod tok;
@@ -1,155 +1,1473 @@
-use clap::Parser as ArgumentParser;
+use clap::{
+    error::ErrorKind, ArgAction, Args, CommandFactory, Parser as ArgumentParser, Subcommand,
+    ValueEnum,
+};
 use tempfile::Builder as TempFileBuilder;
 
+use object::{Object, ObjectSymbol};
 use path_absolutize::*;
 use std::{
-    fmt::Debug,
+    collections::hash_map::DefaultHasher,
+    env::consts::{DLL_EXTENSION, DLL_PREFIX},
+    fmt::{Debug, Display},
+    fs::File,
+    hash::{Hash, Hasher},
+    io::{self, BufRead, Write},
+    iter,
+    mem::ManuallyDrop,
+    os::unix::io::FromRawFd,
     path::{Path, PathBuf},
-    process::Command,
-};
-
-use inkwell::{
-    context::Context,
-    passes::PassBuilderOptions,
-    targets::{CodeModel, FileType, InitializationConfig, RelocMode, Target, TargetMachine},
-    OptimizationLevel,
+    process::{Command, ExitCode},
 };
 
-use crate::{optimizer::Optimizer, parser::Parser, tok::Tokenizer};
+use inkwell::targets::{InitializationConfig, Target, TargetMachine, TargetTriple};
+use log::{debug, info, Level, LevelFilter};
 
-mod code_gen;
-mod instruction;
-mod optimizer;
-mod parser;
-mod tok;
+use brainfuck_rs::{
+    code_gen::{CodeGenOptions, InstructionBlocks, TapeAllocation},
+    compiler::{self, CompileOptions},
+    diagnostics::{ColorChoice, Diagnostic, Diagnostics, DiagnosticsSink},
+    error::Error,
+    formatter::{FormatOptions, Formatter},
+    interpreter::{self, RuntimeError},
+    optimizer::{Explanation, DEFAULT_MAX_UNROLL_ITERATIONS},
+    parser::Parser,
+    preprocessor,
+    stats::{Report, ReportFormat, Timings},
+    tok::{Grid, Tokenizer},
+};
 
 #[derive(ArgumentParser)]
-#[command(author, version, about)]
+#[command(
+    author,
+    version,
+    about,
+    args_conflicts_with_subcommands = true,
+    subcommand_negates_reqs = true
+)]
 /// A Brainfuck to executable compiler
 struct Arguments {
-    input_file: String,
+    #[command(subcommand)]
+    command: Option<Commands>,
+    #[command(flatten)]
+    build: BuildArguments,
+    /// When to color errors and warnings, `auto` respects NO_COLOR
+    #[arg(long, value_enum, global = true, default_value_t = ColorChoice::Auto)]
+    color: ColorChoice,
+    /// The format of errors, warnings and notes, `json` prints one object per line
+    #[arg(long = "diagnostic-format", value_enum, global = true, default_value_t = ReportFormat::Human)]
+    diagnostic_format: ReportFormat,
+    /// Log what the compiler does on stderr, -v for its stages and commands, -vv for every loop
+    /// the optimizer rewrites as well
+    #[arg(short, long, action = ArgAction::Count, global = true)]
+    verbose: u8,
+}
+
+#[derive(Args)]
+struct BuildArguments {
+    #[arg(required_unless_present = "list_targets")]
+    input_files: Vec<String>,
     #[arg(short, long)]
     output_file: Option<String>,
+    /// Optimize the program, overriding the BFC_OPT environment variable
+    #[arg(short = 'O', long = "optimize", conflicts_with = "no_optimize")]
+    optimize: bool,
+    /// Don't optimize the program, overriding the BFC_OPT environment variable
+    #[arg(long = "no-optimize")]
+    no_optimize: bool,
+    /// Replace loops that are known to run at most this many times by copies of their body, 0
+    /// disables this
+    #[arg(long = "unroll-limit", value_name = "ITERATIONS", default_value_t = DEFAULT_MAX_UNROLL_ITERATIONS)]
+    unroll_limit: usize,
+    /// Optimize the parts of the program between top-level loops in parallel
+    #[arg(long = "parallel-optimize")]
+    parallel_optimize: bool,
+    /// Print every loop the optimizer rewrites, with its body, the result and the reason
+    #[arg(long)]
+    explain: bool,
+    /// Keep the loops the optimizer can't rewrite into other instructions exactly as written
+    #[arg(long = "preserve-loops")]
+    preserve_loops: bool,
+    /// Remove the instructions after a loop that provably never terminates
+    #[arg(long = "prune-unreachable")]
+    prune_unreachable: bool,
+    /// Generate code for every top-level instruction as soon as it is parsed, instead of holding
+    /// the whole program in memory
+    #[arg(long)]
+    stream: bool,
+    /// Don't free the tape before exiting, leaving it to the operating system
+    #[arg(long = "no-free")]
+    no_free: bool,
+    /// Buffer output in a buffer of this many bytes instead of flushing after every character,
+    /// 0 disables buffering
+    #[arg(long = "output-buffer", value_name = "BYTES")]
+    output_buffer: Option<usize>,
+    /// Allocate the input buffer with this many bytes at program start instead of growing it with
+    /// the first line of input
+    #[arg(long = "input-buffer", value_name = "BYTES")]
+    input_buffer: Option<usize>,
+    /// Abort the program if its tape would grow beyond this many bytes, instead of the size of the
+    /// --grid
+    #[arg(long = "max-tape", visible_alias = "mem-limit", value_name = "BYTES")]
+    max_tape: Option<usize>,
+    /// How the tape is allocated, `mmap` only commits the pages of the tape that are used
+    #[arg(long = "tape-alloc", value_enum, default_value_t = TapeAllocation::Calloc)]
+    tape_alloc: TapeAllocation,
+    /// Keep the pointer at the first cell when moving left of it instead of aborting the program
+    #[arg(long = "clamp-left")]
+    clamp_left: bool,
+    /// Print statistics about the instructions and make the program report its peak tape usage
+    /// when it exits
+    #[arg(long)]
+    stats: bool,
+    /// Print how long each phase of the compilation took
+    #[arg(long)]
+    timings: bool,
+    /// The format of the --stats and --timings reports
+    #[arg(long, value_enum, default_value_t = ReportFormat::Human)]
+    format: ReportFormat,
+    /// Write output with fputc(c, stdout) instead of putchar(c)
+    #[arg(long = "use-fputc")]
+    use_fputc: bool,
+    /// Write the output of the program to this file descriptor instead of stdout
+    #[arg(long = "output-fd", value_name = "FD")]
+    output_fd: Option<i32>,
+    /// Emit a standalone Rust program instead of an executable
+    #[arg(long = "emit-rust")]
+    emit_rust: bool,
+    /// Also write the optimized LLVM IR next to the output file, with the extension .ll
+    #[arg(long = "emit-llvm")]
+    emit_llvm: bool,
+    /// Also write the assembly next to the output file, with the extension .s
+    #[arg(long = "emit-asm")]
+    emit_asm: bool,
+    /// Print the clang command that links the executable instead of running it, keeping the object
+    /// file it links
+    #[arg(long = "dry-run")]
+    dry_run: bool,
+    /// Print the symbols the object file defines and references before linking it
+    #[arg(long = "dump-symbols")]
+    dump_symbols: bool,
+    /// Keep the temporary object file the executable is linked from
+    #[arg(long = "save-temps")]
+    save_temps: bool,
+    /// Compile the helpers and link the executable with these sanitizers, separated by commas
+    #[arg(long, value_enum, value_delimiter = ',', value_name = "SANITIZERS")]
+    sanitize: Vec<Sanitizer>,
+    /// Check that every access of the tape is within it, reporting the instruction otherwise, which
+    /// slows the program down considerably
+    #[arg(long = "debug-bounds")]
+    debug_bounds: bool,
+    /// Link a position-independent executable, instead of following the convention of the platform
+    #[arg(long)]
+    pie: bool,
+    /// Link a position-dependent executable, instead of following the convention of the platform
+    #[arg(long = "no-pie")]
+    no_pie: bool,
+    /// Compile for this target triple instead of the host, e.g. x86_64-unknown-linux-musl
+    #[arg(long, value_name = "TRIPLE")]
+    target: Option<String>,
+    /// Link the executable statically, including the C library
+    #[arg(long = "static")]
+    static_link: bool,
+    /// Also write a listing of the instructions with their addresses and locations next to the
+    /// output file, with the extension .lst
+    #[arg(long)]
+    list: bool,
+    /// Also write the instructions as S-expressions next to the output file, with the extension
+    /// .sexpr
+    #[arg(long = "emit-sexpr")]
+    emit_sexpr: bool,
+    /// Write which LLVM basic blocks were generated for every top-level instruction to this file
+    #[arg(long = "map-file", value_name = "PATH")]
+    map_file: Option<String>,
+    /// Link a shared library exporting a function that executes the program instead of an
+    /// executable
+    #[arg(long)]
+    shared: bool,
+    /// The name of the function exported from the shared library
+    #[arg(
+        long = "entry-name",
+        value_name = "NAME",
+        requires = "shared",
+        default_value = "main"
+    )]
+    entry_name: String,
+    /// Store the source of the program in a section of the executable
+    #[arg(long = "embed-source")]
+    embed_source: bool,
+    /// Let the executable print the embedded source instead of running the program if its first
+    /// argument is --print-source
+    #[arg(long = "print-source-option", requires = "embed_source")]
+    print_source_option: bool,
+    /// Let `,` read the bytes of the executable's first argument instead of stdin
+    #[arg(long = "argv-input")]
+    argv_input: bool,
+    /// Comment every instruction in the emitted program with its source location
+    #[arg(long, requires = "emit_rust")]
+    annotate: bool,
+    #[command(flatten)]
+    source: SourceArguments,
+    /// Don't print warnings about the program
+    #[arg(short, long)]
+    quiet: bool,
+    /// Warn about runs of more than 255 `+` or `-`, whose amount wraps around
+    #[arg(long = "warn-wrap")]
+    warn_wrap: bool,
+    /// Keep compiling the remaining input files after one of them fails
+    #[arg(long = "keep-going")]
+    keep_going: bool,
+    /// Print the targets LLVM was built with, marking the one programs are compiled for, and exit
+    #[arg(long = "list-targets")]
+    list_targets: bool,
+}
+
+// The options of how a program is read, which all commands that read one from a file share. This
+// isn't a doc comment, as clap would use it as the about of the commands it is flattened into.
+#[derive(Debug, Args)]
+struct SourceArguments {
+    /// Treat `=N` as an assertion that the current cell equals N instead of a comment
+    #[arg(long)]
+    assertions: bool,
+    /// Treat `^` and `v` as moves up and down a grid of this size laid out row by row on the tape,
+    /// which also limits the tape to the grid
+    #[arg(long, value_name = "WIDTHxHEIGHT")]
+    grid: Option<Grid>,
+    /// Ignore every line starting with this character, even if it contains commands
+    #[arg(long = "comment-prefix", value_name = "CHAR")]
+    comment_prefix: Option<char>,
+    /// Expand `@include` and `@define` directives and `@NAME` macros before reading the program
+    #[arg(long)]
+    preprocess: bool,
+}
+
+/// The sanitizers of clang that the helpers and the executable can be built with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum Sanitizer {
+    Address,
+    Undefined,
+}
+
+#[derive(Debug, Subcommand)]
+enum Commands {
+    /// Compile a Brainfuck program into an executable, which passing the input file without a
+    /// subcommand does as well
+    Build(BuildArguments),
+    /// Check a Brainfuck program for errors and report its warnings without generating code
+    Check(CheckArguments),
+    /// Print the LLVM IR, the assembly or the Rust program generated for a Brainfuck program
+    Emit(EmitArguments),
+    /// Reformat a Brainfuck program as canonical Brainfuck without comments
+    Fmt(FmtArguments),
+    /// Run a Brainfuck program with the interpreter instead of compiling it
+    Run(RunArguments),
+    /// Run every line of stdin as a separate Brainfuck program with the interpreter, with the
+    /// input of the program after a `!`
+    Serve(ServeArguments),
+}
+
+#[derive(Debug, Args)]
+struct FmtArguments {
+    input_file: String,
+    /// Wrap lines at this column
+    #[arg(short, long, value_name = "COLUMNS")]
+    width: Option<usize>,
+    /// Put loop brackets on their own lines and indent loop bodies by this many spaces
+    #[arg(short, long, value_name = "SPACES")]
+    indent: Option<usize>,
+    #[command(flatten)]
+    source: SourceArguments,
+}
+
+#[derive(Debug, Args)]
+struct CheckArguments {
+    input_file: String,
+    /// Keep the pointer at the first cell when moving left of it instead of aborting the program
+    #[arg(long = "clamp-left")]
+    clamp_left: bool,
+    /// Warn about runs of more than 255 `+` or `-`, whose amount wraps around
+    #[arg(long = "warn-wrap")]
+    warn_wrap: bool,
+    #[command(flatten)]
+    source: SourceArguments,
+}
+
+#[derive(Debug, Args)]
+struct EmitArguments {
+    #[arg(value_enum)]
+    kind: EmitKind,
+    input_file: String,
+    #[arg(short = 'O', long = "optimize")]
+    optimize: bool,
+    /// Keep the pointer at the first cell when moving left of it instead of aborting the program
+    #[arg(long = "clamp-left")]
+    clamp_left: bool,
+    #[command(flatten)]
+    source: SourceArguments,
+}
+
+/// The outputs `emit` prints.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum EmitKind {
+    /// The optimized LLVM IR
+    Llvm,
+    /// The assembly
+    Asm,
+    /// A standalone Rust program
+    Rust,
+    /// The instructions as S-expressions
+    Sexpr,
+}
+
+#[derive(Debug, Args)]
+struct ServeArguments {
     #[arg(short = 'O', long = "optimize")]
     optimize: bool,
+    /// Treat `=N` as an assertion that the current cell equals N instead of a comment
+    #[arg(long)]
+    assertions: bool,
+}
+
+#[derive(Debug, Args)]
+struct RunArguments {
+    input_file: String,
+    #[arg(short = 'O', long = "optimize")]
+    optimize: bool,
+    /// Print every loop the optimizer rewrites, with its body, the result and the reason
+    #[arg(long, requires = "optimize")]
+    explain: bool,
+    /// Keep the loops the optimizer can't rewrite into other instructions exactly as written
+    #[arg(long = "preserve-loops", requires = "optimize")]
+    preserve_loops: bool,
+    /// Keep the pointer at the first cell when moving left of it instead of aborting the program
+    #[arg(long = "clamp-left")]
+    clamp_left: bool,
+    /// Write the output of the program to this file descriptor instead of stdout
+    #[arg(long = "output-fd", value_name = "FD")]
+    output_fd: Option<i32>,
+    #[command(flatten)]
+    source: SourceArguments,
 }
 
 impl Arguments {
-    fn get_input_file(&self) -> PathBuf {
-        Path::new(&self.input_file)
-            .absolutize()
-            .unwrap()
-            .into_owned()
+    fn diagnostics(&self) -> Diagnostics {
+        Diagnostics::new(self.color.use_color())
+            .with_json(self.diagnostic_format == ReportFormat::Json)
     }
 
-    fn get_output_file(&self) -> PathBuf {
+    /// Sets up the logger for the level of `--verbose`. Messages at the info level, e.g. the
+    /// generated files, are printed as they are and are always shown.
+    fn init_logger(&self) {
+        let level = match self.verbose {
+            0 => LevelFilter::Info,
+            1 => LevelFilter::Debug,
+            _ => LevelFilter::Trace,
+        };
+
+        env_logger::Builder::new()
+            .filter_level(level)
+            .format(|buf, record| match record.level() {
+                Level::Info => writeln!(buf, "{}", record.args()),
+                level => writeln!(
+                    buf,
+                    "{}: {}",
+                    level.as_str().to_ascii_lowercase(),
+                    record.args()
+                ),
+            })
+            .init();
+    }
+}
+
+impl SourceArguments {
+    /// Returns a tokenizer for `input` with the directives these options enable.
+    fn tokenizer<'a>(&self, input: &'a str) -> Tokenizer<'a> {
+        Tokenizer::new(input)
+            .with_assertions(self.assertions)
+            .with_grid(self.grid)
+            .with_comment_prefix(self.comment_prefix)
+    }
+
+    /// Returns the options for compiling a program read with these options, which only generate
+    /// the object file.
+    fn get_compile_options(&self) -> CompileOptions {
+        CompileOptions {
+            assertions: self.assertions,
+            grid: self.grid,
+            comment_prefix: self.comment_prefix,
+            code_gen: CodeGenOptions {
+                max_tape_bytes: self.grid.map(|grid| grid.cells()),
+                ..CodeGenOptions::default()
+            },
+            ..CompileOptions::default()
+        }
+    }
+}
+
+impl BuildArguments {
+    fn get_input_files(&self) -> Vec<PathBuf> {
+        self.input_files
+            .iter()
+            .map(|input_file| Path::new(input_file).absolutize().unwrap().into_owned())
+            .collect()
+    }
+
+    fn get_output_file(&self, input_file: &Path) -> PathBuf {
         match &self.output_file {
             Some(file) => Path::new(&file).absolutize().unwrap().into_owned(),
-            None => self.get_input_file().with_extension(""),
+            None if self.emit_rust => input_file.with_extension("rs"),
+            None if self.shared => {
+                let library_name = format!(
+                    "{}{}.{}",
+                    DLL_PREFIX,
+                    input_file.file_stem().unwrap().to_string_lossy(),
+                    DLL_EXTENSION
+                );
+
+                input_file.with_file_name(library_name)
+            }
+            None => input_file.with_extension(""),
         }
     }
 
-    fn get_optimization_level(&self) -> OptimizationLevel {
-        if self.optimize {
-            OptimizationLevel::Default
-        } else {
-            OptimizationLevel::None
+    /// Fills in defaults from the environment for options that weren't passed on the command line.
+    ///
+    /// `-O` and `--no-optimize` take precedence. Without either of them, the `BFC_OPT` environment
+    /// variable decides whether to optimize, and without that, the program isn't optimized.
+    fn with_env_defaults(mut self, diagnostics: Diagnostics) -> Self {
+        if !self.optimize && !self.no_optimize {
+            self.optimize = optimize_from_env(diagnostics).unwrap_or(false);
         }
+
+        self
     }
 
-    fn get_optimization_passes(&self) -> &str {
-        if self.optimize {
-            "default<O2>"
-        } else {
-            "default<O0>"
+    /// Checks for combinations of options that would otherwise be silently ignored, but that clap
+    /// can't reject on its own, because they depend on the environment or on several options.
+    ///
+    /// Returns a message describing the first conflict found.
+    fn check_conflicts(&self) -> Result<(), String> {
+        let conflicts = [
+            (
+                self.parallel_optimize && !self.optimize,
+                "--parallel-optimize requires optimizations, enable them with -O or BFC_OPT",
+            ),
+            (
+                self.explain && !self.optimize,
+                "--explain requires optimizations, enable them with -O or BFC_OPT",
+            ),
+            (
+                self.preserve_loops && !self.optimize,
+                "--preserve-loops requires optimizations, enable them with -O or BFC_OPT",
+            ),
+            (
+                self.format != ReportFormat::Human && !self.stats && !self.timings,
+                "--format requires --stats or --timings",
+            ),
+            (
+                self.dry_run && self.emit_rust,
+                "--dry-run can't be used with --emit-rust, which doesn't link an executable",
+            ),
+            (
+                self.dump_symbols && self.emit_rust,
+                "--dump-symbols can't be used with --emit-rust, which doesn't emit an object file",
+            ),
+            (
+                self.shared && self.emit_rust,
+                "--shared can't be used with --emit-rust, which doesn't link a library",
+            ),
+            (
+                self.print_source_option && self.shared,
+                "--print-source-option can't be used with --shared, whose entry takes no arguments",
+            ),
+            (
+                self.argv_input && self.shared,
+                "--argv-input can't be used with --shared, whose entry takes no arguments",
+            ),
+            (
+                self.save_temps && self.emit_rust,
+                "--save-temps can't be used with --emit-rust, which doesn't link an executable",
+            ),
+            (
+                !self.sanitize.is_empty() && self.emit_rust,
+                "--sanitize can't be used with --emit-rust, which doesn't link an executable",
+            ),
+            (self.pie && self.no_pie, "--pie can't be used with --no-pie"),
+            (
+                (self.pie || self.no_pie) && self.emit_rust,
+                "--pie and --no-pie can't be used with --emit-rust, which doesn't link an executable",
+            ),
+            (
+                (self.pie || self.no_pie) && self.shared,
+                "--pie and --no-pie can't be used with --shared, which doesn't link an executable",
+            ),
+            (
+                self.static_link && self.emit_rust,
+                "--static can't be used with --emit-rust, which doesn't link an executable",
+            ),
+            (
+                self.static_link && self.shared,
+                "--static can't be used with --shared, which is linked dynamically",
+            ),
+            (
+                self.static_link && self.pie,
+                "--static can't be used with --pie, which needs the dynamic loader",
+            ),
+            (
+                self.static_link && !self.sanitize.is_empty(),
+                "--static can't be used with --sanitize, whose runtimes are linked dynamically",
+            ),
+            (
+                self.stream && self.parallel_optimize,
+                "--stream can't be used with --parallel-optimize, which needs the whole program",
+            ),
+            (
+                self.stream && self.stats,
+                "--stream can't be used with --stats, which needs the whole program",
+            ),
+            (
+                self.stream && self.prune_unreachable,
+                "--stream can't be used with --prune-unreachable, which needs the whole program",
+            ),
+            (
+                self.stream && self.emit_rust,
+                "--stream can't be used with --emit-rust, which needs the whole program",
+            ),
+            (
+                self.stream && self.map_file.is_some(),
+                "--stream can't be used with --map-file, which needs the whole program",
+            ),
+            (
+                self.stream && self.list,
+                "--stream can't be used with --list, which needs the whole program",
+            ),
+            (
+                self.stream && self.emit_sexpr,
+                "--stream can't be used with --emit-sexpr, which needs the whole program",
+            ),
+            (
+                self.input_files.len() > 1 && self.output_file.is_some(),
+                "--output-file can't be used with several input files, which each need their own",
+            ),
+            (
+                self.input_files.len() > 1 && self.map_file.is_some(),
+                "--map-file can't be used with several input files, which each need their own",
+            ),
+        ];
+
+        if let Some((_, message)) = conflicts.into_iter().find(|(conflict, _)| *conflict) {
+            return Err(message.to_owned());
+        }
+
+        // The code generation options also shape the LLVM IR and the assembly, so they only have no
+        // effect if the Rust program is the only output.
+        if self.emit_rust && !self.emit_llvm && !self.emit_asm {
+            let code_gen_options = [
+                ("--no-free", self.no_free),
+                ("--output-buffer", self.output_buffer.is_some()),
+                ("--input-buffer", self.input_buffer.is_some()),
+                ("--max-tape", self.max_tape.is_some()),
+                ("--tape-alloc", self.tape_alloc != TapeAllocation::Calloc),
+                ("--use-fputc", self.use_fputc),
+                ("--output-fd", self.output_fd.is_some()),
+                ("--embed-source", self.embed_source),
+                ("--print-source-option", self.print_source_option),
+                ("--argv-input", self.argv_input),
+                ("--map-file", self.map_file.is_some()),
+                ("--debug-bounds", self.debug_bounds),
+                ("--target", self.target.is_some()),
+            ];
+
+            if let Some((option, _)) = code_gen_options.into_iter().find(|(_, set)| *set) {
+                return Err(format!(
+                    "{} has no effect on the Rust program generated with --emit-rust",
+                    option
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Returns the path of an additional artifact, which is named like the output file, but with
+    /// `extension`.
+    fn get_artifact_file(&self, input_file: &Path, extension: &str) -> PathBuf {
+        self.get_output_file(input_file).with_extension(extension)
+    }
+
+    /// Returns the triple of `--target`, or the one of the host without it.
+    fn get_target_triple(&self) -> TargetTriple {
+        self.target
+            .as_deref()
+            .map_or_else(TargetMachine::get_default_triple, TargetTriple::create)
+    }
+
+    /// Returns the `-fsanitize` flag for the sanitizers of `--sanitize`, if there are any.
+    fn get_sanitize_flag(&self) -> Option<String> {
+        let sanitizers = self
+            .sanitize
+            .iter()
+            .map(|sanitizer| sanitizer.to_possible_value().unwrap().get_name().to_owned())
+            .collect::<Vec<_>>();
+
+        (!sanitizers.is_empty()).then(|| format!("-fsanitize={}", sanitizers.join(",")))
+    }
+
+    fn get_compile_options(&self) -> CompileOptions {
+        CompileOptions {
+            optimize: self.optimize,
+            max_unroll_iterations: self.unroll_limit,
+            parallel_optimize: self.parallel_optimize,
+            explain: self.explain,
+            preserve_loops: self.preserve_loops,
+            prune_unreachable: self.prune_unreachable,
+            stream: self.stream,
+            assertions: self.source.assertions,
+            grid: self.source.grid,
+            comment_prefix: self.source.comment_prefix,
+            warn_wrap: self.warn_wrap,
+            stats: self.stats,
+            embed_source: self.embed_source,
+            annotate: self.annotate,
+            map: self.map_file.is_some(),
+            list: self.list,
+            emit_sexpr: self.emit_sexpr,
+            emit_rust: self.emit_rust,
+            emit_llvm: self.emit_llvm,
+            emit_asm: self.emit_asm,
+            emit_object: !self.emit_rust,
+            entry_name: self.entry_name.clone(),
+            target: self.target.clone(),
+            code_gen: self.get_code_gen_options(),
+        }
+    }
+
+    fn get_code_gen_options(&self) -> CodeGenOptions {
+        CodeGenOptions {
+            free_memory: !self.no_free,
+            tape_allocation: self.tape_alloc,
+            output_buffer_size: self.output_buffer,
+            input_buffer_size: self.input_buffer,
+            max_tape_bytes: self.max_tape.or(self.source.grid.map(|grid| grid.cells())),
+            clamp_left: self.clamp_left,
+            report_tape_usage: self.stats,
+            use_fputc: self.use_fputc,
+            output_fd: self.output_fd,
+            print_source_option: self.print_source_option,
+            argv_input: self.argv_input,
+            debug_bounds: self.debug_bounds,
         }
     }
 }
 
-impl Debug for Arguments {
+impl Debug for BuildArguments {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        f.debug_struct("Arguments")
-            .field("input_file", &self.get_input_file())
-            .field("output_file", &self.get_output_file())
+        let input_files = self.get_input_files();
+        let output_files = input_files
+            .iter()
+            .map(|input_file| self.get_output_file(input_file))
+            .collect::<Vec<_>>();
+
+        f.debug_struct("BuildArguments")
+            .field("input_files", &input_files)
+            .field("output_files", &output_files)
+            .field("keep_going", &self.keep_going)
             .field("optimize", &self.optimize)
+            .field("no_optimize", &self.no_optimize)
+            .field("unroll_limit", &self.unroll_limit)
+            .field("parallel_optimize", &self.parallel_optimize)
+            .field("explain", &self.explain)
+            .field("preserve_loops", &self.preserve_loops)
+            .field("prune_unreachable", &self.prune_unreachable)
+            .field("stream", &self.stream)
+            .field("no_free", &self.no_free)
+            .field("output_buffer", &self.output_buffer)
+            .field("input_buffer", &self.input_buffer)
+            .field("max_tape", &self.max_tape)
+            .field("tape_alloc", &self.tape_alloc)
+            .field("clamp_left", &self.clamp_left)
+            .field("stats", &self.stats)
+            .field("timings", &self.timings)
+            .field("format", &self.format)
+            .field("use_fputc", &self.use_fputc)
+            .field("output_fd", &self.output_fd)
+            .field("emit_rust", &self.emit_rust)
+            .field("emit_llvm", &self.emit_llvm)
+            .field("emit_asm", &self.emit_asm)
+            .field("dry_run", &self.dry_run)
+            .field("dump_symbols", &self.dump_symbols)
+            .field("save_temps", &self.save_temps)
+            .field("sanitize", &self.sanitize)
+            .field("target", &self.target)
+            .field("static_link", &self.static_link)
+            .field("debug_bounds", &self.debug_bounds)
+            .field("pie", &self.pie)
+            .field("no_pie", &self.no_pie)
+            .field("shared", &self.shared)
+            .field("entry_name", &self.entry_name)
+            .field("list", &self.list)
+            .field("emit_sexpr", &self.emit_sexpr)
+            .field("map_file", &self.map_file)
+            .field("embed_source", &self.embed_source)
+            .field("print_source_option", &self.print_source_option)
+            .field("argv_input", &self.argv_input)
+            .field("annotate", &self.annotate)
+            .field("source", &self.source)
+            .field("quiet", &self.quiet)
+            .field("warn_wrap", &self.warn_wrap)
+            .field("list_targets", &self.list_targets)
             .finish()
     }
 }
 
-fn main() {
+/// Reads the default for whether to optimize from the `BFC_OPT` environment variable, which accepts
+/// the levels `0` to `3` as well as `true`/`false`, `on`/`off` and `yes`/`no`.
+///
+/// Returns `None` if the variable isn't set or has an unsupported value.
+fn optimize_from_env(diagnostics: Diagnostics) -> Option<bool> {
+    let value = std::env::var("BFC_OPT").ok()?;
+
+    match value.trim().to_ascii_lowercase().as_str() {
+        "0" | "false" | "off" | "no" => Some(false),
+        "1" | "2" | "3" | "true" | "on" | "yes" => Some(true),
+        _ => {
+            diagnostics.warning(format_args!(
+                "ignoring unsupported value {:?} of BFC_OPT",
+                value
+            ));
+
+            None
+        }
+    }
+}
+
+fn main() -> ExitCode {
     let args = Arguments::parse();
-    let input_file_path = args.get_input_file();
+    args.init_logger();
+    let mut diagnostics = args.diagnostics();
+
+    // The options of `build` without a subcommand are a shorthand for it.
+    let result = match args.command.unwrap_or(Commands::Build(args.build)) {
+        Commands::Build(build_args) => build(build_args, diagnostics),
+        Commands::Check(check_args) => check(&check_args, diagnostics),
+        Commands::Emit(emit_args) => {
+            emit(&emit_args, diagnostics).map(|output| print!("{}", output))
+        }
+        Commands::Fmt(fmt_args) => format(&fmt_args).map(|source| print!("{}", source)),
+        Commands::Run(run_args) => run(&run_args, diagnostics),
+        Commands::Serve(serve_args) => serve(&serve_args, diagnostics),
+    };
+
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(error) => {
+            diagnostics.emit(Diagnostic::error(&error).with_loc(error.loc()));
+
+            error.code().into()
+        }
+    }
+}
+
+/// Compiles every input file and prints the reports and the generated files, or only lists the
+/// targets with `--list-targets`.
+///
+/// With `--keep-going`, a failing input file is reported and skipped, and the batch fails at the
+/// end if any input file did.
+fn build(args: BuildArguments, mut diagnostics: Diagnostics) -> Result<(), Error> {
+    let args = args.with_env_defaults(diagnostics);
+    if let Err(message) = args.check_conflicts() {
+        Arguments::command()
+            .error(ErrorKind::ArgumentConflict, message)
+            .exit();
+    }
+
+    if args.list_targets {
+        list_targets();
+
+        return Ok(());
+    }
+
+    let input_files = args.get_input_files();
+    let mut failures = Vec::new();
+
+    for input_file in &input_files {
+        let (output_files, report) = match compile(&args, input_file, diagnostics) {
+            Ok(result) => result,
+            Err(error) if args.keep_going => {
+                diagnostics.emit(
+                    Diagnostic::error(format_args!("{}: {}", input_file.display(), error))
+                        .with_loc(error.loc()),
+                );
+                failures.push(error.code());
+
+                continue;
+            }
+            Err(error) => return Err(error),
+        };
+
+        if args.format == ReportFormat::Json && (args.stats || args.timings) {
+            print!("{}", report.to_json(&output_files));
+        } else {
+            print!("{}", report.to_human());
+
+            for output_file in output_files {
+                info!("Generated {}", output_file.display());
+            }
+        }
+    }
+
+    match failures.first() {
+        Some(&code) => Err(Error::Batch {
+            failed: failures.len(),
+            total: input_files.len(),
+            code,
+        }),
+        None => Ok(()),
+    }
+}
+
+/// Parses and optimizes the program without generating any code, reporting its warnings. The
+/// program is always optimized, as the optimizer finds most of them.
+fn check(args: &CheckArguments, mut diagnostics: Diagnostics) -> Result<(), Error> {
+    let input_file_path = Path::new(&args.input_file)
+        .absolutize()
+        .unwrap()
+        .into_owned();
+    let input = read_program(&input_file_path, args.source.preprocess)?;
+
+    let options = args.source.get_compile_options();
+    let options = CompileOptions {
+        optimize: true,
+        warn_wrap: args.warn_wrap,
+        emit_object: false,
+        code_gen: CodeGenOptions {
+            clamp_left: args.clamp_left,
+            ..options.code_gen
+        },
+        ..options
+    };
+
+    let artifacts = compiler::compile(&input, &input_file_path, &options)?;
+
+    for warning in &artifacts.warnings {
+        diagnostics.emit(Diagnostic::warning(warning).with_loc(warning.loc()));
+    }
+
+    Ok(())
+}
+
+/// Generates the output `args.kind` of the program in memory, reporting its warnings.
+fn emit(args: &EmitArguments, mut diagnostics: Diagnostics) -> Result<String, Error> {
+    let input_file_path = Path::new(&args.input_file)
+        .absolutize()
+        .unwrap()
+        .into_owned();
+    let input = read_program(&input_file_path, args.source.preprocess)?;
+
+    let options = args.source.get_compile_options();
+    let options = CompileOptions {
+        optimize: args.optimize,
+        emit_rust: args.kind == EmitKind::Rust,
+        emit_llvm: args.kind == EmitKind::Llvm,
+        emit_asm: args.kind == EmitKind::Asm,
+        emit_sexpr: args.kind == EmitKind::Sexpr,
+        emit_object: false,
+        code_gen: CodeGenOptions {
+            clamp_left: args.clamp_left,
+            ..options.code_gen
+        },
+        ..options
+    };
+
+    let artifacts = compiler::compile(&input, &input_file_path, &options)?;
+
+    for warning in &artifacts.warnings {
+        diagnostics.emit(Diagnostic::warning(warning).with_loc(warning.loc()));
+    }
+
+    let output = match args.kind {
+        EmitKind::Llvm => artifacts.llvm_ir,
+        EmitKind::Asm => artifacts.assembly,
+        EmitKind::Rust => artifacts.rust,
+        EmitKind::Sexpr => artifacts.sexpr,
+    };
+
+    Ok(output.unwrap())
+}
+
+/// Prints the name and description of every target LLVM was built with, marking the target of the
+/// host, which programs are compiled for.
+fn list_targets() {
+    Target::initialize_all(&InitializationConfig::default());
+
+    let host_target = Target::from_triple(&TargetMachine::get_default_triple()).ok();
+    let host_target_name = host_target.as_ref().map(Target::get_name);
+
+    for target in iter::successors(Target::get_first(), Target::get_next) {
+        let host_marker = if host_target_name == Some(target.get_name()) {
+            " (host)"
+        } else {
+            ""
+        };
+
+        println!(
+            "{:<12} {}{}",
+            target.get_name().to_string_lossy(),
+            target.get_description().to_string_lossy(),
+            host_marker
+        );
+    }
+}
+
+fn format(args: &FmtArguments) -> Result<String, Error> {
+    let input = read_program(Path::new(&args.input_file), args.source.preprocess)?;
+
+    // Only well-formed programs are formatted, so that the loop indentation is meaningful.
+    let tokenizer = args.source.tokenizer(&input);
+    Parser::new(tokenizer.clone()).collect::<Result<Vec<_>, _>>()?;
+
+    let options = FormatOptions {
+        width: args.width,
+        indent: args.indent,
+    };
+
+    Ok(Formatter::new(tokenizer, options).format())
+}
+
+/// Returns the note explaining a loop the optimizer rewrote, which shows the loop as it was
+/// written in `input`, formatted without comments, and spans all of it.
+fn explanation_note(
+    explanation: &Explanation,
+    input: &str,
+    source: &SourceArguments,
+) -> Diagnostic {
+    let loop_source = &input[explanation.loc.offset..explanation.end.offset];
+    let loop_source =
+        Formatter::new(source.tokenizer(loop_source), FormatOptions::default()).format();
+
+    Diagnostic::note(explanation.describe_with_source(loop_source.trim_end()))
+        .with_span(explanation.loc, explanation.end)
+}
+
+fn run(args: &RunArguments, mut diagnostics: Diagnostics) -> Result<(), Error> {
+    let input = read_program(Path::new(&args.input_file), args.source.preprocess)?;
+
+    let tokenizer = args.source.tokenizer(&input);
+
+    let instructions = compiler::parse(tokenizer)?;
+    let (instructions, warnings, explanations) = if args.optimize {
+        compiler::optimize(
+            &instructions,
+            DEFAULT_MAX_UNROLL_ITERATIONS,
+            args.explain,
+            args.clamp_left,
+            args.preserve_loops,
+        )
+    } else {
+        (instructions, Vec::new(), Vec::new())
+    };
+
+    for warning in warnings {
+        diagnostics.emit(Diagnostic::warning(warning));
+    }
+
+    for explanation in &explanations {
+        diagnostics.emit(explanation_note(explanation, &input, &args.source));
+    }
+
+    let options = CodeGenOptions {
+        max_tape_bytes: args.source.grid.map(|grid| grid.cells()),
+        clamp_left: args.clamp_left,
+        ..CodeGenOptions::default()
+    };
+
+    // The file descriptor is never closed, as it might be stderr, which errors are reported on.
+    // SAFETY: Nothing else in this process uses the file descriptor while the program runs.
+    let output_file = args
+        .output_fd
+        .map(|output_fd| ManuallyDrop::new(unsafe { File::from_raw_fd(output_fd) }));
+    let output: Box<dyn Write + '_> = match &output_file {
+        Some(output_file) => Box::new(&**output_file),
+        None => Box::new(io::stdout().lock()),
+    };
+
+    interpreter::interpret_with_options(&instructions, &options, io::stdin().lock(), output)?;
 
-    let input = std::fs::read_to_string(&input_file_path).unwrap();
+    Ok(())
+}
+
+/// Runs the program on every line of stdin, until stdin is closed. The errors of a program are
+/// reported without stopping, and its output is flushed as soon as it finishes.
+fn serve(args: &ServeArguments, diagnostics: Diagnostics) -> Result<(), Error> {
+    let mut output = io::stdout().lock();
+
+    for (index, line) in io::stdin().lock().lines().enumerate() {
+        let line = line.map_err(RuntimeError::from)?;
+        let (source, input) = line.split_once('!').unwrap_or((&line, ""));
+
+        if let Err(error) = serve_program(args, source, input, &mut output, |warning| {
+            diagnostics.warning(format_args!("line {}: {}", index + 1, warning))
+        }) {
+            diagnostics.error(format_args!("line {}: {}", index + 1, error));
+        }
+
+        output.flush().map_err(RuntimeError::from)?;
+    }
+
+    Ok(())
+}
 
-    let tokenizer = Tokenizer::new(&input);
-    let parser = Parser::new(tokenizer);
+/// Runs a single program of `serve` on a new tape.
+fn serve_program(
+    args: &ServeArguments,
+    source: &str,
+    input: &str,
+    output: impl Write,
+    mut warn: impl FnMut(&dyn Display),
+) -> Result<(), Error> {
+    let tokenizer = Tokenizer::new(source).with_assertions(args.assertions);
 
+    let instructions = compiler::parse(tokenizer)?;
     let instructions = if args.optimize {
-        Optimizer::new(parser).collect::<Vec<_>>()
+        let (instructions, warnings, _) = compiler::optimize(
+            &instructions,
+            DEFAULT_MAX_UNROLL_ITERATIONS,
+            false,
+            false,
+            false,
+        );
+
+        for warning in warnings {
+            warn(&warning);
+        }
+
+        instructions
     } else {
-        parser.collect::<Vec<_>>()
+        instructions
     };
 
-    let context = Context::create();
-    let code_gen = code_gen::CodeGen::new(instructions, &input_file_path, &context);
-    let module = code_gen.generate_module();
-
-    Target::initialize_native(&InitializationConfig::default())
-        .expect("Failed to initialize native target");
-
-    let triple = TargetMachine::get_default_triple();
-    let cpu = TargetMachine::get_host_cpu_name().to_string();
-    let features = TargetMachine::get_host_cpu_features().to_string();
-
-    let target = Target::from_triple(&triple).unwrap();
-    let target_machine = target
-        .create_target_machine(
-            &triple,
-            &cpu,
-            &features,
-            args.get_optimization_level(),
-            RelocMode::PIC,
-            CodeModel::Default,
-        )
-        .unwrap();
+    interpreter::interpret_with_io(&instructions, input.as_bytes(), output)?;
 
-    module
-        .run_passes(
-            args.get_optimization_passes(),
-            &target_machine,
-            PassBuilderOptions::create(),
-        )
-        .unwrap();
+    Ok(())
+}
 
-    let object_file_path = TempFileBuilder::new()
+fn read_input(input_file_path: &Path) -> Result<String, Error> {
+    std::fs::read_to_string(input_file_path)
+        .map_err(|error| Error::Io(input_file_path.to_owned(), error))
+}
+
+/// Reads the program in the input file, expanding its directives if `preprocess` is set.
+fn read_program(input_file_path: &Path, preprocess: bool) -> Result<String, Error> {
+    let input = read_input(input_file_path)?;
+
+    if preprocess {
+        Ok(preprocessor::preprocess(&input, input_file_path)?)
+    } else {
+        Ok(input)
+    }
+}
+
+/// Compiles the program, returning the paths of all generated artifacts, starting with the
+/// executable or the Rust program.
+fn compile(
+    args: &BuildArguments,
+    input_file_path: &Path,
+    mut diagnostics: Diagnostics,
+) -> Result<(Vec<PathBuf>, Report), Error> {
+    let input = read_program(input_file_path, args.source.preprocess)?;
+
+    let mut artifacts = compiler::compile(&input, input_file_path, &args.get_compile_options())?;
+
+    if !args.quiet {
+        for warning in &artifacts.warnings {
+            diagnostics.emit(Diagnostic::warning(warning).with_loc(warning.loc()));
+        }
+    }
+
+    for explanation in &artifacts.explanations {
+        diagnostics.emit(explanation_note(explanation, &input, &args.source));
+    }
+
+    let mut output_files = Vec::new();
+
+    let outputs = [
+        (&artifacts.rust, args.get_output_file(input_file_path)),
+        (
+            &artifacts.llvm_ir,
+            args.get_artifact_file(input_file_path, "ll"),
+        ),
+        (
+            &artifacts.assembly,
+            args.get_artifact_file(input_file_path, "s"),
+        ),
+        (
+            &artifacts.listing,
+            args.get_artifact_file(input_file_path, "lst"),
+        ),
+        (
+            &artifacts.sexpr,
+            args.get_artifact_file(input_file_path, "sexpr"),
+        ),
+    ];
+
+    for (output, output_file) in outputs {
+        if let Some(output) = output {
+            std::fs::write(&output_file, output)
+                .map_err(|error| Error::Io(output_file.clone(), error))?;
+
+            output_files.push(output_file);
+        }
+    }
+
+    if let (Some(map), Some(map_file)) = (&artifacts.map, &args.map_file) {
+        let map_file = Path::new(map_file).absolutize().unwrap().into_owned();
+
+        std::fs::write(&map_file, format_map(map))
+            .map_err(|error| Error::Io(map_file.clone(), error))?;
+
+        output_files.push(map_file);
+    }
+
+    if let Some(object) = &artifacts.object {
+        let linked_files = link(args, object, input_file_path, &mut artifacts.timings)?;
+        output_files.splice(0..0, linked_files);
+    }
+
+    let report = Report {
+        stats: artifacts.stats,
+        timings: args.timings.then_some(artifacts.timings),
+    };
+
+    Ok((output_files, report))
+}
+
+/// Formats the map of the basic blocks generated for every top-level instruction with one line per
+/// instruction: its index, the instruction with its location and the blocks, separated by tabs.
+fn format_map(map: &[InstructionBlocks]) -> String {
+    map.iter()
+        .map(|entry| {
+            format!(
+                "{}\t{}\t{}\n",
+                entry.index,
+                entry.summary,
+                entry.blocks.join(" ")
+            )
+        })
+        .collect()
+}
+
+/// Writes the `object` file and links it with the helpers into the executable.
+///
+/// Returns the executable, unless linking was skipped with `--dry-run`, followed by the object file
+/// if it was kept.
+fn link(
+    args: &BuildArguments,
+    object: &[u8],
+    input_file_path: &Path,
+    timings: &mut Timings,
+) -> Result<Vec<PathBuf>, Error> {
+    let mut object_file = TempFileBuilder::new()
         .prefix(&input_file_path.file_stem().unwrap())
         .suffix(".o")
         .tempfile()
-        .unwrap()
-        .into_temp_path();
+        .map_err(|error| Error::Io(std::env::temp_dir(), error))?;
 
-    target_machine
-        .write_to_file(module, FileType::Object, &object_file_path)
-        .unwrap();
+    object_file
+        .write_all(object)
+        .map_err(|error| Error::Io(object_file.path().to_owned(), error))?;
+    let object_file = object_file.into_temp_path();
+
+    if args.dump_symbols {
+        dump_symbols(&object_file, object)?;
+    }
+
+    // A dry run keeps the object file, so that the printed command can actually be run.
+    let keep_object_file = args.save_temps || args.dry_run;
+    let object_file_path = if keep_object_file {
+        let object_file_path = object_file.to_path_buf();
+        object_file
+            .keep()
+            .map_err(|error| Error::Io(object_file_path, error.into()))?
+    } else {
+        object_file.to_path_buf()
+    };
+
+    let output_file = args.get_output_file(input_file_path);
 
-    let output_file = args.get_output_file();
+    let triple = args.get_target_triple();
+    let sanitize_flag = args.get_sanitize_flag();
+    let helpers_object_path = get_helpers_object(&triple, sanitize_flag.as_deref())?;
 
+    let mut command = Command::new("clang");
+    if args.target.is_some() {
+        command
+            .arg("-target")
+            .arg(triple.as_str().to_str().unwrap());
+    }
+    if args.shared {
+        command.arg("-shared");
+    }
+    if args.static_link {
+        check_static_libc(&triple)?;
+
+        command.arg("-static");
+    }
+    // The object file is always position-independent, so it can be linked either way.
+    if args.pie {
+        command.arg("-pie");
+    } else if args.no_pie {
+        command.arg("-no-pie");
+    }
+    // Links the runtimes of the sanitizers, which the helpers need.
+    if let Some(sanitize_flag) = &sanitize_flag {
+        command.arg(sanitize_flag);
+    }
+    command
+        .arg("-o")
+        .arg(&output_file)
+        .arg(&object_file_path)
+        .arg(helpers_object_path);
+
+    let mut linked_files = Vec::new();
+
+    if args.dry_run {
+        println!("{:?}", command);
+    } else {
+        run_clang(&mut command)?;
+        timings.record("link");
+
+        linked_files.push(output_file);
+    }
+
+    if keep_object_file {
+        linked_files.push(object_file_path);
+    }
+
+    Ok(linked_files)
+}
+
+/// Prints the global symbols that the object file `data`, written to `object_file_path`, defines,
+/// followed by the ones it references, which the helpers and the C library have to provide when
+/// linking.
+fn dump_symbols(object_file_path: &Path, data: &[u8]) -> Result<(), Error> {
+    let invalid_object =
+        |error: object::Error| Error::Io(object_file_path.to_owned(), io::Error::other(error));
+
+    let symbols = object_symbols(data).map_err(invalid_object)?;
+
+    println!("Symbols of {}", object_file_path.display());
+    for (undefined, name) in symbols {
+        let kind = if undefined { "undefined" } else { "defined" };
+        println!("  {:<12}{}", kind, name);
+    }
+
+    Ok(())
+}
+
+/// Returns the global symbols of the object file `data` as pairs of whether they are undefined and
+/// their name, the defined ones first and both sorted by name.
+fn object_symbols(data: &[u8]) -> Result<Vec<(bool, &str)>, object::Error> {
+    let object_file = object::File::parse(data)?;
+
+    let mut symbols = Vec::new();
+    for symbol in object_file.symbols() {
+        if symbol.is_global() && (symbol.is_definition() || symbol.is_undefined()) {
+            symbols.push((symbol.is_undefined(), symbol.name()?));
+        }
+    }
+    symbols.sort_unstable();
+
+    Ok(symbols)
+}
+
+/// Returns the path of the compiled helpers object for `triple`, instrumented with the sanitizers
+/// of `sanitize_flag` if it is given.
+///
+/// The object is cached in the temporary directory, keyed by the content of the helpers source, the
+/// target triple and the flags, so that it only has to be recompiled when any of them changes.
+fn get_helpers_object(
+    triple: &TargetTriple,
+    sanitize_flag: Option<&str>,
+) -> Result<PathBuf, Error> {
     let helpers_file_path = Path::new("stdlib/helpers.c")
         .absolutize()
         .unwrap()
         .into_owned();
 
-    let clang_status = Command::new("clang")
-        .arg("-O2")
-        .arg("-o")
-        .arg(&output_file)
-        .arg(&object_file_path)
-        .arg(helpers_file_path)
-        .status()
-        .unwrap();
+    let helpers_source = std::fs::read(&helpers_file_path)
+        .map_err(|error| Error::Io(helpers_file_path.clone(), error))?;
+    let triple = triple.as_str().to_str().unwrap();
+
+    // The helpers are position independent and hidden, so that the same object can be linked into
+    // executables and into shared libraries, which then only export the entry function.
+    let flags = ["-O2", "-fPIC", "-fvisibility=hidden"]
+        .into_iter()
+        .chain(sanitize_flag)
+        .collect::<Vec<_>>();
+
+    let mut hasher = DefaultHasher::new();
+    helpers_source.hash(&mut hasher);
+    triple.hash(&mut hasher);
+    flags.hash(&mut hasher);
+
+    let cache_dir = std::env::temp_dir().join("brainfuck-rs");
+    let cached_object_path = cache_dir.join(format!("helpers-{:016x}.o", hasher.finish()));
+
+    if cached_object_path.is_file() {
+        return Ok(cached_object_path);
+    }
 
-    assert!(clang_status.success());
+    std::fs::create_dir_all(&cache_dir).map_err(|error| Error::Io(cache_dir.clone(), error))?;
 
-    println!("Generated {}", output_file.to_str().unwrap());
+    // Compile into a temporary file first and move it into place afterwards, so that concurrent
+    // invocations never link against a partially written object.
+    let object_file_path = TempFileBuilder::new()
+        .prefix("helpers")
+        .suffix(".o")
+        .tempfile_in(&cache_dir)
+        .map_err(|error| Error::Io(cache_dir.clone(), error))?
+        .into_temp_path();
+
+    run_clang(
+        Command::new("clang")
+            .args(flags)
+            .arg("-c")
+            .arg("-target")
+            .arg(triple)
+            .arg("-o")
+            .arg(&object_file_path)
+            .arg(helpers_file_path),
+    )?;
+
+    object_file_path
+        .persist(&cached_object_path)
+        .map_err(|error| Error::Io(cached_object_path.clone(), error.into()))?;
+
+    Ok(cached_object_path)
+}
+
+/// Checks that clang finds a static C library for `triple` to link with `--static`, as its own error
+/// about the missing library doesn't say what to install.
+fn check_static_libc(triple: &TargetTriple) -> Result<(), Error> {
+    let triple = triple.as_str().to_str().unwrap();
+
+    let output = Command::new("clang")
+        .arg("-target")
+        .arg(triple)
+        .arg("-print-file-name=libc.a")
+        .output()
+        .map_err(|error| Error::Link(format!("could not run clang: {}", error)))?;
+
+    // clang prints the name unchanged if it doesn't find the library.
+    let libc_path = String::from_utf8_lossy(&output.stdout);
+    if Path::new(libc_path.trim()).is_file() {
+        Ok(())
+    } else {
+        Err(Error::Link(format!(
+            "clang can't find a static C library for {}, which --static needs, e.g. the musl \
+             development files for a musl target",
+            triple
+        )))
+    }
+}
+
+fn run_clang(command: &mut Command) -> Result<(), Error> {
+    debug!("running {:?}", command);
+
+    let output = command
+        .output()
+        .map_err(|error| Error::Link(format!("could not run clang: {}", error)))?;
+
+    let messages = [output.stdout, output.stderr]
+        .iter()
+        .map(|bytes| String::from_utf8_lossy(bytes).trim_end().to_owned())
+        .filter(|message| !message.is_empty())
+        .collect::<Vec<_>>()
+        .join("\n");
+
+    if output.status.success() {
+        // Successful runs only print warnings, which aren't actionable for the user.
+        if !messages.is_empty() {
+            debug!("clang printed:\n{}", messages);
+        }
+
+        Ok(())
+    } else if messages.is_empty() {
+        Err(Error::Link(format!("clang exited with {}", output.status)))
+    } else {
+        Err(Error::Link(format!(
+            "clang exited with {}:\n{}",
+            output.status, messages
+        )))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn symbols_of_object() {
+        let artifacts =
+            compiler::compile(",[.,]", Path::new("test.bf"), &CompileOptions::default()).unwrap();
+        let object = artifacts.object.unwrap();
+        let symbols = object_symbols(&object).unwrap();
+        assert!(symbols.is_sorted());
+
+        // Mach-O prefixes C symbols with an underscore.
+        let prefix = if cfg!(target_os = "macos") { "_" } else { "" };
+        let symbols = symbols
+            .into_iter()
+            .map(|(undefined, name)| (undefined, name.strip_prefix(prefix).unwrap()))
+            .collect::<Vec<_>>();
+
+        assert!(symbols.contains(&(false, "main")));
+        assert!(symbols.contains(&(true, "allocateCells")));
+        assert!(symbols.contains(&(true, "freeCells")));
+    }
+
+    #[test]
+    fn serve_two_programs() {
+        let args = ServeArguments {
+            optimize: true,
+            assertions: false,
+        };
+        let mut output = Vec::new();
+
+        serve_program(&args, ",+.", "a", &mut output, |_| ()).unwrap();
+        // The second program starts on a new tape and only reads its own input.
+        serve_program(&args, ".,.,.", "z", &mut output, |_| ()).unwrap();
+
+        assert_eq!(output, b"b\0z\0");
+    }
+
+    #[test]
+    fn keep_going_compiles_remaining_files() {
+        let directory = TempFileBuilder::new().tempdir().unwrap();
+        let good_file = directory.path().join("good.bf");
+        let bad_file = directory.path().join("bad.bf");
+        std::fs::write(&bad_file, "+]").unwrap();
+        std::fs::write(&good_file, "+.").unwrap();
+
+        // The Rust program doesn't have to be linked, so this doesn't need clang.
+        let arguments = Arguments::try_parse_from([
+            "brainfuck-rs",
+            "--keep-going",
+            "--emit-rust",
+            bad_file.to_str().unwrap(),
+            good_file.to_str().unwrap(),
+        ])
+        .unwrap();
+        let result = build(arguments.build, Diagnostics::new(false));
+
+        assert!(matches!(
+            result,
+            Err(Error::Batch {
+                failed: 1,
+                total: 2,
+                ..
+            })
+        ));
+        assert!(good_file.with_extension("rs").exists());
+    }
 }
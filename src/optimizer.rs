@@ -1,97 +1,387 @@
 use std::{
-    collections::{hash_map::Entry, HashMap},
-    iter,
+    cmp::Ordering,
+    collections::{btree_map::Entry, BTreeMap, VecDeque},
+    fmt::{Display, Formatter, Result as FmtResult},
+    iter::{self, Peekable},
     num::Wrapping,
-    vec::IntoIter,
 };
 
-use either::Either;
+use rayon::prelude::*;
 
-use crate::{instruction::Instruction, parser::Parser};
+use crate::{
+    instruction::{Instruction, InstructionKind},
+    tok::SourceLoc,
+};
 
-pub struct Optimizer<Iter>
-where
-    Iter: Iterator<Item = Instruction>,
-{
-    iter: Iter,
+/// The default for the maximum number of iterations of a loop that is unrolled completely.
+pub const DEFAULT_MAX_UNROLL_ITERATIONS: usize = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OptimizerWarning {
+    /// A loop leaves the pointer and the current cell unchanged, so it never terminates once it
+    /// is entered.
+    InfiniteLoop,
+}
+
+impl Display for OptimizerWarning {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        match self {
+            Self::InfiniteLoop => {
+                f.write_str("loop body doesn't change the current cell, the loop never terminates")
+            }
+        }
+    }
+}
+
+/// Why the optimizer replaced a loop.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RewriteReason {
+    NeverEntered,
+    /// The body only moves the pointer, which becomes `MoveRightUntilZero` or `MoveLeftUntilZero`.
+    Scan,
+    /// The body only changes the current cell by an odd amount, which becomes `SetToZero`.
+    Clear,
+    /// The body outputs the current cell and moves right, which becomes `OutputUntilZero`.
+    Print,
+    /// The body moves the value of the current cell to another cell, which becomes
+    /// `MoveValueRight` or `MoveValueLeft`.
+    Move,
+    /// The body adds multiples of the current cell to other cells, which becomes `WithMultiplier`.
+    Multiply,
+    /// The loop starts at the known value `counter`, so it is replaced by its iterations.
+    KnownCounter {
+        counter: u8,
+    },
+}
+
+impl RewriteReason {
+    /// Returns the reason for optimizing a loop into `kind`, or `None` if it is still a loop.
+    fn of(kind: &InstructionKind) -> Option<Self> {
+        match kind {
+            InstructionKind::MoveRightUntilZero { step_size: _ }
+            | InstructionKind::MoveLeftUntilZero { step_size: _ } => Some(Self::Scan),
+            InstructionKind::SetToZero => Some(Self::Clear),
+            InstructionKind::OutputUntilZero { step_size: _ } => Some(Self::Print),
+            InstructionKind::MoveValueRight { amount: _ }
+            | InstructionKind::MoveValueLeft { amount: _ } => Some(Self::Move),
+            InstructionKind::WithMultiplier { instructions: _ } => Some(Self::Multiply),
+            _ => None,
+        }
+    }
+}
+
+impl Display for RewriteReason {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        match self {
+            Self::NeverEntered => f.write_str("the current cell is always zero here"),
+            Self::Scan => f.write_str("the body only moves the pointer, scanning for a zero cell"),
+            Self::Clear => f.write_str("the body only changes the current cell, by an odd amount"),
+            Self::Print => f.write_str(
+                "the body outputs the current cell and moves right, printing up to a zero cell",
+            ),
+            Self::Move => f.write_str(
+                "the body decrements the current cell once and increments one other cell once",
+            ),
+            Self::Multiply => f.write_str(
+                "the body decrements the current cell once and adds to other cells, each time",
+            ),
+            Self::KnownCounter { counter } => f.write_fmt(format_args!(
+                "the current cell is always {} here, so the number of iterations is known",
+                counter
+            )),
+        }
+    }
+}
+
+/// A loop that the optimizer rewrote, as returned by `Optimizer::explanations`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Explanation {
+    pub loc: SourceLoc,
+    /// The location right after the `]` of the loop.
+    pub end: SourceLoc,
+    /// The body of the loop before it was optimized.
+    pub body: Vec<Instruction>,
+    /// The instructions that replace the loop, which are empty if the loop was removed.
+    pub result: Vec<Instruction>,
+    pub reason: RewriteReason,
 }
 
-impl<'a> Optimizer<Parser<'a>> {
-    pub fn new(parser: Parser<'a>) -> Self {
-        Self { iter: parser }
+impl Explanation {
+    /// Describes the rewrite like `Display`, but shows the loop as `loop_source`, e.g. the loop as
+    /// it was written, instead of its body, e.g. `1:2: [->+<] -> MoveValueRight(1) (...)`.
+    pub fn describe_with_source(&self, loop_source: &str) -> String {
+        format!(
+            "{}: {} -> {} ({})",
+            self.loc,
+            loop_source,
+            self.describe_result(),
+            self.reason
+        )
+    }
+
+    fn describe_result(&self) -> String {
+        if self.result.is_empty() {
+            "nothing".to_owned()
+        } else {
+            describe(&self.result)
+        }
     }
 }
 
-impl Optimizer<IntoIter<Instruction>> {
-    fn new(iter: IntoIter<Instruction>) -> Self {
-        Self { iter }
+impl Display for Explanation {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        f.write_fmt(format_args!(
+            "{}: Loop({}) -> {} ({})",
+            self.loc,
+            describe(&self.body),
+            self.describe_result(),
+            self.reason
+        ))
     }
 }
 
+/// Returns `instructions` on one line, e.g. `Decrement(1), MoveRight(1)`.
+fn describe(instructions: &[Instruction]) -> String {
+    instructions
+        .iter()
+        .map(|instruction| match &instruction.kind {
+            InstructionKind::Loop { instructions } => format!("Loop({})", describe(instructions)),
+            InstructionKind::WithMultiplier { instructions } => {
+                format!("WithMultiplier({})", describe(instructions))
+            }
+            kind => format!("{:?}", kind),
+        })
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+pub struct Optimizer<Iter>
+where
+    Iter: Iterator<Item = Instruction>,
+{
+    iter: Peekable<FoldCellOperations<Iter>>,
+    /// The value of the current cell before the next instruction, if it is known. A loop starting
+    /// at a zero cell is never entered, and one starting at another known value may be unrolled.
+    current_cell_value: Option<u8>,
+    /// Instructions of an unrolled loop that are yet to be returned.
+    pending: VecDeque<Instruction>,
+    /// Instructions of a folded clear range that are yet to be returned.
+    folded: VecDeque<Instruction>,
+    /// Optimized instructions that were looked at to fold a clear range, but aren't part of it.
+    lookahead: VecDeque<Instruction>,
+    max_unroll_iterations: usize,
+    warnings: Vec<OptimizerWarning>,
+    /// The rewritten loops, which are only recorded if this is set.
+    explanations: Option<Vec<Explanation>>,
+    /// Whether moves left of the first cell stay at it, see `CodeGenOptions::clamp_left`.
+    clamp_left: bool,
+    /// Whether loops that remain loops keep their bodies as they were parsed.
+    preserve_loops: bool,
+}
+
 impl<Iter> Optimizer<Iter>
 where
     Iter: Iterator<Item = Instruction>,
 {
-    fn optimize_loop(&mut self, instructions: Vec<Instruction>) -> Instruction {
+    /// Creates an optimizer for a whole program, which starts on a tape of zeroed cells.
+    pub fn new(iter: Iter) -> Self {
+        Self::new_with_clamp_left(iter, false)
+    }
+
+    /// Creates an optimizer like `new`, but for a program whose moves left of the first cell stay
+    /// at it if `clamp_left` is set.
+    ///
+    /// Loops and runs of cell operations are then only rewritten if they never move left of the
+    /// cell they start at, as their rewrites assume that every cell they reach is a different one.
+    pub fn new_with_clamp_left(iter: Iter, clamp_left: bool) -> Self {
+        Self {
+            iter: FoldCellOperations::new(iter, clamp_left).peekable(),
+            current_cell_value: Some(0),
+            pending: VecDeque::new(),
+            folded: VecDeque::new(),
+            lookahead: VecDeque::new(),
+            max_unroll_iterations: DEFAULT_MAX_UNROLL_ITERATIONS,
+            warnings: Vec::new(),
+            explanations: None,
+            clamp_left,
+            preserve_loops: false,
+        }
+    }
+
+    /// Creates an optimizer for a loop body, which is only entered with a non-zero current cell.
+    fn new_loop_body(iter: Iter, clamp_left: bool) -> Self {
+        Self::new_segment(iter, None, clamp_left)
+    }
+
+    /// Creates an optimizer for a part of a program, which starts at a cell with the value
+    /// `current_cell_value`, or an unknown one if that is `None`.
+    fn new_segment(iter: Iter, current_cell_value: Option<u8>, clamp_left: bool) -> Self {
+        Self {
+            current_cell_value,
+            ..Self::new_with_clamp_left(iter, clamp_left)
+        }
+    }
+
+    /// Sets the maximum number of iterations of a loop that is replaced by copies of its body
+    /// if the number of iterations is known, 0 disables this.
+    pub fn with_max_unroll_iterations(mut self, max_unroll_iterations: usize) -> Self {
+        self.max_unroll_iterations = max_unroll_iterations;
+        self
+    }
+
+    /// Records an explanation for every loop that is rewritten if `explain` is set.
+    pub fn with_explanations(mut self, explain: bool) -> Self {
+        self.explanations = explain.then(Vec::new);
+        self
+    }
+
+    /// Keeps every loop that isn't rewritten into another instruction exactly as it was parsed if
+    /// `preserve_loops` is set, so that its body isn't optimized and it is never unrolled, e.g. to
+    /// compare the behavior of a loop with and without the optimizer.
+    pub fn with_preserve_loops(mut self, preserve_loops: bool) -> Self {
+        self.preserve_loops = preserve_loops;
+        self
+    }
+
+    pub fn warnings(&self) -> &[OptimizerWarning] {
+        &self.warnings
+    }
+
+    /// Returns the loops rewritten so far, in the order in which they were optimized, so inner
+    /// loops come before the loops around them. This is empty unless `with_explanations` was set.
+    pub fn explanations(&self) -> &[Explanation] {
+        self.explanations.as_deref().unwrap_or_default()
+    }
+
+    fn explain(
+        &mut self,
+        loc: SourceLoc,
+        end: SourceLoc,
+        body: Vec<Instruction>,
+        result: Vec<Instruction>,
+        reason: RewriteReason,
+    ) {
+        if let Some(explanations) = &mut self.explanations {
+            explanations.push(Explanation {
+                loc,
+                end,
+                body,
+                result,
+                reason,
+            });
+        }
+    }
+
+    /// Optimizes the loop from `loc` to `end` with the body `instructions`. The instructions the
+    /// body is rewritten into span the whole loop.
+    fn optimize_loop(
+        &mut self,
+        instructions: Vec<Instruction>,
+        loc: SourceLoc,
+        end: SourceLoc,
+    ) -> InstructionKind {
+        let parsed_instructions = self.preserve_loops.then(|| instructions.clone());
+
+        let mut optimizer = Optimizer::new_loop_body(instructions.into_iter(), self.clamp_left)
+            .with_max_unroll_iterations(self.max_unroll_iterations)
+            .with_explanations(self.explanations.is_some())
+            .with_preserve_loops(self.preserve_loops);
+        let instructions = optimizer.by_ref().collect::<Vec<_>>();
+        self.warnings.extend(optimizer.warnings);
+
+        let kind = self.rewrite_loop(instructions, loc, end);
+
+        // The loops in the body are only explained if the optimized body is kept.
+        if let (InstructionKind::Loop { instructions: _ }, Some(instructions)) =
+            (&kind, parsed_instructions)
+        {
+            return InstructionKind::Loop { instructions };
+        }
+
+        if let (Some(explanations), Some(inner_explanations)) =
+            (&mut self.explanations, optimizer.explanations)
+        {
+            explanations.extend(inner_explanations);
+        }
+
+        kind
+    }
+
+    /// Rewrites the loop from `loc` to `end` with the optimized body `instructions` into another
+    /// instruction if possible, or returns it as a loop otherwise.
+    fn rewrite_loop(
+        &mut self,
+        instructions: Vec<Instruction>,
+        loc: SourceLoc,
+        end: SourceLoc,
+    ) -> InstructionKind {
+        // E.g. `[.>]`, which prints a null-terminated string.
+        if let [output, move_right] = instructions.as_slice() {
+            if let (InstructionKind::Output, InstructionKind::MoveRight { amount }) =
+                (&output.kind, &move_right.kind)
+            {
+                return InstructionKind::OutputUntilZero { step_size: *amount };
+            }
+        }
+
         if instructions.len() == 1 {
-            match instructions[0] {
-                Instruction::MoveRight { amount } => {
-                    Instruction::MoveRightUntilZero { step_size: amount }
+            match instructions[0].kind {
+                InstructionKind::MoveRight { amount } => {
+                    InstructionKind::MoveRightUntilZero { step_size: amount }
                 }
-                Instruction::MoveLeft { amount } => {
-                    Instruction::MoveLeftUntilZero { step_size: amount }
+                InstructionKind::MoveLeft { amount } if !self.clamp_left => {
+                    InstructionKind::MoveLeftUntilZero { step_size: amount }
                 }
-                Instruction::Increment { amount: 1 } | Instruction::Decrement { amount: 1 } => {
-                    Instruction::SetToZero
+                // A loop that only changes the current cell by an odd amount reaches zero from
+                // every value, as odd amounts are coprime to 256. With an even amount, it only
+                // does so from values that are multiples of the amount's power of two, and loops
+                // forever otherwise, so it can't become `SetToZero`.
+                InstructionKind::Increment { amount } | InstructionKind::Decrement { amount }
+                    if amount % 2 == 1 =>
+                {
+                    InstructionKind::SetToZero
                 }
-                _ => Instruction::Loop { instructions },
+                _ => InstructionKind::Loop { instructions },
             }
         } else {
-            self.unroll_loop(instructions)
+            self.unroll_loop(instructions, loc, end)
         }
     }
 
-    fn unroll_loop(&mut self, instructions: Vec<Instruction>) -> Instruction {
+    fn unroll_loop(
+        &mut self,
+        instructions: Vec<Instruction>,
+        loc: SourceLoc,
+        end: SourceLoc,
+    ) -> InstructionKind {
         let mut current_relative_cell = 0isize;
-        let mut relative_cell_operations = HashMap::new();
-
-        let instructions =
-            Optimizer::<IntoIter<_>>::new(instructions.into_iter()).collect::<Vec<_>>();
+        // Ordered by the relative cell, so that the generated instructions are deterministic.
+        let mut relative_cell_operations = BTreeMap::new();
 
         let unroll_possible = instructions.iter().all(|instruction| {
-            match instruction {
-                Instruction::MoveRight { amount } => current_relative_cell += *amount as isize,
-                Instruction::MoveLeft { amount } => current_relative_cell -= *amount as isize,
-                Instruction::Increment { amount } => {
-                    match relative_cell_operations.entry(current_relative_cell) {
-                        Entry::Occupied(entry) => {
-                            let (increment, increment_amount) = entry.into_mut();
-                            if *increment {
-                                *increment_amount += *amount;
-                            } else {
-                                *increment_amount -= *amount;
-                            }
-                        }
-                        Entry::Vacant(entry) => {
-                            entry.insert((true, Wrapping(*amount)));
-                        }
-                    }
-                }
-                Instruction::Decrement { amount } => {
-                    match relative_cell_operations.entry(current_relative_cell) {
-                        Entry::Occupied(entry) => {
-                            let (increment, increment_amount) = entry.into_mut();
-                            if *increment {
-                                *increment_amount -= *amount;
-                            } else {
-                                *increment_amount += *amount;
-                            }
-                        }
-                        Entry::Vacant(entry) => {
-                            entry.insert((false, Wrapping(*amount)));
-                        }
+            match &instruction.kind {
+                InstructionKind::MoveRight { amount } => current_relative_cell += *amount as isize,
+                InstructionKind::MoveLeft { amount } => {
+                    current_relative_cell -= *amount as isize;
+
+                    // Left of the starting cell, the body may stay at the first cell instead of
+                    // reaching the relative cell.
+                    if self.clamp_left && current_relative_cell < 0 {
+                        return false;
                     }
                 }
+                InstructionKind::Increment { amount } => add_cell_operation(
+                    &mut relative_cell_operations,
+                    current_relative_cell,
+                    true,
+                    *amount,
+                ),
+                InstructionKind::Decrement { amount } => add_cell_operation(
+                    &mut relative_cell_operations,
+                    current_relative_cell,
+                    false,
+                    *amount,
+                ),
                 _ => return false,
             }
 
@@ -99,82 +389,886 @@ where
         });
 
         if unroll_possible && current_relative_cell == 0 {
+            if let None | Some((_, Wrapping(0))) = relative_cell_operations.get(&0) {
+                self.warnings.push(OptimizerWarning::InfiniteLoop);
+
+                return InstructionKind::Loop { instructions };
+            }
+
             if let Some((false, Wrapping(1))) = relative_cell_operations.remove(&0) {
                 if relative_cell_operations.is_empty() {
-                    return Instruction::SetToZero;
+                    return InstructionKind::SetToZero;
                 } else if relative_cell_operations.len() == 1 {
                     if let (relative_cell, (true, Wrapping(1))) =
                         relative_cell_operations.iter().next().unwrap()
                     {
                         if relative_cell > &0 {
-                            return Instruction::MoveValueRight {
+                            return InstructionKind::MoveValueRight {
                                 amount: *relative_cell as usize,
                             };
                         } else {
-                            return Instruction::MoveValueLeft {
+                            return InstructionKind::MoveValueLeft {
                                 amount: relative_cell.unsigned_abs(),
                             };
                         }
                     }
                 }
 
-                let operation_count = relative_cell_operations.len();
-                let instructions = relative_cell_operations
-                    .into_iter()
-                    .enumerate()
-                    .flat_map(|(i, (relative_cell, (increment, Wrapping(amount))))| {
-                        if amount == 0 {
-                            Either::Left(iter::empty())
+                let mut instructions = Vec::new();
+
+                // Cells whose operations cancel out are skipped, so the pointer is returned to the
+                // multiplier cell from the last cell that is changed, if there is any.
+                for (relative_cell, (increment, Wrapping(amount))) in relative_cell_operations {
+                    if amount == 0 {
+                        continue;
+                    }
+
+                    let movement = relative_cell - current_relative_cell;
+                    current_relative_cell = relative_cell;
+
+                    let increment_instruction = if increment {
+                        InstructionKind::Increment { amount }
+                    } else {
+                        InstructionKind::Decrement { amount }
+                    };
+
+                    instructions.push(Instruction::new(move_by(movement), loc, end));
+                    instructions.push(Instruction::new(increment_instruction, loc, end));
+                }
+
+                if current_relative_cell != 0 {
+                    instructions.push(Instruction::new(move_by(-current_relative_cell), loc, end));
+                }
+
+                return InstructionKind::WithMultiplier { instructions };
+            }
+        }
+
+        InstructionKind::Loop { instructions }
+    }
+
+    /// Merges a run of moves starting with `instruction` into a single move, which spans the whole
+    /// run, returning `None` if they cancel out.
+    ///
+    /// Only directly adjacent moves are merged. Every other instruction reads or writes the
+    /// current cell, so none of them is transparent to moves. Loops that are never entered are
+    /// dropped before this runs though, so moves around them are merged as well.
+    ///
+    /// A left move is always merged into the run, but a right move only if the run hasn't moved
+    /// the pointer to the left so far. This way, the merged move reaches a negative cell exactly
+    /// if one of the original moves does, e.g. `<>` isn't merged, as it fails on the first cell.
+    /// With `clamp_left`, the merged move also stops at the first cell exactly if the original
+    /// moves end there, as no move right follows a move that may stop at it.
+    fn merge_moves(&mut self, instruction: Instruction) -> Option<Instruction> {
+        let mut offset = match instruction.kind {
+            InstructionKind::MoveRight { amount } => amount as isize,
+            InstructionKind::MoveLeft { amount } => -(amount as isize),
+            _ => return Some(instruction),
+        };
+        let mut end = instruction.end;
+
+        // Moves are only merged as long as the offset fits, which only fails for moves far beyond
+        // any tape.
+        while let Some(next) = self.iter.next_if(|next| match next.kind {
+            InstructionKind::MoveRight { amount } => {
+                offset >= 0 && offset.checked_add(amount as isize).is_some()
+            }
+            InstructionKind::MoveLeft { amount } => offset.checked_sub(amount as isize).is_some(),
+            _ => false,
+        }) {
+            match next.kind {
+                InstructionKind::MoveRight { amount } => offset += amount as isize,
+                InstructionKind::MoveLeft { amount } => offset -= amount as isize,
+                _ => unreachable!(),
+            }
+
+            end = next.end;
+        }
+
+        let kind = match offset.cmp(&0) {
+            Ordering::Greater => InstructionKind::MoveRight {
+                amount: offset as usize,
+            },
+            Ordering::Less => InstructionKind::MoveLeft {
+                amount: offset.unsigned_abs(),
+            },
+            Ordering::Equal => return None,
+        };
+
+        Some(Instruction::new(kind, instruction.loc, end))
+    }
+
+    /// Replaces a loop that starts at a cell with the known value `counter` by straight-line code,
+    /// returning `None` if that isn't possible.
+    ///
+    /// Multiplier loops and value moves become a single pass of their body with the amounts
+    /// multiplied by the counter, followed by a clear. Other loops are copied once per iteration,
+    /// if they only consist of moves, increments, decrements and outputs, return to the counter
+    /// and terminate after at most `max_unroll_iterations` iterations.
+    ///
+    /// The new instructions span the loop from `loc` to `end`, while the copies of a body keep the
+    /// spans of its instructions.
+    fn unroll_known_loop(
+        &self,
+        kind: &InstructionKind,
+        counter: u8,
+        loc: SourceLoc,
+        end: SourceLoc,
+    ) -> Option<Vec<Instruction>> {
+        let new = |kind| Instruction::new(kind, loc, end);
+
+        let instructions = match kind {
+            InstructionKind::WithMultiplier { instructions } => instructions
+                .iter()
+                .filter_map(|instruction| match instruction.kind {
+                    InstructionKind::Increment { amount }
+                    | InstructionKind::Decrement { amount }
+                        if amount.wrapping_mul(counter) == 0 =>
+                    {
+                        None
+                    }
+                    InstructionKind::Increment { amount } => {
+                        Some(new(InstructionKind::Increment {
+                            amount: amount.wrapping_mul(counter),
+                        }))
+                    }
+                    InstructionKind::Decrement { amount } => {
+                        Some(new(InstructionKind::Decrement {
+                            amount: amount.wrapping_mul(counter),
+                        }))
+                    }
+                    _ => Some(instruction.clone()),
+                })
+                .chain(iter::once(new(InstructionKind::SetToZero)))
+                .collect(),
+            InstructionKind::MoveValueRight { amount } => vec![
+                new(InstructionKind::SetToZero),
+                new(InstructionKind::MoveRight { amount: *amount }),
+                new(InstructionKind::Increment { amount: counter }),
+                new(InstructionKind::MoveLeft { amount: *amount }),
+            ],
+            InstructionKind::MoveValueLeft { amount } => vec![
+                new(InstructionKind::SetToZero),
+                new(InstructionKind::MoveLeft { amount: *amount }),
+                new(InstructionKind::Increment { amount: counter }),
+                new(InstructionKind::MoveRight { amount: *amount }),
+            ],
+            InstructionKind::Loop { instructions: _ } if self.preserve_loops => return None,
+            InstructionKind::Loop { instructions } => {
+                let mut offset = 0isize;
+                let mut change = Wrapping(0u8);
+
+                for instruction in instructions {
+                    match &instruction.kind {
+                        InstructionKind::MoveRight { amount } => offset += *amount as isize,
+                        InstructionKind::MoveLeft { amount } => {
+                            offset -= *amount as isize;
+
+                            // Every copy has to reach the cells the body reaches from the counter.
+                            if self.clamp_left && offset < 0 {
+                                return None;
+                            }
+                        }
+                        InstructionKind::Increment { amount } if offset == 0 => change += amount,
+                        InstructionKind::Decrement { amount } if offset == 0 => change -= amount,
+                        InstructionKind::Increment { amount: _ }
+                        | InstructionKind::Decrement { amount: _ }
+                        | InstructionKind::Output => {}
+                        _ => return None,
+                    }
+                }
+
+                if offset != 0 || change.0 == 0 {
+                    return None;
+                }
+
+                let mut value = Wrapping(counter);
+                let iterations = (1..=self.max_unroll_iterations).find(|_| {
+                    value += change;
+                    value.0 == 0
+                })?;
+
+                (0..iterations)
+                    .flat_map(|_| instructions.iter().cloned())
+                    .collect()
+            }
+            _ => return None,
+        };
+
+        // Optimizing the straight-line code merges the moves between the copies of the body.
+        let optimizer =
+            Optimizer::new_segment(instructions.into_iter(), Some(counter), self.clamp_left);
+        Some(optimizer.collect())
+    }
+
+    fn fold_set_value(&mut self, instruction: Instruction) -> Instruction {
+        let mut value = match instruction.kind {
+            InstructionKind::SetToZero => Wrapping(0u8),
+            InstructionKind::SetValue { value } => Wrapping(value),
+            _ => return instruction,
+        };
+
+        let mut end = None;
+        while let Some(next) = self.iter.next_if(|next| {
+            matches!(
+                next.kind,
+                InstructionKind::Increment { .. } | InstructionKind::Decrement { .. }
+            )
+        }) {
+            match next.kind {
+                InstructionKind::Increment { amount } => value += amount,
+                InstructionKind::Decrement { amount } => value -= amount,
+                _ => unreachable!(),
+            }
+
+            end = Some(next.end);
+        }
+
+        if let Some(end) = end {
+            Instruction::new(
+                InstructionKind::SetValue { value: value.0 },
+                instruction.loc,
+                end,
+            )
+        } else {
+            instruction
+        }
+    }
+
+    /// Replaces `instruction`, which sets the current cell, by the instructions after it as long as
+    /// they set the current cell as well, e.g. a `SetValue` followed by a clear by the clear. The
+    /// result starts at `instruction` and ends where the last instruction it replaces ends.
+    ///
+    /// The instructions after it are taken from the optimized output, as a clear usually is a loop
+    /// before it is optimized. The first instruction that doesn't set the current cell is returned
+    /// next.
+    fn fold_overwritten_value(&mut self, mut instruction: Instruction) -> Instruction {
+        while sets_current_cell(&instruction.kind) {
+            match self.next_unfolded() {
+                Some(next) if sets_current_cell(&next.kind) => {
+                    instruction.kind = next.kind;
+                    instruction.end = next.end;
+                }
+                Some(next) => {
+                    self.pending.push_front(next);
+                    break;
+                }
+                None => break,
+            }
+        }
+
+        instruction
+    }
+}
+
+/// Adds an increment or decrement of the cell at `relative_cell` by `amount` to `operations`. The
+/// operation of every cell keeps the direction of its first one, with the net amount in that
+/// direction.
+fn add_cell_operation(
+    operations: &mut BTreeMap<isize, (bool, Wrapping<u8>)>,
+    relative_cell: isize,
+    increment: bool,
+    amount: u8,
+) {
+    match operations.entry(relative_cell) {
+        Entry::Occupied(entry) => {
+            let (first_increment, net_amount) = entry.into_mut();
+            if *first_increment == increment {
+                *net_amount += amount;
+            } else {
+                *net_amount -= amount;
+            }
+        }
+        Entry::Vacant(entry) => {
+            entry.insert((increment, Wrapping(amount)));
+        }
+    }
+}
+
+/// Returns a move of the pointer by the non-zero `offset`, to the right if it is positive.
+fn move_by(offset: isize) -> InstructionKind {
+    if offset > 0 {
+        InstructionKind::MoveRight {
+            amount: offset as usize,
+        }
+    } else {
+        InstructionKind::MoveLeft {
+            amount: offset.unsigned_abs(),
+        }
+    }
+}
+
+/// Folds all increments and decrements of a cell within a run of moves, increments and decrements
+/// into the first of them, e.g. `+>+<-` into `>+<`. Operations that cancel out are removed, and
+/// the folded operation ends where the last operation of its cell ends.
+///
+/// The moves are kept as they are, so that the pointer visits the same cells and reaches a
+/// negative cell or the end of the tape exactly if it did before. As the run contains no outputs,
+/// inputs or loops, nothing observes the cells before the end of the run.
+struct FoldCellOperations<Iter>
+where
+    Iter: Iterator<Item = Instruction>,
+{
+    iter: Peekable<Iter>,
+    /// The folded instructions of the current run that are yet to be returned.
+    pending: VecDeque<Instruction>,
+    /// Whether runs end in front of a move left of the cell they started at, as it may stay at the
+    /// first cell instead of reaching the cell it is folded for.
+    clamp_left: bool,
+}
+
+impl<Iter> FoldCellOperations<Iter>
+where
+    Iter: Iterator<Item = Instruction>,
+{
+    fn new(iter: Iter, clamp_left: bool) -> Self {
+        Self {
+            iter: iter.peekable(),
+            pending: VecDeque::new(),
+            clamp_left,
+        }
+    }
+
+    /// Returns the offset of the pointer from the start of the run after `instruction`, which is
+    /// executed at `relative_cell`, or `None` if it can't be part of the run. A move whose offset
+    /// doesn't fit ends the run, which only happens far beyond any tape, and so does a move left of
+    /// the start of the run if `clamp_left` is set.
+    fn relative_cell_after(
+        relative_cell: isize,
+        instruction: &Instruction,
+        clamp_left: bool,
+    ) -> Option<isize> {
+        match instruction.kind {
+            InstructionKind::MoveRight { amount } => relative_cell.checked_add(amount as isize),
+            InstructionKind::MoveLeft { amount } => relative_cell
+                .checked_sub(amount as isize)
+                .filter(|relative_cell| !clamp_left || *relative_cell >= 0),
+            InstructionKind::Increment { amount: _ } | InstructionKind::Decrement { amount: _ } => {
+                Some(relative_cell)
+            }
+            _ => None,
+        }
+    }
+
+    /// Folds the run starting with `first` into `pending`.
+    fn fold_run(&mut self, first: Instruction) {
+        let mut run = Vec::new();
+        let mut relative_cell = 0isize;
+        let mut operations = BTreeMap::new();
+        let mut operation_ends = BTreeMap::new();
+        let mut next = Some(first);
+
+        while let Some(instruction) = next {
+            match instruction.kind {
+                InstructionKind::Increment { amount } => {
+                    add_cell_operation(&mut operations, relative_cell, true, amount);
+                    operation_ends.insert(relative_cell, instruction.end);
+                }
+                InstructionKind::Decrement { amount } => {
+                    add_cell_operation(&mut operations, relative_cell, false, amount);
+                    operation_ends.insert(relative_cell, instruction.end);
+                }
+                _ => {}
+            }
+
+            let next_relative_cell =
+                Self::relative_cell_after(relative_cell, &instruction, self.clamp_left).unwrap();
+            run.push((relative_cell, instruction));
+            relative_cell = next_relative_cell;
+
+            next = self.iter.next_if(|next| {
+                Self::relative_cell_after(relative_cell, next, self.clamp_left).is_some()
+            });
+        }
+
+        for (relative_cell, instruction) in run {
+            let (kind, end) = match instruction.kind {
+                InstructionKind::MoveRight { amount: _ }
+                | InstructionKind::MoveLeft { amount: _ } => (instruction.kind, instruction.end),
+                // Only the first operation of a cell is still in `operations`.
+                _ => match operations.remove(&relative_cell) {
+                    Some((_, Wrapping(0))) | None => continue,
+                    // More than half the range is a shorter change in the other direction, e.g. an
+                    // increment by 255 is a decrement by 1.
+                    Some((increment, Wrapping(amount))) => {
+                        let (increment, amount) = if amount > 128 {
+                            (!increment, amount.wrapping_neg())
                         } else {
-                            let movement = relative_cell - current_relative_cell;
-                            current_relative_cell = relative_cell;
-
-                            let movement_instruction = if movement > 0 {
-                                Instruction::MoveRight {
-                                    amount: movement as usize,
-                                }
-                            } else {
-                                Instruction::MoveLeft {
-                                    amount: movement.unsigned_abs(),
-                                }
-                            };
+                            (increment, amount)
+                        };
 
-                            let increment_instruction = if increment {
-                                Instruction::Increment { amount }
-                            } else {
-                                Instruction::Decrement { amount }
-                            };
+                        let kind = if increment {
+                            InstructionKind::Increment { amount }
+                        } else {
+                            InstructionKind::Decrement { amount }
+                        };
 
-                            let additional_instructions = if i == operation_count - 1 {
-                                let last_movement_instruction = if current_relative_cell > 0 {
-                                    Instruction::MoveLeft {
-                                        amount: current_relative_cell as usize,
-                                    }
-                                } else {
-                                    Instruction::MoveRight {
-                                        amount: current_relative_cell.unsigned_abs(),
-                                    }
-                                };
-
-                                Either::Left(iter::once(last_movement_instruction))
-                            } else {
-                                Either::Right(iter::empty())
-                            };
+                        (kind, operation_ends[&relative_cell])
+                    }
+                },
+            };
+
+            self.pending
+                .push_back(Instruction::new(kind, instruction.loc, end));
+        }
+    }
+}
+
+impl<Iter> Iterator for FoldCellOperations<Iter>
+where
+    Iter: Iterator<Item = Instruction>,
+{
+    type Item = Instruction;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.pending.is_empty() {
+            let instruction = self.iter.next()?;
+
+            if Self::relative_cell_after(0, &instruction, self.clamp_left).is_none() {
+                return Some(instruction);
+            }
+
+            self.fold_run(instruction);
+        }
+
+        self.pending.pop_front()
+    }
+}
+
+/// Whether `kind` sets the current cell to a value that doesn't depend on its previous one.
+fn sets_current_cell(kind: &InstructionKind) -> bool {
+    matches!(
+        kind,
+        InstructionKind::SetToZero | InstructionKind::SetValue { value: _ }
+    )
+}
+
+/// Optimizes a whole program like `Optimizer::new_with_clamp_left`, but optimizes its segments in
+/// parallel.
+///
+/// The program is split in front of every top-level loop. Neither merging moves nor folding values
+/// reaches into a loop, so the only state that crosses from one segment to the next is the known
+/// value of the current cell. All segments are optimized in parallel assuming that it is unknown,
+/// and the few segments for which this turns out to be wrong are optimized again afterwards. A loop
+/// may become a clear that continues a run of clears, so runs of clears are only folded into clear
+/// ranges once the segments are joined. The result is therefore identical to the one of the
+/// sequential optimizer.
+pub fn optimize_parallel(
+    instructions: &[Instruction],
+    max_unroll_iterations: usize,
+    explain: bool,
+    clamp_left: bool,
+    preserve_loops: bool,
+) -> (Vec<Instruction>, Vec<OptimizerWarning>, Vec<Explanation>) {
+    let mut segment_starts = instructions
+        .iter()
+        .enumerate()
+        .filter(|(_, instruction)| matches!(instruction.kind, InstructionKind::Loop { .. }))
+        .map(|(i, _)| i)
+        .collect::<Vec<_>>();
+    if segment_starts.first() != Some(&0) {
+        segment_starts.insert(0, 0);
+    }
+
+    let segments = segment_starts
+        .iter()
+        .zip(segment_starts.iter().skip(1).chain([&instructions.len()]))
+        .map(|(start, end)| &instructions[*start..*end])
+        .collect::<Vec<_>>();
+
+    let optimize_segment = |segment: &[Instruction], current_cell_value| {
+        let mut optimizer =
+            Optimizer::new_segment(segment.iter().cloned(), current_cell_value, clamp_left)
+                .with_max_unroll_iterations(max_unroll_iterations)
+                .with_explanations(explain)
+                .with_preserve_loops(preserve_loops);
+        // Runs of clears may continue into the next segment, so they are folded after joining them.
+        let instructions = iter::from_fn(|| optimizer.next_unfolded()).collect::<Vec<_>>();
+
+        (
+            instructions,
+            optimizer.warnings,
+            optimizer.explanations.unwrap_or_default(),
+            optimizer.current_cell_value,
+        )
+    };
+
+    // Only the first segment is known to start at a zero cell, the start of the tape.
+    let assumed_value = |i| (i == 0).then_some(0);
+
+    let optimized_segments = segments
+        .par_iter()
+        .enumerate()
+        .map(|(i, segment)| optimize_segment(segment, assumed_value(i)))
+        .collect::<Vec<_>>();
+
+    let mut optimized: Vec<Instruction> = Vec::new();
+    let mut warnings = Vec::new();
+    let mut explanations = Vec::new();
+    let mut current_cell_value = Some(0);
+
+    for (i, (segment, optimized_segment)) in
+        segments.into_iter().zip(optimized_segments).enumerate()
+    {
+        let (instructions, segment_warnings, segment_explanations, segment_end_value) =
+            if current_cell_value == assumed_value(i) {
+                optimized_segment
+            } else {
+                optimize_segment(segment, current_cell_value)
+            };
+
+        let mut instructions = instructions.into_iter();
+
+        // A segment may start with a loop that became a clear, which overwrites a value set at the
+        // end of the previous segment just like within a segment.
+        if let (Some(last), Some(first)) = (optimized.last_mut(), instructions.as_slice().first()) {
+            if sets_current_cell(&last.kind) && sets_current_cell(&first.kind) {
+                let first = instructions.next().unwrap();
+                last.kind = first.kind;
+                last.end = first.end;
+            }
+        }
+
+        optimized.extend(instructions);
+        warnings.extend(segment_warnings);
+        explanations.extend(segment_explanations);
+        current_cell_value = segment_end_value;
+    }
+
+    (
+        fold_clear_ranges(optimized, clamp_left),
+        warnings,
+        explanations,
+    )
+}
+
+/// Folds every run of clears and every transfer in `instructions` like `Optimizer` does, see
+/// `fold_run`.
+fn fold_clear_ranges(instructions: Vec<Instruction>, clamp_left: bool) -> Vec<Instruction> {
+    let mut folded = Vec::with_capacity(instructions.len());
+    let mut rest = VecDeque::from(instructions);
+
+    while let Some(first) = rest.pop_front() {
+        let (instructions, unfolded) = fold_run(first, || rest.pop_front(), clamp_left);
+
+        folded.extend(instructions);
+        for instruction in unfolded.into_iter().rev() {
+            rest.push_front(instruction);
+        }
+    }
+
+    folded
+}
+
+/// Folds the instructions that start with `first` and continue with the ones returned by `next`
+/// like `fold_transfer` if `first` is a move, and like `fold_clear_range` otherwise.
+fn fold_run(
+    first: Instruction,
+    next: impl FnMut() -> Option<Instruction>,
+    clamp_left: bool,
+) -> (Vec<Instruction>, Vec<Instruction>) {
+    match first.kind {
+        InstructionKind::MoveRight { amount: _ } | InstructionKind::MoveLeft { amount: _ } => {
+            fold_transfer(first, next, clamp_left)
+        }
+        _ => fold_clear_range(first, next, clamp_left),
+    }
+}
+
+/// Folds a move to a cell, a clear of it, a move back and a move of the current cell's value to
+/// it that start with `first` and continue with the instructions returned by `next` into a single
+/// `TransferValue`, e.g. `>[-]<[->+<]` into `TransferValue(1)`. As the cell is known to be zero
+/// after the clear, adding the value to it is the same as setting it.
+///
+/// Returns the folded instructions and the ones taken from `next` that aren't part of them. If
+/// `first` doesn't start such a transfer, it is returned unchanged.
+///
+/// With `clamp_left`, a transfer to the left isn't folded, as the moves only cancel out if the
+/// first one isn't clamped.
+fn fold_transfer(
+    first: Instruction,
+    mut next: impl FnMut() -> Option<Instruction>,
+    clamp_left: bool,
+) -> (Vec<Instruction>, Vec<Instruction>) {
+    let offset = match first.kind {
+        InstructionKind::MoveRight { amount } => isize::try_from(amount).ok(),
+        InstructionKind::MoveLeft { amount } if !clamp_left => {
+            isize::try_from(amount).ok().map(|amount| -amount)
+        }
+        _ => None,
+    };
+
+    let offset = match offset.filter(|offset| *offset != 0) {
+        Some(offset) => offset,
+        None => return (vec![first], Vec::new()),
+    };
+
+    let mut taken = Vec::new();
+
+    while taken.len() < 3 {
+        let instruction = match next() {
+            Some(instruction) => instruction,
+            None => return (vec![first], taken),
+        };
+
+        let matches = match (taken.len(), &instruction.kind) {
+            (0, InstructionKind::SetToZero) => true,
+            (1, InstructionKind::MoveRight { amount })
+            | (2, InstructionKind::MoveValueLeft { amount }) => {
+                isize::try_from(*amount).ok() == Some(-offset)
+            }
+            (1, InstructionKind::MoveLeft { amount })
+            | (2, InstructionKind::MoveValueRight { amount }) => {
+                isize::try_from(*amount).ok() == Some(offset)
+            }
+            _ => false,
+        };
+
+        taken.push(instruction);
+
+        if !matches {
+            return (vec![first], taken);
+        }
+    }
+
+    let transfer = Instruction::new(
+        InstructionKind::TransferValue { offset },
+        first.loc,
+        taken[2].end,
+    );
+
+    (vec![transfer], Vec::new())
+}
+
+/// Folds a run of clears of consecutive cells that starts with `first` and continues with the
+/// instructions returned by `next` into a single `ClearRange`, followed by a single move to where
+/// the moves between the clears end, e.g. `[-]>[-]>[-]<<` into `ClearRange(0, 3)`.
+///
+/// Returns the folded instructions and the ones taken from `next` that aren't part of them. If
+/// `first` doesn't start a run of at least two clears, it is returned unchanged.
+///
+/// A move right is only folded if it doesn't start left of the range. Without `clamp_left`, the
+/// program then fails moving left of the first cell exactly if the range starts left of it. With
+/// `clamp_left`, a move left is only folded if it doesn't end left of the range, so it can't be
+/// clamped.
+fn fold_clear_range(
+    first: Instruction,
+    mut next: impl FnMut() -> Option<Instruction>,
+    clamp_left: bool,
+) -> (Vec<Instruction>, Vec<Instruction>) {
+    let (mut start, mut end) = match cleared_range(&first.kind) {
+        Some(range) => range,
+        None => return (vec![first], Vec::new()),
+    };
+
+    // The pointer relative to the first clear and the span of the moves that were folded.
+    let mut offset = 0isize;
+    let mut moves_span: Option<(SourceLoc, SourceLoc)> = None;
+
+    let mut clears = 1;
+    let mut clears_end = first.end;
+    // The instructions taken since the last clear, which are returned if nothing is folded.
+    let mut since_last_clear = Vec::new();
+    let mut rest = None;
+
+    while let Some(instruction) = next() {
+        let offset_after = match instruction.kind {
+            InstructionKind::MoveRight { amount } if offset >= start => {
+                offset.checked_add(amount as isize)
+            }
+            InstructionKind::MoveLeft { amount } => offset
+                .checked_sub(amount as isize)
+                .filter(|offset| !clamp_left || *offset >= start),
+            _ => None,
+        };
+
+        if let Some(offset_after) = offset_after {
+            offset = offset_after;
+            moves_span = Some(match moves_span {
+                Some((loc, _)) => (loc, instruction.end),
+                None => (instruction.loc, instruction.end),
+            });
+            since_last_clear.push(instruction);
+            continue;
+        }
+
+        let cleared = cleared_range(&instruction.kind).and_then(|(clear_start, clear_end)| {
+            Some((
+                offset.checked_add(clear_start)?,
+                offset.checked_add(clear_end)?,
+            ))
+        });
+
+        match cleared {
+            // The cleared cells touch or overlap the range.
+            Some((clear_start, clear_end)) if clear_start <= end && clear_end >= start => {
+                start = start.min(clear_start);
+                end = end.max(clear_end);
+                clears += 1;
+                clears_end = instruction.end;
+                since_last_clear.clear();
+            }
+            _ => {
+                rest = Some(instruction);
+                break;
+            }
+        }
+    }
+
+    if clears == 1 {
+        since_last_clear.extend(rest);
+        return (vec![first], since_last_clear);
+    }
 
-                            Either::Right(
-                                [movement_instruction, increment_instruction]
-                                    .into_iter()
-                                    .chain(additional_instructions),
-                            )
+    let mut folded = vec![Instruction::new(
+        InstructionKind::ClearRange {
+            start_offset: start,
+            len: (end - start) as usize,
+        },
+        first.loc,
+        clears_end,
+    )];
+
+    if let Some((loc, moves_end)) = moves_span {
+        match offset.cmp(&0) {
+            Ordering::Greater => folded.push(Instruction::new(
+                InstructionKind::MoveRight {
+                    amount: offset as usize,
+                },
+                loc,
+                moves_end,
+            )),
+            Ordering::Less => folded.push(Instruction::new(
+                InstructionKind::MoveLeft {
+                    amount: offset.unsigned_abs(),
+                },
+                loc,
+                moves_end,
+            )),
+            Ordering::Equal => {}
+        }
+    }
+
+    (folded, rest.into_iter().collect())
+}
+
+/// The cells `kind` clears relative to the current one as a half-open range, if it is a clear.
+fn cleared_range(kind: &InstructionKind) -> Option<(isize, isize)> {
+    match kind {
+        InstructionKind::SetToZero => Some((0, 1)),
+        InstructionKind::ClearRange { start_offset, len } => {
+            Some((*start_offset, start_offset.checked_add(*len as isize)?))
+        }
+        _ => None,
+    }
+}
+
+impl<Iter> Optimizer<Iter>
+where
+    Iter: Iterator<Item = Instruction>,
+{
+    /// Returns the next optimized instruction before runs of clears are folded into clear ranges.
+    fn next_unfolded(&mut self) -> Option<Instruction> {
+        if let Some(instruction) = self.pending.pop_front() {
+            return Some(instruction);
+        }
+
+        let mut instruction = self.iter.next()?;
+
+        loop {
+            match instruction.kind {
+                // A loop never runs and a clear has no effect on a cell that is known to be zero,
+                // e.g. right after a `MoveValueRight`, which leaves its source cell cleared.
+                InstructionKind::Loop { instructions } if self.current_cell_value == Some(0) => {
+                    self.explain(
+                        instruction.loc,
+                        instruction.end,
+                        instructions,
+                        Vec::new(),
+                        RewriteReason::NeverEntered,
+                    );
+                }
+                InstructionKind::SetToZero | InstructionKind::SetValue { value: 0 }
+                    if self.current_cell_value == Some(0) => {}
+                InstructionKind::Nop => {}
+                InstructionKind::MoveRight { amount: _ }
+                | InstructionKind::MoveLeft { amount: _ } => {
+                    if let Some(merged) = self.merge_moves(instruction) {
+                        instruction = merged;
+                        break;
+                    }
+                }
+                _ => break,
+            }
+
+            instruction = self.iter.next()?;
+        }
+
+        let instruction = match instruction.kind {
+            InstructionKind::Loop { instructions } => {
+                let body = self.explanations.is_some().then(|| instructions.clone());
+                let (loc, end) = (instruction.loc, instruction.end);
+                let kind = self.optimize_loop(instructions, loc, end);
+
+                // A loop never terminates at a cell that isn't zero, so the counter is non-zero.
+                if let Some(counter) = self.current_cell_value {
+                    if let Some(unrolled) = self.unroll_known_loop(&kind, counter, loc, end) {
+                        if let Some(body) = body {
+                            let reason = RewriteReason::KnownCounter { counter };
+                            self.explain(loc, end, body, unrolled.clone(), reason);
                         }
-                    })
-                    .collect();
 
-                return Instruction::WithMultiplier { instructions };
+                        self.pending.extend(unrolled);
+                        self.current_cell_value = Some(0);
+
+                        return self.next_unfolded();
+                    }
+                }
+
+                let instruction = Instruction::new(kind, loc, end);
+
+                if let (Some(body), Some(reason)) = (body, RewriteReason::of(&instruction.kind)) {
+                    self.explain(loc, end, body, vec![instruction.clone()], reason);
+                }
+
+                instruction
+            }
+            _ => instruction,
+        };
+
+        let instruction = self.fold_set_value(instruction);
+
+        self.current_cell_value = match &instruction.kind {
+            InstructionKind::Loop { instructions: _ }
+            | InstructionKind::MoveRightUntilZero { step_size: _ }
+            | InstructionKind::MoveLeftUntilZero { step_size: _ }
+            | InstructionKind::OutputUntilZero { step_size: _ }
+            | InstructionKind::SetToZero
+            | InstructionKind::WithMultiplier { instructions: _ }
+            | InstructionKind::MoveValueRight { amount: _ }
+            | InstructionKind::MoveValueLeft { amount: _ } => Some(0),
+            InstructionKind::SetValue { value } | InstructionKind::AssertEquals { value } => {
+                Some(*value)
             }
+            InstructionKind::Increment { amount } => self
+                .current_cell_value
+                .map(|value| value.wrapping_add(*amount)),
+            InstructionKind::Decrement { amount } => self
+                .current_cell_value
+                .map(|value| value.wrapping_sub(*amount)),
+            InstructionKind::Output | InstructionKind::Nop => self.current_cell_value,
+            _ => None,
+        };
+
+        let instruction = self.fold_overwritten_value(instruction);
+
+        // The final pass: passes may replace the instructions they delete by `Nop`, which is never
+        // returned, just like the ones in the input that are skipped above.
+        if instruction.kind == InstructionKind::Nop {
+            return self.next_unfolded();
         }
 
-        Instruction::Loop { instructions }
+        Some(instruction)
     }
 }
 
@@ -185,9 +1279,245 @@ where
     type Item = Instruction;
 
     fn next(&mut self) -> Option<Self::Item> {
-        self.iter.next().map(|instruction| match instruction {
-            Instruction::Loop { instructions } => self.optimize_loop(instructions),
-            _ => instruction,
-        })
+        if let Some(instruction) = self.folded.pop_front() {
+            return Some(instruction);
+        }
+
+        let first = match self.lookahead.pop_front() {
+            Some(instruction) => instruction,
+            None => self.next_unfolded()?,
+        };
+
+        let clamp_left = self.clamp_left;
+        let (folded, unfolded) = fold_run(
+            first,
+            || self.lookahead.pop_front().or_else(|| self.next_unfolded()),
+            clamp_left,
+        );
+
+        // The instructions after a clear range or a transfer may start another one.
+        for instruction in unfolded.into_iter().rev() {
+            self.lookahead.push_front(instruction);
+        }
+
+        self.folded.extend(folded);
+        self.folded.pop_front()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{parser::Parser, tok::Tokenizer};
+
+    use InstructionKind::*;
+
+    /// Returns the kinds of the instructions `source` is optimized into. Most sources start with an
+    /// input, as a loop on the first cell, which is known to be zero, is dropped.
+    fn optimize(source: &str) -> Vec<InstructionKind> {
+        let instructions = Parser::new(Tokenizer::new(source)).map(Result::unwrap);
+
+        Optimizer::new(instructions)
+            .map(|instruction| instruction.kind)
+            .collect()
+    }
+
+    #[test]
+    fn set_value_folds_following_changes() {
+        // The changes cancel out in `FoldCellOperations` before they reach the clear, which stays
+        // the `SetToZero` it was, the canonical form of setting a cell to zero.
+        assert_eq!(optimize(",[-]+++---"), [Input, SetToZero]);
+        assert_eq!(optimize(",[-]+++++"), [Input, SetValue { value: 5 }]);
+    }
+
+    #[test]
+    fn clear_after_value_move_is_dropped() {
+        assert_eq!(
+            optimize(",[->+<][-]"),
+            [Input, MoveValueRight { amount: 1 }]
+        );
+    }
+
+    #[test]
+    fn known_counter_unrolls_multiply() {
+        assert_eq!(
+            optimize("+++[>++<-]"),
+            [
+                Increment { amount: 3 },
+                MoveRight { amount: 1 },
+                Increment { amount: 6 },
+                MoveLeft { amount: 1 },
+                SetToZero,
+            ]
+        );
+    }
+
+    #[test]
+    fn only_single_step_clear_loops_are_set_to_zero() {
+        assert_eq!(optimize(",[-]"), [Input, SetToZero]);
+        assert_eq!(optimize(",[+]"), [Input, SetToZero]);
+
+        // An odd cell never reaches zero when decremented by 2.
+        let instructions = optimize(",[--]");
+        assert_eq!(instructions.len(), 2);
+        assert!(matches!(instructions[1], Loop { instructions: _ }));
+    }
+
+    #[test]
+    fn set_value_folds_wrapping_changes_and_clears() {
+        assert_eq!(optimize(",[-]---"), [Input, SetValue { value: 253 }]);
+        assert_eq!(optimize(",[-]+++[-]"), [Input, SetToZero]);
+    }
+
+    #[test]
+    fn changes_cancel_out_across_moves() {
+        assert_eq!(
+            optimize("+>+<-"),
+            [
+                MoveRight { amount: 1 },
+                Increment { amount: 1 },
+                MoveLeft { amount: 1 }
+            ]
+        );
+
+        // The changes don't cancel out across an output of the cell.
+        assert_eq!(
+            optimize("+.>+<-"),
+            [
+                Increment { amount: 1 },
+                Output,
+                MoveRight { amount: 1 },
+                Increment { amount: 1 },
+                MoveLeft { amount: 1 },
+                Decrement { amount: 1 }
+            ]
+        );
+    }
+
+    #[test]
+    fn clears_of_consecutive_cells_fold() {
+        assert_eq!(
+            optimize(&format!(",{}", "[-]>".repeat(16))),
+            [
+                Input,
+                ClearRange {
+                    start_offset: 0,
+                    len: 16
+                },
+                MoveRight { amount: 16 }
+            ]
+        );
+    }
+
+    #[test]
+    fn multiplier_body_skips_return_at_origin() {
+        // The changes of the second cell cancel out, so the body doesn't move at all.
+        assert_eq!(optimize(",[->+<>-<]"), [Input, SetToZero]);
+
+        // The body returns from the last cell it changes, not from the one whose changes cancel.
+        let body = match optimize(",[->+>++<-<]").as_slice() {
+            [Input, WithMultiplier { instructions }] => instructions
+                .iter()
+                .map(|instruction| instruction.kind.clone())
+                .collect::<Vec<_>>(),
+            instructions => panic!("not a multiplier: {:?}", instructions),
+        };
+
+        assert_eq!(
+            body,
+            [
+                MoveRight { amount: 2 },
+                Increment { amount: 2 },
+                MoveLeft { amount: 2 }
+            ]
+        );
+    }
+
+    #[test]
+    fn clear_before_value_move_becomes_transfer() {
+        assert_eq!(optimize(">[-]<[->+<]"), [TransferValue { offset: 1 }]);
+        assert_eq!(
+            optimize(",>[-]<[->+<]"),
+            [Input, TransferValue { offset: 1 }]
+        );
+    }
+
+    #[test]
+    fn parallel_matches_sequential() {
+        let sources = [
+            ",[-]>[-]>[-]<<[->+<]>[<+>-]+++[>++<-]>.[-]<<,[.,][-]>[-]",
+            include_str!("../examples/Mandelbrot.bf"),
+        ];
+
+        for source in sources {
+            let instructions = Parser::new(Tokenizer::new(source))
+                .map(Result::unwrap)
+                .collect::<Vec<_>>();
+
+            assert_eq!(
+                optimize_parallel(
+                    &instructions,
+                    DEFAULT_MAX_UNROLL_ITERATIONS,
+                    true,
+                    false,
+                    false
+                ),
+                crate::compiler::optimize(
+                    &instructions,
+                    DEFAULT_MAX_UNROLL_ITERATIONS,
+                    true,
+                    false,
+                    false
+                )
+            );
+        }
+    }
+
+    #[test]
+    fn explanations_of_clear_and_move() {
+        let source = ",[-]>,[->+<]";
+        let instructions = Parser::new(Tokenizer::new(source)).map(Result::unwrap);
+        let mut optimizer = Optimizer::new(instructions).with_explanations(true);
+        optimizer.by_ref().for_each(drop);
+
+        let explanations = optimizer
+            .explanations()
+            .iter()
+            .map(|explanation| {
+                explanation
+                    .describe_with_source(&source[explanation.loc.offset..explanation.end.offset])
+            })
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            explanations,
+            [
+                "1:2: [-] -> SetToZero (the body only changes the current cell, by an odd amount)",
+                "1:7: [->+<] -> MoveValueRight(1) (the body decrements the current cell once and \
+                 increments one other cell once)"
+            ]
+        );
+    }
+
+    #[test]
+    fn preserved_loop_keeps_parsed_body() {
+        // The output keeps the loop from being rewritten, while its body would otherwise fold the
+        // clear and the changes after it into a `SetValue`.
+        let source = ",[>[-]+++--.<,]";
+        let parsed = Parser::new(Tokenizer::new(source))
+            .map(Result::unwrap)
+            .collect::<Vec<_>>();
+        let loop_body = |instructions: Vec<Instruction>| match instructions[1].kind.clone() {
+            Loop { instructions } => instructions,
+            kind => panic!("{:?} isn't a loop", kind),
+        };
+
+        let preserved = Optimizer::new(parsed.clone().into_iter())
+            .with_preserve_loops(true)
+            .collect();
+        assert_eq!(loop_body(preserved), loop_body(parsed.clone()));
+
+        let optimized = Optimizer::new(parsed.clone().into_iter()).collect();
+        assert_ne!(loop_body(optimized), loop_body(parsed));
     }
 }
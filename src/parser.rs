@@ -3,26 +3,33 @@ use std::{iter::Peekable, num::Wrapping};
 use replace_with::replace_with_or_abort_and_return;
 
 use crate::{
-    instruction::Instruction,
-    tok::{TokenType, Tokenizer},
+    instruction::{Instruction, InstructionKind},
+    tok::{SourceLoc, TokenType, Tokenizer},
 };
 
-impl Instruction {
-    fn parse_move(right: bool, tokenizer: &mut Peekable<Tokenizer>) -> Self {
-        let expected = if right {
-            TokenType::MoveRight
-        } else {
-            TokenType::MoveLeft
-        };
+pub use detail::ParseError;
 
-        let mut amount = 1;
-        while tokenizer
-            .next_if(|token| token.token_type == expected)
-            .is_some()
-        {
-            amount += 1;
+impl InstructionKind {
+    /// Parses a run of `expected` tokens, which each move the pointer `step` cells to the right or
+    /// the left, into a single move. `end` is moved to the end of the last token of the run.
+    fn parse_move(
+        expected: TokenType,
+        right: bool,
+        step: usize,
+        tokenizer: &mut Peekable<Tokenizer>,
+        end: &mut SourceLoc,
+    ) -> Self {
+        let mut amount = step;
+        while let Some(token) = tokenizer.next_if(|token| token.token_type == expected) {
+            amount = amount.saturating_add(step);
+            *end = token.end;
         }
 
+        // No tape can hold more than `isize::MAX` cells, so a longer move always fails anyway. The
+        // cap lets every move be converted into an offset, and moves right can be added to any
+        // cell of the tape without overflowing.
+        let amount = amount.min(isize::MAX as usize);
+
         if right {
             Self::MoveRight { amount }
         } else {
@@ -30,7 +37,11 @@ impl Instruction {
         }
     }
 
-    fn parse_change_cell(increment: bool, tokenizer: &mut Peekable<Tokenizer>) -> Self {
+    fn parse_change_cell(
+        increment: bool,
+        tokenizer: &mut Peekable<Tokenizer>,
+        end: &mut SourceLoc,
+    ) -> Self {
         let expected = if increment {
             TokenType::Increment
         } else {
@@ -38,11 +49,9 @@ impl Instruction {
         };
 
         let mut amount = Wrapping(1u8);
-        while tokenizer
-            .next_if(|token| token.token_type == expected)
-            .is_some()
-        {
+        while let Some(token) = tokenizer.next_if(|token| token.token_type == expected) {
             amount += 1;
+            *end = token.end;
         }
 
         let amount = amount.0;
@@ -66,12 +75,17 @@ mod detail {
 
     use crate::tok::{SourceLoc, TokenType, Tokenizer};
 
-    use super::Instruction;
+    use super::{Instruction, InstructionKind};
 
     #[derive(Debug, Copy, Clone, PartialEq, Eq)]
-    pub(crate) enum ParseError {
+    pub enum ParseError {
         UnexpectedLoopEnd(SourceLoc),
-        ExpectedLoopEnd(SourceLoc),
+        /// The input ends inside the loop starting at `loop_start`, the outermost of the
+        /// `open_loops` loops that are still open.
+        ExpectedLoopEnd {
+            loop_start: SourceLoc,
+            open_loops: usize,
+        },
     }
 
     impl Display for ParseError {
@@ -80,9 +94,40 @@ mod detail {
                 Self::UnexpectedLoopEnd(loc) => {
                     f.write_fmt(format_args!("unexpected loop end at {}", loc))
                 }
-                Self::ExpectedLoopEnd(loc) => {
-                    f.write_fmt(format_args!("expected loop end for start at {}", loc))
-                }
+                Self::ExpectedLoopEnd {
+                    loop_start,
+                    open_loops: 1,
+                } => f.write_fmt(format_args!(
+                    "expected loop end for start at {}",
+                    loop_start
+                )),
+                Self::ExpectedLoopEnd {
+                    loop_start,
+                    open_loops: 2,
+                } => f.write_fmt(format_args!(
+                    "expected loop end for start at {} and for the open loop nested in it",
+                    loop_start
+                )),
+                Self::ExpectedLoopEnd {
+                    loop_start,
+                    open_loops,
+                } => f.write_fmt(format_args!(
+                    "expected loop end for start at {} and for the {} open loops nested in it",
+                    loop_start,
+                    open_loops - 1
+                )),
+            }
+        }
+    }
+
+    impl ParseError {
+        pub fn loc(&self) -> SourceLoc {
+            match self {
+                Self::UnexpectedLoopEnd(loc)
+                | Self::ExpectedLoopEnd {
+                    loop_start: loc,
+                    open_loops: _,
+                } => *loc,
             }
         }
     }
@@ -92,6 +137,8 @@ mod detail {
     pub(crate) struct Parser<'a> {
         pub(crate) tokenizer: Peekable<Tokenizer<'a>>,
         loop_start: Option<SourceLoc>,
+        /// The end of the `]` of the loop, once it has been parsed.
+        loop_end: Option<SourceLoc>,
     }
 
     impl<'a> Parser<'a> {
@@ -99,6 +146,7 @@ mod detail {
             Self {
                 tokenizer,
                 loop_start: None,
+                loop_end: None,
             }
         }
 
@@ -106,6 +154,7 @@ mod detail {
             Self {
                 tokenizer,
                 loop_start: Some(loop_start),
+                loop_end: None,
             }
         }
     }
@@ -117,51 +166,83 @@ mod detail {
             let token = if let Some(token) = self.tokenizer.next() {
                 token
             } else if let Some(loop_start) = self.loop_start {
-                return Some(Err(ParseError::ExpectedLoopEnd(loop_start)));
+                return Some(Err(ParseError::ExpectedLoopEnd {
+                    loop_start,
+                    open_loops: 1,
+                }));
             } else {
                 return None;
             };
 
-            match token.token_type {
+            let tokenizer = &mut self.tokenizer;
+            let mut end = token.end;
+            let kind = match token.token_type {
                 TokenType::MoveRight => {
-                    Some(Ok(Instruction::parse_move(true, &mut self.tokenizer)))
+                    InstructionKind::parse_move(token.token_type, true, 1, tokenizer, &mut end)
                 }
                 TokenType::MoveLeft => {
-                    Some(Ok(Instruction::parse_move(false, &mut self.tokenizer)))
+                    InstructionKind::parse_move(token.token_type, false, 1, tokenizer, &mut end)
+                }
+                TokenType::MoveDown(width) => {
+                    InstructionKind::parse_move(token.token_type, true, width, tokenizer, &mut end)
+                }
+                TokenType::MoveUp(width) => {
+                    InstructionKind::parse_move(token.token_type, false, width, tokenizer, &mut end)
                 }
-                TokenType::Increment => Some(Ok(Instruction::parse_change_cell(
-                    true,
-                    &mut self.tokenizer,
-                ))),
-                TokenType::Decrement => Some(Ok(Instruction::parse_change_cell(
-                    false,
-                    &mut self.tokenizer,
-                ))),
-                TokenType::Output => Some(Ok(Instruction::Output)),
-                TokenType::Input => Some(Ok(Instruction::Input)),
+                TokenType::Increment => {
+                    InstructionKind::parse_change_cell(true, tokenizer, &mut end)
+                }
+                TokenType::Decrement => {
+                    InstructionKind::parse_change_cell(false, tokenizer, &mut end)
+                }
+                TokenType::Output => InstructionKind::Output,
+                TokenType::Input => InstructionKind::Input,
+                TokenType::AssertEquals(value) => InstructionKind::AssertEquals { value },
                 TokenType::LoopStart => {
-                    replace_with_or_abort_and_return(&mut self.tokenizer, |tokenizer| {
-                        let mut loop_parser = Parser::new_loop(tokenizer, token.loc);
+                    let loop_instructions =
+                        replace_with_or_abort_and_return(&mut self.tokenizer, |tokenizer| {
+                            let mut loop_parser = Parser::new_loop(tokenizer, token.loc);
+
+                            let loop_instructions = (&mut loop_parser)
+                                .collect::<Result<Vec<_>, _>>()
+                                .map(|instructions| (instructions, loop_parser.loop_end.unwrap()));
 
-                        let loop_instructions = (&mut loop_parser).collect::<Result<Vec<_>, _>>();
+                            (loop_instructions, loop_parser.tokenizer)
+                        });
 
+                    match (loop_instructions, self.loop_start) {
+                        (Ok((instructions, loop_end)), _) => {
+                            end = loop_end;
+                            InstructionKind::Loop { instructions }
+                        }
+                        // The error names the outermost loop that is still open and counts the
+                        // open loops nested in it.
                         (
-                            Some(
-                                loop_instructions
-                                    .map(|instructions| Instruction::Loop { instructions }),
-                            ),
-                            loop_parser.tokenizer,
-                        )
-                    })
+                            Err(ParseError::ExpectedLoopEnd {
+                                loop_start: _,
+                                open_loops,
+                            }),
+                            Some(loop_start),
+                        ) => {
+                            return Some(Err(ParseError::ExpectedLoopEnd {
+                                loop_start,
+                                open_loops: open_loops + 1,
+                            }))
+                        }
+                        (Err(error), _) => return Some(Err(error)),
+                    }
                 }
                 TokenType::LoopEnd => {
-                    if self.loop_start.is_some() {
+                    return if self.loop_start.is_some() {
+                        self.loop_end = Some(token.end);
                         None
                     } else {
                         Some(Err(ParseError::UnexpectedLoopEnd(token.loc)))
-                    }
+                    };
                 }
-            }
+            };
+
+            Some(Ok(Instruction::new(kind, token.loc, end)))
         }
     }
 }
@@ -179,15 +260,52 @@ impl<'a> Parser<'a> {
 }
 
 impl<'a> Iterator for Parser<'a> {
-    type Item = Instruction;
+    type Item = Result<Instruction, ParseError>;
 
-    fn next(&mut self) -> Option<Instruction> {
+    fn next(&mut self) -> Option<Self::Item> {
         replace_with_or_abort_and_return(&mut self.tokenizer, |tokenizer| {
             let mut parser = detail::Parser::new(tokenizer);
 
             let instruction = parser.next();
 
-            (instruction.map(Result::unwrap), parser.tokenizer)
+            (instruction, parser.tokenizer)
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(source: &str) -> Vec<Result<Instruction, ParseError>> {
+        Parser::new(Tokenizer::new(source)).collect()
+    }
+
+    #[test]
+    fn long_move_run() {
+        let instructions = parse(&">".repeat(10_000_000));
+
+        assert_eq!(instructions.len(), 1);
+        assert_eq!(
+            instructions[0].as_ref().unwrap().kind,
+            InstructionKind::MoveRight { amount: 10_000_000 }
+        );
+    }
+
+    #[test]
+    fn unclosed_loops_report_outermost_start() {
+        let instructions = parse("+\n [[");
+
+        assert_eq!(
+            instructions.last().unwrap(),
+            &Err(ParseError::ExpectedLoopEnd {
+                loop_start: SourceLoc {
+                    line: 2,
+                    col: 2,
+                    offset: 3,
+                },
+                open_loops: 2,
+            })
+        );
+    }
+}
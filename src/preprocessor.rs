@@ -0,0 +1,222 @@
+use std::{
+    collections::HashMap,
+    error::Error,
+    fmt::{Display, Formatter, Result as FmtResult},
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+#[derive(Debug)]
+pub enum PreprocessError {
+    Io(PathBuf, io::Error),
+    /// The file includes itself, directly or through other files.
+    IncludeCycle(PathBuf),
+    /// An `@include` without a path or a `@define` without a name.
+    MissingArgument {
+        directive: &'static str,
+        file: PathBuf,
+        line: usize,
+    },
+    UnknownMacro {
+        name: String,
+        file: PathBuf,
+        line: usize,
+    },
+    /// The body of the macro uses the macro itself, directly or through other macros.
+    RecursiveMacro {
+        name: String,
+        file: PathBuf,
+        line: usize,
+    },
+}
+
+impl Display for PreprocessError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        match self {
+            Self::Io(path, error) => f.write_fmt(format_args!("{}: {}", path.display(), error)),
+            Self::IncludeCycle(path) => {
+                f.write_fmt(format_args!("{} includes itself", path.display()))
+            }
+            Self::MissingArgument {
+                directive,
+                file,
+                line,
+            } => f.write_fmt(format_args!(
+                "{}:{}: {} is missing its argument",
+                file.display(),
+                line,
+                directive
+            )),
+            Self::UnknownMacro { name, file, line } => f.write_fmt(format_args!(
+                "{}:{}: unknown macro @{}",
+                file.display(),
+                line,
+                name
+            )),
+            Self::RecursiveMacro { name, file, line } => f.write_fmt(format_args!(
+                "{}:{}: macro @{} uses itself",
+                file.display(),
+                line,
+                name
+            )),
+        }
+    }
+}
+
+impl Error for PreprocessError {}
+
+const INCLUDE: &str = "@include";
+const DEFINE: &str = "@define";
+
+/// Expands the directives in `source`, the contents of `file`, returning the plain program:
+///
+/// - A line `@include PATH` is replaced by the expanded contents of the file at `PATH`, relative to
+///   the directory of the including file.
+/// - A line `@define NAME BODY` defines the macro `NAME` for the rest of the program, including the
+///   files included afterwards, and is removed.
+/// - `@NAME` anywhere else is replaced by the expanded body of the macro `NAME`.
+///
+/// Directives may be indented. A lone `@` that isn't followed by a name is kept as a comment.
+pub fn preprocess(source: &str, file: &Path) -> Result<String, PreprocessError> {
+    let mut preprocessor = Preprocessor {
+        macros: HashMap::new(),
+        include_stack: vec![fs::canonicalize(file).unwrap_or_else(|_| file.to_owned())],
+    };
+
+    let mut output = String::with_capacity(source.len());
+    preprocessor.preprocess_file(source, file, &mut output)?;
+
+    Ok(output)
+}
+
+struct Preprocessor {
+    macros: HashMap<String, String>,
+    /// The canonical paths of the files that are being expanded, to detect include cycles.
+    include_stack: Vec<PathBuf>,
+}
+
+impl Preprocessor {
+    fn preprocess_file(
+        &mut self,
+        source: &str,
+        file: &Path,
+        output: &mut String,
+    ) -> Result<(), PreprocessError> {
+        for (index, line) in source.lines().enumerate() {
+            let line_number = index + 1;
+            let missing_argument = |directive| PreprocessError::MissingArgument {
+                directive,
+                file: file.to_owned(),
+                line: line_number,
+            };
+
+            if let Some(path) = Self::directive_argument(line, INCLUDE) {
+                if path.is_empty() {
+                    return Err(missing_argument(INCLUDE));
+                }
+
+                let directory = file.parent().unwrap_or_else(|| Path::new(""));
+                self.include(&directory.join(path), output)?;
+            } else if let Some(definition) = Self::directive_argument(line, DEFINE) {
+                let (name, body) = definition
+                    .split_once(char::is_whitespace)
+                    .unwrap_or((definition, ""));
+
+                if name.is_empty() {
+                    return Err(missing_argument(DEFINE));
+                }
+
+                self.macros.insert(name.to_owned(), body.trim().to_owned());
+            } else {
+                self.expand(line, &mut Vec::new(), file, line_number, output)?;
+                output.push('\n');
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Returns the trimmed rest of `line` if it is the directive `directive`.
+    fn directive_argument<'a>(line: &'a str, directive: &str) -> Option<&'a str> {
+        let rest = line.trim_start().strip_prefix(directive)?;
+
+        // E.g. `@included` is a macro, not an include.
+        if rest.starts_with(|c: char| !c.is_whitespace()) {
+            return None;
+        }
+
+        Some(rest.trim())
+    }
+
+    fn include(&mut self, path: &Path, output: &mut String) -> Result<(), PreprocessError> {
+        let path =
+            fs::canonicalize(path).map_err(|error| PreprocessError::Io(path.to_owned(), error))?;
+
+        if self.include_stack.contains(&path) {
+            return Err(PreprocessError::IncludeCycle(path));
+        }
+
+        let source =
+            fs::read_to_string(&path).map_err(|error| PreprocessError::Io(path.clone(), error))?;
+
+        self.include_stack.push(path.clone());
+        self.preprocess_file(&source, &path, output)?;
+        self.include_stack.pop();
+
+        Ok(())
+    }
+
+    /// Appends `text` to `output` with all macros expanded. `active` holds the macros whose bodies
+    /// are being expanded, and `file` and `line` locate `text` for errors.
+    fn expand(
+        &self,
+        text: &str,
+        active: &mut Vec<String>,
+        file: &Path,
+        line: usize,
+        output: &mut String,
+    ) -> Result<(), PreprocessError> {
+        let mut rest = text;
+
+        while let Some(at) = rest.find('@') {
+            output.push_str(&rest[..at]);
+
+            let after = &rest[at + 1..];
+            let name_length = after
+                .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                .unwrap_or(after.len());
+            let name = &after[..name_length];
+            rest = &after[name_length..];
+
+            if name.is_empty() {
+                output.push('@');
+                continue;
+            }
+
+            let body = self
+                .macros
+                .get(name)
+                .ok_or_else(|| PreprocessError::UnknownMacro {
+                    name: name.to_owned(),
+                    file: file.to_owned(),
+                    line,
+                })?;
+
+            if active.iter().any(|active_name| active_name == name) {
+                return Err(PreprocessError::RecursiveMacro {
+                    name: name.to_owned(),
+                    file: file.to_owned(),
+                    line,
+                });
+            }
+
+            active.push(name.to_owned());
+            self.expand(body, active, file, line, output)?;
+            active.pop();
+        }
+
+        output.push_str(rest);
+
+        Ok(())
+    }
+}
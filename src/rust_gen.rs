@@ -0,0 +1,298 @@
+use crate::instruction::{Instruction, InstructionKind};
+
+const PRELUDE: &str = r#"#![allow(unused)]
+
+use std::{
+    io::{self, Read, Write},
+    process,
+};
+
+struct Tape {
+    cells: Vec<u8>,
+    pointer: usize,
+}
+
+impl Tape {
+    fn move_right(&mut self, amount: usize) {
+        self.pointer += amount;
+
+        if self.pointer >= self.cells.len() {
+            self.cells.resize((self.pointer + 1).next_power_of_two(), 0);
+        }
+    }
+
+    fn move_left(&mut self, amount: usize) {
+        if self.pointer < amount {
+            eprintln!("Error: Cannot move pointer to negative cell!");
+            process::exit(1);
+        }
+
+        self.pointer -= amount;
+    }
+
+    fn move_left_clamped(&mut self, amount: usize) {
+        self.pointer = self.pointer.saturating_sub(amount);
+    }
+
+    fn get(&self) -> u8 {
+        self.cells[self.pointer]
+    }
+
+    fn set(&mut self, value: u8) {
+        self.cells[self.pointer] = value;
+    }
+
+    fn clear_range(&mut self, start_offset: isize, len: usize) {
+        if start_offset < 0 && self.pointer < start_offset.unsigned_abs() {
+            eprintln!("Error: Cannot move pointer to negative cell!");
+            process::exit(1);
+        }
+
+        let start = self.pointer.wrapping_add(start_offset as usize);
+        if start + len > self.cells.len() {
+            self.cells.resize((start + len).next_power_of_two(), 0);
+        }
+
+        self.cells[start..start + len].fill(0);
+    }
+
+    fn add(&mut self, amount: u8) {
+        self.set(self.get().wrapping_add(amount));
+    }
+
+    fn sub(&mut self, amount: u8) {
+        self.set(self.get().wrapping_sub(amount));
+    }
+
+    fn assert_equals(&self, expected: u8) {
+        if self.get() != expected {
+            eprintln!(
+                "Error: Assertion failed at cell {}: expected {}, found {}!",
+                self.pointer,
+                expected,
+                self.get()
+            );
+            process::exit(1);
+        }
+    }
+}
+
+/// Reads a single byte, returning 0 at the end of the input.
+fn read_byte(input: &mut impl Read) -> u8 {
+    let mut byte = [0];
+    match input.read(&mut byte) {
+        Ok(1) => byte[0],
+        _ => 0,
+    }
+}
+
+fn write_byte(output: &mut impl Write, byte: u8) {
+    let _ = output.write_all(&[byte]).and_then(|_| output.flush());
+}
+
+fn main() {
+    let mut tape = Tape {
+        cells: vec![0; 256],
+        pointer: 0,
+    };
+    let mut stdin = io::stdin().lock();
+    let mut stdout = io::stdout().lock();
+"#;
+
+/// Transpiles instructions into a standalone Rust program that only depends on `std`.
+pub struct RustGen {
+    instructions: Vec<Instruction>,
+    annotate: bool,
+    clamp_left: bool,
+}
+
+impl RustGen {
+    pub fn new(instructions: Vec<Instruction>) -> Self {
+        Self {
+            instructions,
+            annotate: false,
+            clamp_left: false,
+        }
+    }
+
+    /// Precedes the code of every instruction with a comment naming the instruction and its
+    /// location in the source, e.g. `// MoveRight(3) at 1:1`.
+    pub fn with_annotations(mut self, annotate: bool) -> Self {
+        self.annotate = annotate;
+        self
+    }
+
+    /// Makes moves left of the first cell stay at it instead of exiting with an error, see
+    /// `CodeGenOptions::clamp_left`.
+    pub fn with_clamp_left(mut self, clamp_left: bool) -> Self {
+        self.clamp_left = clamp_left;
+        self
+    }
+
+    pub fn generate_source(&self) -> String {
+        let mut source = String::from(PRELUDE);
+
+        self.generate_instructions(&mut source, &self.instructions, 1, false);
+
+        source.push_str("}\n");
+        source
+    }
+
+    fn generate_instructions(
+        &self,
+        source: &mut String,
+        instructions: &[Instruction],
+        indent: usize,
+        has_multiplier: bool,
+    ) {
+        for instruction in instructions {
+            if self.annotate {
+                Self::push_line(source, indent, &format!("// {}", instruction.summary()));
+            }
+
+            self.generate_instruction(source, instruction, indent, has_multiplier);
+        }
+    }
+
+    fn generate_instruction(
+        &self,
+        source: &mut String,
+        instruction: &Instruction,
+        indent: usize,
+        has_multiplier: bool,
+    ) {
+        match &instruction.kind {
+            InstructionKind::MoveRight { amount } => {
+                Self::push_line(source, indent, &format!("tape.move_right({});", amount));
+            }
+            InstructionKind::MoveLeft { amount } => {
+                let method = if self.clamp_left {
+                    "move_left_clamped"
+                } else {
+                    "move_left"
+                };
+
+                Self::push_line(source, indent, &format!("tape.{}({});", method, amount));
+            }
+            InstructionKind::Increment { amount } | InstructionKind::Decrement { amount } => {
+                let method = if let InstructionKind::Increment { amount: _ } = &instruction.kind {
+                    "add"
+                } else {
+                    "sub"
+                };
+
+                let amount = if has_multiplier {
+                    format!("{}u8.wrapping_mul(multiplier)", amount)
+                } else {
+                    amount.to_string()
+                };
+
+                Self::push_line(source, indent, &format!("tape.{}({});", method, amount));
+            }
+            InstructionKind::Output => {
+                Self::push_line(source, indent, "write_byte(&mut stdout, tape.get());")
+            }
+            InstructionKind::Input => {
+                Self::push_line(source, indent, "tape.set(read_byte(&mut stdin));")
+            }
+            InstructionKind::Loop { instructions } => {
+                Self::push_line(source, indent, "while tape.get() != 0 {");
+                self.generate_instructions(source, instructions, indent + 1, false);
+                Self::push_line(source, indent, "}");
+            }
+            InstructionKind::MoveRightUntilZero { step_size } => {
+                Self::push_line(source, indent, "while tape.get() != 0 {");
+                Self::push_line(
+                    source,
+                    indent + 1,
+                    &format!("tape.move_right({});", step_size),
+                );
+                Self::push_line(source, indent, "}");
+            }
+            InstructionKind::MoveLeftUntilZero { step_size } => {
+                Self::push_line(source, indent, "while tape.get() != 0 {");
+                Self::push_line(
+                    source,
+                    indent + 1,
+                    &format!("tape.move_left({});", step_size),
+                );
+                Self::push_line(source, indent, "}");
+            }
+            InstructionKind::OutputUntilZero { step_size } => {
+                Self::push_line(source, indent, "while tape.get() != 0 {");
+                Self::push_line(source, indent + 1, "write_byte(&mut stdout, tape.get());");
+                Self::push_line(
+                    source,
+                    indent + 1,
+                    &format!("tape.move_right({});", step_size),
+                );
+                Self::push_line(source, indent, "}");
+            }
+            InstructionKind::SetToZero => Self::push_line(source, indent, "tape.set(0);"),
+            InstructionKind::SetValue { value } => {
+                Self::push_line(source, indent, &format!("tape.set({});", value));
+            }
+            InstructionKind::ClearRange { start_offset, len } => {
+                Self::push_line(
+                    source,
+                    indent,
+                    &format!("tape.clear_range({}, {});", start_offset, len),
+                );
+            }
+            InstructionKind::WithMultiplier { instructions } => {
+                Self::push_line(source, indent, "{");
+                Self::push_line(source, indent + 1, "let multiplier = tape.get();");
+                Self::push_line(source, indent + 1, "if multiplier != 0 {");
+                self.generate_instructions(source, instructions, indent + 2, true);
+                Self::push_line(source, indent + 2, "tape.set(0);");
+                Self::push_line(source, indent + 1, "}");
+                Self::push_line(source, indent, "}");
+            }
+            InstructionKind::MoveValueRight { amount }
+            | InstructionKind::MoveValueLeft { amount } => {
+                let (there, back) =
+                    if let InstructionKind::MoveValueRight { amount: _ } = &instruction.kind {
+                        ("move_right", "move_left")
+                    } else {
+                        ("move_left", "move_right")
+                    };
+
+                Self::push_line(source, indent, "{");
+                Self::push_line(source, indent + 1, "let value = tape.get();");
+                Self::push_line(source, indent + 1, "if value != 0 {");
+                Self::push_line(source, indent + 2, "tape.set(0);");
+                Self::push_line(source, indent + 2, &format!("tape.{}({});", there, amount));
+                Self::push_line(source, indent + 2, "tape.add(value);");
+                Self::push_line(source, indent + 2, &format!("tape.{}({});", back, amount));
+                Self::push_line(source, indent + 1, "}");
+                Self::push_line(source, indent, "}");
+            }
+            InstructionKind::TransferValue { offset } => {
+                let (there, back) = if *offset > 0 {
+                    ("move_right", "move_left")
+                } else {
+                    ("move_left", "move_right")
+                };
+                let amount = offset.unsigned_abs();
+
+                Self::push_line(source, indent, "{");
+                Self::push_line(source, indent + 1, "let value = tape.get();");
+                Self::push_line(source, indent + 1, "tape.set(0);");
+                Self::push_line(source, indent + 1, &format!("tape.{}({});", there, amount));
+                Self::push_line(source, indent + 1, "tape.set(value);");
+                Self::push_line(source, indent + 1, &format!("tape.{}({});", back, amount));
+                Self::push_line(source, indent, "}");
+            }
+            InstructionKind::AssertEquals { value } => {
+                Self::push_line(source, indent, &format!("tape.assert_equals({});", value));
+            }
+            InstructionKind::Nop => {}
+        }
+    }
+
+    fn push_line(source: &mut String, indent: usize, line: &str) {
+        source.push_str(&"    ".repeat(indent));
+        source.push_str(line);
+        source.push('\n');
+    }
+}
@@ -0,0 +1,383 @@
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    path::PathBuf,
+    time::{Duration, Instant},
+};
+
+use clap::ValueEnum;
+use log::debug;
+
+use crate::{
+    analysis,
+    instruction::{Instruction, InstructionKind},
+};
+
+/// The version of the JSON report schema, bumped whenever fields are removed or change meaning.
+const SCHEMA_VERSION: u32 = 1;
+
+const INSTRUCTION_KINDS: [&str; 19] = [
+    "move_right",
+    "move_left",
+    "increment",
+    "decrement",
+    "output",
+    "input",
+    "loop",
+    "move_right_until_zero",
+    "move_left_until_zero",
+    "output_until_zero",
+    "set_to_zero",
+    "set_value",
+    "clear_range",
+    "with_multiplier",
+    "move_value_right",
+    "move_value_left",
+    "transfer_value",
+    "assert_equals",
+    "nop",
+];
+
+const LOOP_KINDS: [&str; 6] = [
+    "general",
+    "clear",
+    "scan",
+    "multiply",
+    "move_value",
+    "print",
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum ReportFormat {
+    Human,
+    Json,
+}
+
+/// Counts of the instructions of a program, including the ones nested in loops.
+#[derive(Debug, Clone)]
+pub struct ProgramStats {
+    parsed_instructions: usize,
+    /// The number of loops in the parsed program, including the nested ones.
+    parsed_loops: usize,
+    /// The deepest nesting of loops in the parsed program, 0 if it has no loops.
+    max_loop_depth: usize,
+    instruction_counts: BTreeMap<&'static str, usize>,
+    /// The number of cells cleared by all `ClearRange`s, each of which stems from a clear loop.
+    range_cleared_cells: usize,
+}
+
+impl ProgramStats {
+    /// Collects the stats of the `optimized` program, which was parsed into `parsed`.
+    pub fn new(parsed: &[Instruction], optimized: &[Instruction]) -> Self {
+        let mut instruction_counts = INSTRUCTION_KINDS.iter().map(|kind| (*kind, 0)).collect();
+        Self::count_instructions(optimized, &mut instruction_counts);
+
+        Self {
+            parsed_instructions: Self::total_instructions(parsed),
+            parsed_loops: analysis::count_loops(parsed),
+            max_loop_depth: analysis::max_nesting_depth(parsed),
+            instruction_counts,
+            range_cleared_cells: Self::range_cleared_cells(optimized),
+        }
+    }
+
+    fn count_instructions(
+        instructions: &[Instruction],
+        instruction_counts: &mut BTreeMap<&'static str, usize>,
+    ) {
+        for instruction in instructions {
+            *instruction_counts.get_mut(Self::kind(instruction)).unwrap() += 1;
+
+            if let InstructionKind::Loop { instructions }
+            | InstructionKind::WithMultiplier { instructions } = &instruction.kind
+            {
+                Self::count_instructions(instructions, instruction_counts);
+            }
+        }
+    }
+
+    fn total_instructions(instructions: &[Instruction]) -> usize {
+        instructions
+            .iter()
+            .map(|instruction| match &instruction.kind {
+                InstructionKind::Loop { instructions }
+                | InstructionKind::WithMultiplier { instructions } => {
+                    1 + Self::total_instructions(instructions)
+                }
+                _ => 1,
+            })
+            .sum()
+    }
+
+    fn range_cleared_cells(instructions: &[Instruction]) -> usize {
+        instructions
+            .iter()
+            .map(|instruction| match &instruction.kind {
+                InstructionKind::ClearRange {
+                    start_offset: _,
+                    len,
+                } => *len,
+                InstructionKind::Loop { instructions }
+                | InstructionKind::WithMultiplier { instructions } => {
+                    Self::range_cleared_cells(instructions)
+                }
+                _ => 0,
+            })
+            .sum()
+    }
+
+    fn kind(instruction: &Instruction) -> &'static str {
+        match &instruction.kind {
+            InstructionKind::MoveRight { amount: _ } => "move_right",
+            InstructionKind::MoveLeft { amount: _ } => "move_left",
+            InstructionKind::Increment { amount: _ } => "increment",
+            InstructionKind::Decrement { amount: _ } => "decrement",
+            InstructionKind::Output => "output",
+            InstructionKind::Input => "input",
+            InstructionKind::Loop { instructions: _ } => "loop",
+            InstructionKind::MoveRightUntilZero { step_size: _ } => "move_right_until_zero",
+            InstructionKind::MoveLeftUntilZero { step_size: _ } => "move_left_until_zero",
+            InstructionKind::OutputUntilZero { step_size: _ } => "output_until_zero",
+            InstructionKind::SetToZero => "set_to_zero",
+            InstructionKind::SetValue { value: _ } => "set_value",
+            InstructionKind::ClearRange {
+                start_offset: _,
+                len: _,
+            } => "clear_range",
+            InstructionKind::WithMultiplier { instructions: _ } => "with_multiplier",
+            InstructionKind::MoveValueRight { amount: _ } => "move_value_right",
+            InstructionKind::MoveValueLeft { amount: _ } => "move_value_left",
+            InstructionKind::TransferValue { offset: _ } => "transfer_value",
+            InstructionKind::AssertEquals { value: _ } => "assert_equals",
+            InstructionKind::Nop => "nop",
+        }
+    }
+
+    fn total(&self) -> usize {
+        self.instruction_counts.values().sum()
+    }
+
+    /// Returns how many loops of the source the optimizer turned into each kind of instruction.
+    ///
+    /// Every `SetToZero` and `SetValue` stems from a clear loop, as the parser never emits them. A
+    /// `ClearRange` stems from a clear loop for each of its cells, unless the clears overlapped. A
+    /// `TransferValue` stems from both a clear loop and a move loop.
+    fn loop_counts(&self) -> [(&'static str, usize); LOOP_KINDS.len()] {
+        let count = |kinds: &[&str]| {
+            kinds
+                .iter()
+                .map(|kind| self.instruction_counts[kind])
+                .sum::<usize>()
+        };
+
+        [
+            (LOOP_KINDS[0], count(&["loop"])),
+            (
+                LOOP_KINDS[1],
+                count(&["set_to_zero", "set_value", "transfer_value"]) + self.range_cleared_cells,
+            ),
+            (
+                LOOP_KINDS[2],
+                count(&["move_right_until_zero", "move_left_until_zero"]),
+            ),
+            (LOOP_KINDS[3], count(&["with_multiplier"])),
+            (
+                LOOP_KINDS[4],
+                count(&["move_value_right", "move_value_left", "transfer_value"]),
+            ),
+            (LOOP_KINDS[5], count(&["output_until_zero"])),
+        ]
+    }
+}
+
+/// The time spent in each phase of the compilation, in the order the phases ran.
+#[derive(Debug, Clone)]
+pub struct Timings {
+    phases: Vec<(&'static str, Duration)>,
+    phase_start: Instant,
+}
+
+impl Timings {
+    pub fn start() -> Self {
+        Self {
+            phases: Vec::new(),
+            phase_start: Instant::now(),
+        }
+    }
+
+    /// Records that `phase` ended now and started when the previous one ended.
+    pub fn record(&mut self, phase: &'static str) {
+        let now = Instant::now();
+
+        let duration = now - self.phase_start;
+        debug!("{} took {:.2?}", phase, duration);
+
+        self.phases.push((phase, duration));
+        self.phase_start = now;
+    }
+
+    fn total(&self) -> Duration {
+        self.phases.iter().map(|(_, duration)| *duration).sum()
+    }
+}
+
+/// The metrics of a compilation requested with `--stats` and `--timings`.
+#[derive(Debug, Clone)]
+pub struct Report {
+    pub stats: Option<ProgramStats>,
+    pub timings: Option<Timings>,
+}
+
+impl Report {
+    /// Formats the report as tables for humans.
+    pub fn to_human(&self) -> String {
+        let mut output = String::new();
+
+        if let Some(stats) = &self.stats {
+            output.push_str("Instructions\n");
+            Self::push_row(&mut output, "parsed", stats.parsed_instructions);
+            Self::push_row(&mut output, "generated", stats.total());
+
+            for (kind, count) in &stats.instruction_counts {
+                if *count != 0 {
+                    Self::push_row(&mut output, kind, count);
+                }
+            }
+
+            output.push_str("Loops\n");
+            for (kind, count) in stats.loop_counts() {
+                Self::push_row(&mut output, kind, count);
+            }
+            Self::push_row(&mut output, "parsed", stats.parsed_loops);
+            Self::push_row(&mut output, "max_depth", stats.max_loop_depth);
+        }
+
+        if let Some(timings) = &self.timings {
+            output.push_str("Timings\n");
+            for (phase, duration) in &timings.phases {
+                Self::push_row(&mut output, phase, Self::format_duration(*duration));
+            }
+            Self::push_row(&mut output, "total", Self::format_duration(timings.total()));
+        }
+
+        output
+    }
+
+    /// Formats the report as a single JSON object, see the README for its schema.
+    pub fn to_json(&self, output_files: &[PathBuf]) -> String {
+        let mut output = String::new();
+
+        output.push('{');
+        write!(output, "\"version\":{}", SCHEMA_VERSION).unwrap();
+        write!(
+            output,
+            ",\"output_file\":{}",
+            json_string(&output_files[0].to_string_lossy())
+        )
+        .unwrap();
+
+        output.push_str(",\"output_files\":[");
+        for (i, output_file) in output_files.iter().enumerate() {
+            if i != 0 {
+                output.push(',');
+            }
+
+            output.push_str(&json_string(&output_file.to_string_lossy()));
+        }
+        output.push(']');
+
+        if let Some(stats) = &self.stats {
+            write!(
+                output,
+                ",\"stats\":{{\"parsed_instructions\":{},\"instructions\":{}",
+                stats.parsed_instructions,
+                stats.total()
+            )
+            .unwrap();
+
+            output.push_str(",\"instruction_counts\":");
+            Self::push_json_object(
+                &mut output,
+                stats
+                    .instruction_counts
+                    .iter()
+                    .map(|(kind, count)| (*kind, *count)),
+            );
+            output.push_str(",\"loops\":");
+            Self::push_json_object(&mut output, stats.loop_counts().into_iter());
+            write!(output, ",\"parsed_loops\":{}", stats.parsed_loops).unwrap();
+            write!(output, ",\"max_loop_depth\":{}", stats.max_loop_depth).unwrap();
+            output.push('}');
+        }
+
+        if let Some(timings) = &self.timings {
+            output.push_str(",\"timings\":{\"phases\":[");
+
+            for (i, (phase, duration)) in timings.phases.iter().enumerate() {
+                if i != 0 {
+                    output.push(',');
+                }
+
+                write!(
+                    output,
+                    "{{\"phase\":{},\"milliseconds\":{}}}",
+                    json_string(phase),
+                    duration.as_secs_f64() * 1000.0
+                )
+                .unwrap();
+            }
+
+            write!(
+                output,
+                "],\"total_milliseconds\":{}}}",
+                timings.total().as_secs_f64() * 1000.0
+            )
+            .unwrap();
+        }
+
+        output.push_str("}\n");
+        output
+    }
+
+    fn push_row(output: &mut String, name: &str, value: impl ToString) {
+        writeln!(output, "  {:<24}{:>12}", name, value.to_string()).unwrap();
+    }
+
+    fn format_duration(duration: Duration) -> String {
+        format!("{:.3} ms", duration.as_secs_f64() * 1000.0)
+    }
+
+    fn push_json_object<'b>(output: &mut String, entries: impl Iterator<Item = (&'b str, usize)>) {
+        output.push('{');
+
+        for (i, (key, value)) in entries.enumerate() {
+            if i != 0 {
+                output.push(',');
+            }
+
+            write!(output, "{}:{}", json_string(key), value).unwrap();
+        }
+
+        output.push('}');
+    }
+}
+
+/// Quotes `value` as a JSON string.
+pub(crate) fn json_string(value: &str) -> String {
+    let mut string = String::from('"');
+
+    for c in value.chars() {
+        match c {
+            '"' => string.push_str("\\\""),
+            '\\' => string.push_str("\\\\"),
+            '\n' => string.push_str("\\n"),
+            '\r' => string.push_str("\\r"),
+            '\t' => string.push_str("\\t"),
+            c if c.is_control() => write!(string, "\\u{:04x}", c as u32).unwrap(),
+            c => string.push(c),
+        }
+    }
+
+    string.push('"');
+    string
+}
@@ -1,4 +1,7 @@
-use std::fmt::{Debug, Display};
+use std::{
+    fmt::{Debug, Display},
+    str::FromStr,
+};
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum TokenType {
@@ -10,6 +13,11 @@ pub enum TokenType {
     Input,
     LoopStart,
     LoopEnd,
+    AssertEquals(u8),
+    /// Moves the pointer up a row of a grid with the given width.
+    MoveUp(usize),
+    /// Moves the pointer down a row of a grid with the given width.
+    MoveDown(usize),
 }
 
 impl TokenType {
@@ -28,10 +36,74 @@ impl TokenType {
     }
 }
 
+impl Display for TokenType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::MoveRight => f.write_str(">"),
+            Self::MoveLeft => f.write_str("<"),
+            Self::Increment => f.write_str("+"),
+            Self::Decrement => f.write_str("-"),
+            Self::Output => f.write_str("."),
+            Self::Input => f.write_str(","),
+            Self::LoopStart => f.write_str("["),
+            Self::LoopEnd => f.write_str("]"),
+            Self::AssertEquals(value) => f.write_fmt(format_args!("={}", value)),
+            Self::MoveUp(_) => f.write_str("^"),
+            Self::MoveDown(_) => f.write_str("v"),
+        }
+    }
+}
+
+/// A grid of cells laid out row by row on the tape, which the `^` and `v` directives move on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Grid {
+    pub width: usize,
+    pub height: usize,
+}
+
+impl Grid {
+    /// Returns the number of cells in the grid.
+    pub fn cells(&self) -> usize {
+        self.width.saturating_mul(self.height)
+    }
+}
+
+impl FromStr for Grid {
+    type Err = String;
+
+    /// Parses a grid given as `WIDTHxHEIGHT`, e.g. `80x25`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (width, height) = s
+            .split_once('x')
+            .ok_or_else(|| format!("expected WIDTHxHEIGHT, found {:?}", s))?;
+
+        let parse_dimension = |dimension: &str| {
+            dimension
+                .parse()
+                .ok()
+                .filter(|dimension| *dimension > 0)
+                .ok_or_else(|| format!("invalid grid dimension {:?}", dimension))
+        };
+
+        Ok(Self {
+            width: parse_dimension(width)?,
+            height: parse_dimension(height)?,
+        })
+    }
+}
+
+impl Display for Grid {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_fmt(format_args!("{}x{}", self.width, self.height))
+    }
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct SourceLoc {
     pub line: usize,
     pub col: usize,
+    /// The offset in bytes from the start of the input.
+    pub offset: usize,
 }
 
 impl Display for SourceLoc {
@@ -44,6 +116,8 @@ impl Display for SourceLoc {
 pub struct Token {
     pub token_type: TokenType,
     pub loc: SourceLoc,
+    /// The location right after the last character of the token.
+    pub end: SourceLoc,
 }
 
 #[derive(Debug, Default, Clone)]
@@ -51,6 +125,10 @@ pub struct Tokenizer<'a> {
     input: &'a str,
     line: usize,
     col: usize,
+    offset: usize,
+    assertions: bool,
+    grid: Option<Grid>,
+    comment_prefix: Option<char>,
 }
 
 impl<'a> Tokenizer<'a> {
@@ -59,8 +137,51 @@ impl<'a> Tokenizer<'a> {
             input,
             line: 1,
             col: 1,
+            offset: 0,
+            assertions: false,
+            grid: None,
+            comment_prefix: None,
         }
     }
+
+    /// Enables the `=N` directive, which asserts that the current cell equals the decimal value
+    /// `N` (0 to 255). Otherwise, or if `=` isn't followed by such a value, `=` is a comment.
+    pub fn with_assertions(mut self, assertions: bool) -> Self {
+        self.assertions = assertions;
+        self
+    }
+
+    /// Enables the `^` and `v` directives, which move the pointer up and down a row of `grid`, i.e.
+    /// by its width to the left and to the right. Otherwise, `^` and `v` are comments.
+    pub fn with_grid(mut self, grid: Option<Grid>) -> Self {
+        self.grid = grid;
+        self
+    }
+
+    /// Makes every line starting with `comment_prefix` a comment, so that prose in it may contain
+    /// commands.
+    pub fn with_comment_prefix(mut self, comment_prefix: Option<char>) -> Self {
+        self.comment_prefix = comment_prefix;
+        self
+    }
+
+    /// Returns an iterator over the remaining tokens together with the text of the input each of
+    /// them occupies, e.g. to highlight them in an editor.
+    pub fn with_text(self) -> TokensWithText<'a> {
+        TokensWithText {
+            input: self.input,
+            start: self.offset,
+            tokenizer: self,
+        }
+    }
+
+    /// Parses the value of an assertion directive at the start of `input`, returning it together
+    /// with the number of digits it consists of.
+    fn parse_assertion_value(input: &str) -> Option<(u8, usize)> {
+        let digits = input.chars().take_while(char::is_ascii_digit).count();
+
+        input[..digits].parse().ok().map(|value| (value, digits))
+    }
 }
 
 impl<'a> Iterator for Tokenizer<'a> {
@@ -69,13 +190,43 @@ impl<'a> Iterator for Tokenizer<'a> {
     fn next(&mut self) -> Option<Self::Item> {
         let mut chars = self.input.chars();
         let mut token_type = None;
+        let mut digits = 0;
+
+        while let Some(c) = chars.next() {
+            // A shebang line, e.g. `#!/usr/bin/env -S brainfuck -O`, would contain commands and
+            // directives, so it is a comment as a whole, but only at the very start of the input.
+            let shebang =
+                self.line == 1 && self.col == 1 && c == '#' && chars.as_str().starts_with('!');
+
+            if self.col == 1 && (shebang || self.comment_prefix == Some(c)) {
+                // Skip to the line break, which still has to be counted below.
+                let rest = chars.as_str();
+                chars = rest[rest.find('\n').unwrap_or(rest.len())..].chars();
+                continue;
+            }
 
-        for c in chars.by_ref() {
             if let Some(i) = TokenType::from_char(c) {
                 token_type = Some(i);
                 break;
             }
 
+            if c == '=' && self.assertions {
+                if let Some((value, value_digits)) = Self::parse_assertion_value(chars.as_str()) {
+                    token_type = Some(TokenType::AssertEquals(value));
+                    digits = value_digits;
+                    break;
+                }
+            }
+
+            if let (Some(grid), '^' | 'v') = (self.grid, c) {
+                token_type = Some(if c == '^' {
+                    TokenType::MoveUp(grid.width)
+                } else {
+                    TokenType::MoveDown(grid.width)
+                });
+                break;
+            }
+
             if c == '\n' {
                 self.line += 1;
                 self.col = 1;
@@ -85,20 +236,127 @@ impl<'a> Iterator for Tokenizer<'a> {
         }
 
         if let Some(token_type) = token_type {
-            let token = Token {
+            // All commands and directives start with a single byte character.
+            let rest = chars.as_str();
+            let offset = self.offset + self.input.len() - rest.len() - 1;
+
+            let loc = SourceLoc {
+                line: self.line,
+                col: self.col,
+                offset,
+            };
+
+            self.input = &rest[digits..];
+            self.col += 1 + digits;
+            self.offset = offset + 1 + digits;
+
+            Some(Token {
                 token_type,
-                loc: SourceLoc {
+                loc,
+                end: SourceLoc {
                     line: self.line,
                     col: self.col,
+                    offset: self.offset,
                 },
-            };
-
-            self.input = chars.as_str();
-            self.col += 1;
-
-            Some(token)
+            })
         } else {
             None
         }
     }
 }
+
+/// An iterator over tokens together with the text of the input they occupy, created by
+/// `Tokenizer::with_text`.
+#[derive(Debug, Clone)]
+pub struct TokensWithText<'a> {
+    tokenizer: Tokenizer<'a>,
+    input: &'a str,
+    /// The offset of `input` from the start of the whole input, which the offsets of the tokens
+    /// are relative to.
+    start: usize,
+}
+
+impl<'a> Iterator for TokensWithText<'a> {
+    type Item = (Token, &'a str);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let token = self.tokenizer.next()?;
+        let text = &self.input[token.loc.offset - self.start..token.end.offset - self.start];
+
+        Some((token, text))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use TokenType::*;
+
+    fn token_types(tokenizer: Tokenizer) -> Vec<TokenType> {
+        tokenizer.map(|token| token.token_type).collect()
+    }
+
+    #[test]
+    fn comment_prefix_ignores_line_with_commands() {
+        let tokenizer =
+            Tokenizer::new("; adds 1, then prints it.\n+.\n ;+").with_comment_prefix(Some(';'));
+        let tokens = tokenizer.collect::<Vec<_>>();
+
+        assert_eq!(
+            tokens
+                .iter()
+                .map(|token| token.token_type)
+                .collect::<Vec<_>>(),
+            [Increment, Output, Increment]
+        );
+        assert_eq!(tokens[0].loc.to_string(), "2:1");
+
+        // Without the option, the prefix is an ordinary comment character.
+        assert_eq!(
+            token_types(Tokenizer::new("; ,.\n+")),
+            [Input, Output, Increment]
+        );
+    }
+
+    #[test]
+    fn tokens_with_text() {
+        let tokenizer = Tokenizer::new("+ =12 [\n>]").with_assertions(true);
+        let texts = tokenizer
+            .clone()
+            .with_text()
+            .map(|(_, text)| text)
+            .collect::<Vec<_>>();
+
+        assert_eq!(texts, ["+", "=12", "[", ">", "]"]);
+
+        // The text is also found for the remaining tokens of a partially consumed tokenizer.
+        let mut tokenizer = tokenizer;
+        tokenizer.nth(1);
+        let rest = tokenizer.with_text().collect::<Vec<_>>();
+
+        assert_eq!(rest[0].0.token_type, LoopStart);
+        assert_eq!(
+            rest.iter().map(|(_, text)| *text).collect::<Vec<_>>(),
+            ["[", ">", "]"]
+        );
+    }
+
+    #[test]
+    fn shebang_line_is_skipped() {
+        let tokens =
+            Tokenizer::new("#!/usr/bin/env -S brainfuck-rs run +-<>.,\n+.").collect::<Vec<_>>();
+
+        assert_eq!(
+            tokens
+                .iter()
+                .map(|token| token.token_type)
+                .collect::<Vec<_>>(),
+            [Increment, Output]
+        );
+        assert_eq!(tokens[0].loc.to_string(), "2:1");
+
+        // Only the first line can be a shebang.
+        assert_eq!(token_types(Tokenizer::new("\n#!+")), [Increment]);
+    }
+}
@@ -1,8 +1,10 @@
 #include <stdbool.h>
+#include <stddef.h>
 #include <stdio.h>
 #include <stdint.h>
 #include <stdlib.h>
 #include <string.h>
+#include <sys/mman.h>
 
 static inline size_t nextPowerOfTwo(size_t n) {
     if (n <= 1) return 1;
@@ -12,26 +14,145 @@ static inline size_t nextPowerOfTwo(size_t n) {
     return 1ul << (64 - z);
 }
 
-extern void moveRight(char ** cellsPtr, size_t * cellsCountPtr, size_t * currentCellPtr, size_t amount) {
+// The maximum size of the tape in bytes, or 0 if it is unlimited. Defined by the generated code.
+extern const size_t bfMaxTapeBytes;
+// Whether the tape is mapped with mmap instead of allocated with calloc. Defined by the generated
+// code.
+extern const bool bfMapTape;
+
+static void tapeAllocationFailed(size_t cellsCount) {
+    fprintf(stderr, "Error: Cannot allocate a tape of %zu bytes!\n", cellsCount);
+    exit(1);
+}
+
+static char * mapCells(size_t cellsCount) {
+    void * cells = mmap(NULL, cellsCount, PROT_READ | PROT_WRITE, MAP_PRIVATE | MAP_ANONYMOUS, -1, 0);
+    if (cells == MAP_FAILED) tapeAllocationFailed(cellsCount);
+
+    return (char *)cells;
+}
+
+extern char * allocateCells(size_t cellsCount) {
+    if (bfMapTape) return mapCells(cellsCount);
+
+    char * cells = (char *)calloc(cellsCount, 1);
+    if (cells == NULL) tapeAllocationFailed(cellsCount);
+
+    return cells;
+}
+
+extern void freeCells(char * cells, size_t cellsCount) {
+    if (bfMapTape) {
+        munmap(cells, cellsCount);
+    } else {
+        free(cells);
+    }
+}
+
+static void growCells(char ** cellsPtr, size_t * cellsCountPtr, size_t minimumCellsCount) {
     size_t cellsCount = *cellsCountPtr;
-    if (cellsCount <= (*currentCellPtr += amount)) {
-        size_t newCellsCount = nextPowerOfTwo(*currentCellPtr + 1);
 
+    // No tape can be larger than PTRDIFF_MAX bytes, and the next power of two of a larger count
+    // doesn't fit into a size_t.
+    if (minimumCellsCount > PTRDIFF_MAX) tapeAllocationFailed(minimumCellsCount);
+
+    size_t newCellsCount = nextPowerOfTwo(minimumCellsCount);
+
+    if (bfMaxTapeBytes != 0 && newCellsCount > bfMaxTapeBytes) {
+        if (minimumCellsCount > bfMaxTapeBytes) {
+            fprintf(stderr, "Error: Tape would grow beyond the limit of %zu bytes!\n", bfMaxTapeBytes);
+            exit(1);
+        }
+
+        newCellsCount = bfMaxTapeBytes;
+    }
+
+    if (bfMapTape) {
+        // The new mapping is already zeroed, so only the old cells are copied.
+        char * cells = mapCells(newCellsCount);
+        memcpy(cells, *cellsPtr, cellsCount);
+        munmap(*cellsPtr, cellsCount);
+        *cellsPtr = cells;
+    } else {
         *cellsPtr = (char *)realloc(*cellsPtr, newCellsCount);
+        if (*cellsPtr == NULL) tapeAllocationFailed(newCellsCount);
+
+        // Unlike calloc, realloc leaves the new cells uninitialized, but every cell starts at 0.
         memset(*cellsPtr + cellsCount, 0, newCellsCount - cellsCount);
+    }
+
+    *cellsCountPtr = newCellsCount;
+}
+
+extern void moveRight(char ** cellsPtr, size_t * cellsCountPtr, size_t * currentCellPtr, size_t amount) {
+    if (*cellsCountPtr <= (*currentCellPtr += amount)) {
+        growCells(cellsPtr, cellsCountPtr, *currentCellPtr + 1);
+    }
+}
 
-        *cellsCountPtr = newCellsCount;
+extern void reserveCells(char ** cellsPtr, size_t * cellsCountPtr, size_t minimumCellsCount) {
+    if (*cellsCountPtr < minimumCellsCount) {
+        growCells(cellsPtr, cellsCountPtr, minimumCellsCount);
     }
 }
 
+static char * inputPosition = NULL;
+static size_t inputLength = 0, inputBufferLength = 0;
+
+// The bytes that are read instead of stdin with --argv-input, or NULL to read stdin.
+static const char * inputArgument = NULL;
+
+extern void setInputArgument(int argc, char ** argv) {
+    // Without an argument, the input is empty.
+    inputArgument = argc > 1 ? argv[1] : "";
+}
+
+// Every run of the program starts with an input buffer allocated by this function or without one,
+// so the state of a previous run, e.g. an earlier call of the function exported with --shared, is
+// discarded in both cases.
+extern void allocateInputBuffer(char ** inputBufferPtr, size_t size) {
+    *inputBufferPtr = calloc(size, 1);
+    if (*inputBufferPtr == NULL && size > 0) {
+        fprintf(stderr, "Error: Cannot allocate an input buffer of %zu bytes!\n", size);
+        exit(1);
+    }
+
+    inputPosition = *inputBufferPtr;
+    inputLength = 0;
+    inputBufferLength = size;
+}
+
 extern void input(char * cells, size_t currentCell, char ** inputBufferPtr) {
-    static char * inputPosition = NULL;
-    static size_t length = 0, bufferLength = 0;
+    // At the end of the argument, the cell is set to its terminating 0, like at the end of stdin.
+    if (inputArgument != NULL) {
+        cells[currentCell] = *inputArgument;
+        if (*inputArgument != '\0') {
+            inputArgument++;
+        }
+
+        return;
+    }
 
-    size_t currentLength = length - (inputPosition - *inputBufferPtr);
+    if (*inputBufferPtr == NULL) {
+        inputPosition = NULL;
+        inputLength = 0;
+        inputBufferLength = 0;
+    }
+
+    size_t currentLength = inputLength - (inputPosition - *inputBufferPtr);
     if (currentLength == 0) {
-        length = getline(inputBufferPtr, &bufferLength, stdin);
+        // getline grows the buffer with realloc if a line doesn't fit into it.
+        ssize_t readLength = getline(inputBufferPtr, &inputBufferLength, stdin);
         inputPosition = *inputBufferPtr;
+
+        // At the end of the input, the cell is set to 0, like in the interpreter.
+        if (readLength < 0) {
+            inputLength = 0;
+            cells[currentCell] = 0;
+            return;
+        }
+
+        inputLength = readLength;
     }
 
     char currentChar = *(inputPosition++);
@@ -45,13 +166,108 @@ extern void moveRightUntilZero(char ** cellsPtr, size_t * cellsCountPtr, size_t
 
     while (cells[currentCell] != 0) {
         if (cellsCount <= (currentCell += stepSize)) {
-            size_t newCellsCount = nextPowerOfTwo(currentCell + 1);
+            growCells(cellsPtr, cellsCountPtr, currentCell + 1);
+            break;
+        }
+    }
 
-            *cellsPtr = (char *)realloc(*cellsPtr, newCellsCount);
-            memset(*cellsPtr + cellsCount, 0, newCellsCount - cellsCount);
+    *currentCellPtr = currentCell;
+}
 
-            *cellsCountPtr = newCellsCount;
-            break;
+// The buffer of --output-buffer, which the generated code appends every output to directly. It is
+// written with a single fwrite whenever it is full and at exit.
+char * bfOutputBuffer = NULL;
+size_t bfOutputLength = 0;
+static size_t outputBufferSize = 0;
+static FILE * outputBufferStream = NULL;
+
+extern void flushOutputBuffer(void) {
+    if (bfOutputLength == 0) return;
+
+    fwrite(bfOutputBuffer, 1, bfOutputLength, outputBufferStream);
+    bfOutputLength = 0;
+}
+
+extern void unbufferOutput(FILE * stream) {
+    setvbuf(stream, NULL, _IONBF, 0);
+}
+
+extern void allocateOutputBuffer(size_t size, FILE * stream) {
+    static bool flushesAtExit = false;
+
+    bfOutputBuffer = malloc(size);
+    if (bfOutputBuffer == NULL) {
+        fprintf(stderr, "Error: Cannot allocate an output buffer of %zu bytes!\n", size);
+        exit(1);
+    }
+
+    bfOutputLength = 0;
+    outputBufferSize = size;
+    outputBufferStream = stream;
+
+    // The stream doesn't buffer on its own, so that every flush is a single write.
+    unbufferOutput(stream);
+
+    // The helpers that exit the program, e.g. on a failed assertion, still write the output.
+    if (!flushesAtExit) {
+        atexit(flushOutputBuffer);
+        flushesAtExit = true;
+    }
+}
+
+extern void freeOutputBuffer(void) {
+    flushOutputBuffer();
+    free(bfOutputBuffer);
+    bfOutputBuffer = NULL;
+}
+
+static void writeOutput(const char * bytes, size_t length, FILE * stream) {
+    if (bfOutputBuffer == NULL) {
+        fwrite(bytes, 1, length, stream);
+        return;
+    }
+
+    if (bfOutputLength + length > outputBufferSize) {
+        flushOutputBuffer();
+
+        // Bytes that don't fit into the empty buffer either are written at once.
+        if (length >= outputBufferSize) {
+            fwrite(bytes, 1, length, stream);
+            return;
+        }
+    }
+
+    memcpy(bfOutputBuffer + bfOutputLength, bytes, length);
+    bfOutputLength += length;
+
+    if (bfOutputLength == outputBufferSize) {
+        flushOutputBuffer();
+    }
+}
+
+extern void outputUntilZero(char ** cellsPtr, size_t * cellsCountPtr, size_t * currentCellPtr, size_t stepSize, FILE * stream) {
+    char * cells = *cellsPtr;
+    size_t cellsCount = *cellsCountPtr;
+    size_t currentCell = *currentCellPtr;
+
+    if (stepSize == 1) {
+        // The cells up to the next zero one are contiguous, so they are written at once.
+        char * end = memchr(cells + currentCell, 0, cellsCount - currentCell);
+        size_t length = end != NULL ? (size_t)(end - (cells + currentCell)) : cellsCount - currentCell;
+
+        writeOutput(cells + currentCell, length, stream);
+
+        if (cellsCount <= (currentCell += length)) {
+            growCells(cellsPtr, cellsCountPtr, currentCell + 1);
+        }
+    } else {
+        while (cells[currentCell] != 0) {
+            writeOutput(cells + currentCell, 1, stream);
+
+            if (cellsCount <= (currentCell += stepSize)) {
+                growCells(cellsPtr, cellsCountPtr, currentCell + 1);
+                break;
+            }
         }
     }
 
@@ -95,3 +311,58 @@ extern bool moveValueLeft(char * cells, size_t currentCell, size_t amount) {
     cells[currentCell - amount] += value;
     return false;
 }
+
+extern bool clearRange(char ** cellsPtr, size_t * cellsCountPtr, size_t currentCell, ptrdiff_t startOffset, size_t length) {
+    if (startOffset < 0 && currentCell < (size_t)-startOffset) return true;
+
+    size_t startCell = currentCell + startOffset;
+    reserveCells(cellsPtr, cellsCountPtr, startCell + length);
+
+    memset(*cellsPtr + startCell, 0, length);
+    return false;
+}
+
+extern bool transferValue(char ** cellsPtr, size_t * cellsCountPtr, size_t currentCell, ptrdiff_t offset) {
+    if (offset < 0 && currentCell < (size_t)-offset) return true;
+
+    size_t targetCell = currentCell + offset;
+    reserveCells(cellsPtr, cellsCountPtr, targetCell + 1);
+
+    char * cells = *cellsPtr;
+    cells[targetCell] = cells[currentCell];
+    cells[currentCell] = 0;
+    return false;
+}
+
+extern FILE * openOutput(int fd) {
+    static FILE * output = NULL;
+    static int outputFd = -1;
+
+    // The stream is kept open for later runs of the program, e.g. calls of the function exported
+    // with --shared, as closing it would close the file descriptor as well.
+    if (outputFd != fd) {
+        output = fdopen(fd, "w");
+        if (output == NULL) {
+            fprintf(stderr, "Error: Cannot write output to file descriptor %d!\n", fd);
+            exit(1);
+        }
+
+        outputFd = fd;
+    }
+
+    return output;
+}
+
+extern void reportTapeUsage(size_t cellsCount) {
+    fprintf(stderr, "Peak tape usage: %zu bytes\n", cellsCount);
+}
+
+extern void assertionFailed(size_t currentCell, unsigned char expected, unsigned char actual) {
+    fprintf(stderr, "Error: Assertion failed at cell %zu: expected %u, found %u!\n", currentCell, expected, actual);
+    exit(1);
+}
+
+extern void outOfBounds(const char * instruction, size_t currentCell, size_t cellsCount) {
+    fprintf(stderr, "Error: %s accessed cell %td, outside of the tape of %zu cells!\n", instruction, (ptrdiff_t)currentCell, cellsCount);
+    exit(1);
+}
@@ -0,0 +1,308 @@
+use std::{
+    fs,
+    io::Write,
+    path::{Path, PathBuf},
+    process::{Command, Output, Stdio},
+};
+
+use object::Object;
+use tempfile::TempDir;
+
+/// Writes every `(name, source)` of `files` into a new temporary directory, which is deleted when
+/// it is dropped.
+fn write_programs(files: &[(&str, &str)]) -> TempDir {
+    let directory = TempDir::new().unwrap();
+    for (name, source) in files {
+        fs::write(directory.path().join(name), source).unwrap();
+    }
+
+    directory
+}
+
+fn path(directory: &TempDir, name: &str) -> PathBuf {
+    directory.path().join(name)
+}
+
+/// Returns a command that runs the compiler in the root of the crate, where it finds the helpers.
+fn compiler() -> Command {
+    let mut command = Command::new(env!("CARGO_BIN_EXE_brainfuck-rs"));
+    command
+        .current_dir(env!("CARGO_MANIFEST_DIR"))
+        .env_remove("BFC_OPT")
+        .env_remove("RUST_LOG")
+        .env("NO_COLOR", "1");
+
+    command
+}
+
+/// Whether clang can be run to build executables. The tests that build and run executables pass
+/// without doing anything if it can't.
+fn has_clang() -> bool {
+    Command::new("clang")
+        .arg("--version")
+        .output()
+        .is_ok_and(|output| output.status.success())
+}
+
+/// Whether clang finds a static C library for `triple`, like the compiler checks before linking
+/// with `--static`.
+fn has_static_libc(triple: &str) -> bool {
+    Command::new("clang")
+        .args(["-target", triple, "-print-file-name=libc.a"])
+        .output()
+        .is_ok_and(|output| Path::new(String::from_utf8_lossy(&output.stdout).trim()).is_file())
+}
+
+/// Builds the program `name`.bf in `directory` into the executable `name` with the extra `args`
+/// and returns its path.
+fn build(directory: &TempDir, name: &str, args: &[&str]) -> PathBuf {
+    let executable = path(directory, name);
+    let output = compiler()
+        .arg(path(directory, &format!("{}.bf", name)))
+        .arg("-o")
+        .arg(&executable)
+        .args(args)
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{:?}", output);
+
+    executable
+}
+
+/// Runs `executable` with `args` and `input` on stdin.
+fn run(executable: &Path, args: &[&str], input: &[u8]) -> Output {
+    let mut child = Command::new(executable)
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child.stdin.take().unwrap().write_all(input).unwrap();
+
+    child.wait_with_output().unwrap()
+}
+
+#[test]
+fn success_exits_with_0() {
+    let directory = write_programs(&[("good.bf", "+[-].")]);
+    let output = compiler()
+        .arg("check")
+        .arg(path(&directory, "good.bf"))
+        .output()
+        .unwrap();
+
+    assert_eq!(output.status.code(), Some(0));
+}
+
+#[test]
+fn parse_error_exits_with_2() {
+    let directory = write_programs(&[("bad.bf", "+]")]);
+    let output = compiler()
+        .arg("check")
+        .arg(path(&directory, "bad.bf"))
+        .output()
+        .unwrap();
+
+    assert_eq!(output.status.code(), Some(2));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("unexpected loop end"));
+}
+
+#[test]
+fn programs_without_commands_run() {
+    if !has_clang() {
+        return;
+    }
+
+    let directory = write_programs(&[("empty.bf", ""), ("comment.bf", "only a comment\n")]);
+    for name in ["empty", "comment"] {
+        let output = run(&build(&directory, name, &[]), &[], b"");
+
+        assert_eq!(output.status.code(), Some(0));
+        assert!(output.stdout.is_empty());
+    }
+}
+
+#[test]
+fn input_at_end_stores_zero() {
+    if !has_clang() {
+        return;
+    }
+
+    let directory = write_programs(&[("eof.bf", ",.")]);
+    let output = run(&build(&directory, "eof", &[]), &[], b"");
+
+    assert_eq!(output.stdout, b"\0");
+}
+
+#[test]
+fn grown_cells_are_zero() {
+    if !has_clang() {
+        return;
+    }
+
+    let source = format!("+{}.<.{}.", ">".repeat(1000), ">".repeat(5000));
+    let directory = write_programs(&[("grow.bf", &source)]);
+    let output = run(&build(&directory, "grow", &[]), &[], b"");
+
+    assert_eq!(output.stdout, [0, 0, 0]);
+}
+
+/// Loads the library in the first argument and calls its function `run` twice.
+const SHARED_DRIVER: &str = r#"#include <dlfcn.h>
+#include <stdio.h>
+
+int main(int argc, char ** argv) {
+    void * library = dlopen(argv[1], RTLD_NOW);
+    if (library == NULL) {
+        fprintf(stderr, "%s\n", dlerror());
+        return 2;
+    }
+
+    int (*run)(void) = (int (*)(void))dlsym(library, "run");
+    return run() + run();
+}
+"#;
+
+#[test]
+fn second_run_of_shared_entry_is_fresh() {
+    if !has_clang() {
+        return;
+    }
+
+    // Prints the first two cells before and after reading a character into the first one and
+    // adding 3 to the second one, which only prints 0 first if the tape of every run is fresh.
+    let directory = write_programs(&[("program.bf", ".>.<,.>+++.<"), ("driver.c", SHARED_DRIVER)]);
+    let library = build(&directory, "program", &["--shared", "--entry-name", "run"]);
+
+    let driver = path(&directory, "driver");
+    let output = Command::new("clang")
+        .arg("-o")
+        .arg(&driver)
+        .arg(path(&directory, "driver.c"))
+        .arg("-ldl")
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{:?}", output);
+
+    // The first run buffers the whole first line, which the second run must not continue.
+    let output = run(&driver, &[library.to_str().unwrap()], b"ab\ncd\n");
+
+    assert_eq!(output.status.code(), Some(0), "{:?}", output);
+    assert_eq!(output.stdout, [0, 0, b'a', 3, 0, 0, b'c', 3]);
+}
+
+#[test]
+fn both_tape_allocations_run() {
+    if !has_clang() {
+        return;
+    }
+
+    // Prints a cell every 1000 cells until the tape would grow beyond the limit, which fails.
+    let source = format!("+[{}+.]", ">".repeat(1000));
+    let directory = write_programs(&[("tape.bf", &source)]);
+    for allocation in ["calloc", "mmap"] {
+        let executable = build(
+            &directory,
+            "tape",
+            &["--tape-alloc", allocation, "--max-tape", "65536"],
+        );
+        let output = run(&executable, &[], b"");
+
+        assert_eq!(output.status.code(), Some(1), "{:?}", output);
+        assert_eq!(output.stdout, [1; 65]);
+    }
+}
+
+#[test]
+#[ignore = "needs clang with the AddressSanitizer runtime, run with --ignored"]
+fn address_sanitizer_finds_no_errors() {
+    // Reads input and grows the tape, which covers most of the helpers.
+    let source = format!(
+        "{}{}[-]<<,[.,]",
+        include_str!("../examples/Rot13.bf"),
+        ">".repeat(5000)
+    );
+    let directory = write_programs(&[("asan.bf", &source)]);
+    let executable = build(&directory, "asan", &["--sanitize", "address"]);
+    let output = run(&executable, &[], b"Hello\n");
+
+    assert!(output.status.success(), "{:?}", output);
+    assert_eq!(output.stdout, b"Uryyb\n");
+    assert!(!String::from_utf8_lossy(&output.stderr).contains("AddressSanitizer"));
+}
+
+#[test]
+fn verbose_logs_the_linker_command() {
+    if !has_clang() {
+        return;
+    }
+
+    let directory = write_programs(&[("program.bf", "+.")]);
+    let executable = path(&directory, "program");
+    let link_arguments = format!("\"-o\" {:?}", executable);
+    let link = |args: &[&str]| {
+        let output = compiler()
+            .arg(path(&directory, "program.bf"))
+            .arg("-o")
+            .arg(&executable)
+            .args(args)
+            // Keeps the object file of the dry run in the directory, which deletes it.
+            .env("TMPDIR", directory.path())
+            .output()
+            .unwrap();
+        assert!(output.status.success(), "{:?}", output);
+
+        output
+    };
+
+    let stderr = String::from_utf8(link(&["-v"]).stderr).unwrap();
+    assert!(
+        stderr
+            .lines()
+            .any(|line| line.starts_with("debug: running \"clang\"")
+                && line.contains(&link_arguments))
+    );
+
+    let stderr = String::from_utf8(link(&[]).stderr).unwrap();
+    assert!(!stderr.contains("running"));
+
+    // A dry run prints the same command instead of running it.
+    let stdout = String::from_utf8(link(&["--dry-run"]).stdout).unwrap();
+    assert!(stdout.starts_with("\"clang\"") && stdout.contains(&link_arguments));
+}
+
+#[test]
+fn static_musl_executable_runs() {
+    const TRIPLE: &str = "x86_64-unknown-linux-musl";
+    if !cfg!(all(target_os = "linux", target_arch = "x86_64")) || !has_static_libc(TRIPLE) {
+        return;
+    }
+
+    let directory = write_programs(&[("cat.bf", ",[.,]")]);
+    let executable = build(&directory, "cat", &["--target", TRIPLE, "--static"]);
+    let output = run(&executable, &[], b"meow\n");
+
+    assert!(output.status.success(), "{:?}", output);
+    assert_eq!(output.stdout, b"meow\n");
+
+    // A static executable has no interpreter that loads shared libraries.
+    let data = fs::read(&executable).unwrap();
+    let file = object::File::parse(&*data).unwrap();
+    assert!(file.section_by_name(".interp").is_none());
+}
+
+#[test]
+fn argv_input_reads_first_argument() {
+    if !has_clang() {
+        return;
+    }
+
+    // The fourth read is at the end of the argument, which stores 0 like at the end of stdin.
+    let directory = write_programs(&[("echo.bf", ",.,.,.,.")]);
+    let executable = build(&directory, "echo", &["--argv-input"]);
+    let output = run(&executable, &["ABC"], b"xyz");
+
+    assert!(output.status.success(), "{:?}", output);
+    assert_eq!(output.stdout, b"ABC\0");
+}
@@ -0,0 +1,217 @@
+use std::{
+    collections::hash_map::DefaultHasher,
+    hash::{Hash, Hasher},
+    path::Path,
+};
+
+use brainfuck_rs::{
+    compiler::{self, CompileArtifacts, CompileOptions, CompileWarning},
+    interpreter,
+    tok::{SourceLoc, Tokenizer},
+};
+
+fn compile(source: &str, options: CompileOptions) -> CompileArtifacts {
+    compiler::compile(source, Path::new("test.bf"), &options).unwrap()
+}
+
+/// Returns options that only emit the instructions as S-expressions.
+fn sexpr_options() -> CompileOptions {
+    CompileOptions {
+        emit_sexpr: true,
+        emit_object: false,
+        ..CompileOptions::default()
+    }
+}
+
+#[test]
+fn programs_without_commands_compile_and_run() {
+    for source in ["", "This program only consists of a comment\n"] {
+        let artifacts = compile(
+            source,
+            CompileOptions {
+                emit_llvm: true,
+                ..CompileOptions::default()
+            },
+        );
+
+        assert!(!artifacts.object.unwrap().is_empty());
+        assert!(artifacts.llvm_ir.unwrap().contains("ret i32 0"));
+
+        let instructions = compiler::parse(Tokenizer::new(source)).unwrap();
+        assert_eq!(interpreter::interpret(&instructions, b"").unwrap(), b"");
+    }
+}
+
+#[test]
+fn infinite_loop_warns_about_unreachable_code() {
+    let artifacts = compile("+[]+++.", sexpr_options());
+
+    assert_eq!(
+        artifacts.warnings,
+        [CompileWarning::Unreachable {
+            code: SourceLoc {
+                line: 1,
+                col: 4,
+                offset: 3
+            },
+            infinite_loop: SourceLoc {
+                line: 1,
+                col: 2,
+                offset: 1
+            },
+        }]
+    );
+    assert_eq!(
+        artifacts.sexpr.unwrap(),
+        "(increment 1)\n(loop)\n(increment 3)\n(output)\n"
+    );
+}
+
+#[test]
+fn prune_unreachable_drops_code_after_infinite_loop() {
+    let options = CompileOptions {
+        prune_unreachable: true,
+        ..sexpr_options()
+    };
+    let artifacts = compile("+[]+++.", options);
+
+    assert_eq!(artifacts.sexpr.unwrap(), "(increment 1)\n(loop)\n");
+}
+
+#[test]
+fn runs_of_more_than_255_changes_warn_with_warn_wrap() {
+    let warnings = |source: &str| {
+        let options = CompileOptions {
+            warn_wrap: true,
+            ..sexpr_options()
+        };
+
+        compile(source, options).warnings
+    };
+
+    assert_eq!(
+        warnings(&format!(",{}.", "+".repeat(256))),
+        [CompileWarning::WrappingRun {
+            loc: SourceLoc {
+                line: 1,
+                col: 2,
+                offset: 1
+            },
+            count: 256,
+            increment: true,
+        }]
+    );
+    assert_eq!(warnings(&format!(",{}.", "+".repeat(255))), []);
+    assert_eq!(
+        compile(&format!(",{}.", "+".repeat(256)), sexpr_options()).warnings,
+        []
+    );
+}
+
+#[test]
+fn emit_llvm() {
+    let artifacts = compile(
+        "+++.",
+        CompileOptions {
+            emit_llvm: true,
+            emit_object: false,
+            ..CompileOptions::default()
+        },
+    );
+
+    assert!(artifacts.llvm_ir.unwrap().contains("define i32 @main("));
+    assert!(artifacts.object.is_none());
+}
+
+#[test]
+fn emit_asm() {
+    let artifacts = compile(
+        "+++.",
+        CompileOptions {
+            emit_asm: true,
+            emit_object: false,
+            ..CompileOptions::default()
+        },
+    );
+
+    assert!(artifacts.assembly.unwrap().contains("main:"));
+    assert!(artifacts.object.is_none());
+}
+
+#[test]
+fn emit_rust() {
+    let artifacts = compile(
+        "+++.",
+        CompileOptions {
+            emit_rust: true,
+            emit_object: false,
+            ..CompileOptions::default()
+        },
+    );
+
+    assert!(artifacts.rust.unwrap().contains("fn main()"));
+    assert!(artifacts.object.is_none());
+}
+
+#[test]
+fn emit_sexpr() {
+    let artifacts = compile("+++.", sexpr_options());
+
+    assert_eq!(artifacts.sexpr.unwrap(), "(increment 3)\n(output)\n");
+    assert!(artifacts.object.is_none());
+}
+
+#[test]
+fn emit_object() {
+    let artifacts = compile("+++.", CompileOptions::default());
+
+    let object = artifacts.object.unwrap();
+    assert!(object::File::parse(&*object).is_ok());
+    assert!(artifacts.llvm_ir.is_none());
+    assert!(artifacts.assembly.is_none());
+}
+
+#[test]
+fn listing_shows_addresses_and_locations() {
+    let options = CompileOptions {
+        list: true,
+        emit_object: false,
+        ..CompileOptions::default()
+    };
+    let artifacts = compile("+[-]\n>.", options);
+
+    assert_eq!(
+        artifacts.listing.unwrap(),
+        "0000  Increment(1)                    1:1\n\
+         0001  Loop                            1:2\n\
+         0002    Decrement(1)                  1:3\n\
+         0003  MoveRight(1)                    2:1\n\
+         0004  Output                          2:2\n"
+    );
+}
+
+#[test]
+fn compiling_twice_gives_identical_outputs() {
+    // Unrolls a known counter into multiplications of several cells, whose order must not depend
+    // on the run.
+    let source = format!(
+        "++++[>+>++>+++>++++>+++++<<<<<-]>.>.>.>.>.{}",
+        include_str!("../examples/Mandelbrot.bf")
+    );
+    let hash = || {
+        let artifacts = compile(
+            &source,
+            CompileOptions {
+                emit_llvm: true,
+                ..CompileOptions::default()
+            },
+        );
+
+        let mut hasher = DefaultHasher::new();
+        artifacts.llvm_ir.unwrap().hash(&mut hasher);
+        artifacts.object.unwrap().hash(&mut hasher);
+        hasher.finish()
+    };
+
+    assert_eq!(hash(), hash());
+}
@@ -0,0 +1,85 @@
+use std::{fs, path::Path};
+
+use brainfuck_rs::{
+    code_gen::CodeGenOptions, compiler, instruction::Instruction, interpreter,
+    optimizer::DEFAULT_MAX_UNROLL_ITERATIONS, tok::Tokenizer,
+};
+
+/// The examples that read input, with the input they are run with. Every other example is run
+/// without any input.
+const INPUTS: &[(&str, &[u8])] = &[
+    ("BFInterpreter", b"++++++++[>++++++++<-]>+.!"),
+    ("Cat", b"meow\n"),
+    ("Life", b"q\n"),
+    ("Rot13", b"Hello, World!\n"),
+];
+
+/// The examples that never stop on their own. `Random` prints random numbers forever, so it can't be
+/// run at all.
+const SKIPPED: &[&str] = &["Random"];
+
+/// The examples that only stop once they run out of tape, so they are run with a tape limit.
+const TAPE_LIMITED: &[&str] = &["MemoryEater"];
+
+/// The examples that have to be part of the matrix, so that it can't silently pass without them.
+const REQUIRED: &[&str] = &["HelloWorld", "Mandelbrot", "Rot13"];
+
+fn run(name: &str, instructions: &[Instruction]) -> Result<Vec<u8>, String> {
+    let options = CodeGenOptions {
+        max_tape_bytes: TAPE_LIMITED.contains(&name).then_some(1 << 16),
+        ..CodeGenOptions::default()
+    };
+    let input = INPUTS
+        .iter()
+        .find(|(example, _)| *example == name)
+        .map_or(&b""[..], |(_, input)| input);
+
+    let mut output = Vec::new();
+    interpreter::interpret_with_options(instructions, &options, input, &mut output)
+        .map_err(|error| error.to_string())?;
+
+    Ok(output)
+}
+
+#[test]
+fn optimized_examples_behave_like_parsed_ones() {
+    let examples = Path::new(env!("CARGO_MANIFEST_DIR")).join("examples");
+    let mut names = Vec::new();
+
+    for entry in fs::read_dir(examples).unwrap() {
+        let path = entry.unwrap().path();
+        if path.extension().is_none_or(|extension| extension != "bf") {
+            continue;
+        }
+
+        let name = path.file_stem().unwrap().to_str().unwrap().to_owned();
+        if SKIPPED.contains(&name.as_str()) {
+            continue;
+        }
+
+        let source = fs::read_to_string(&path).unwrap();
+        let parsed = compiler::parse(Tokenizer::new(&source)).unwrap();
+        let (optimized, _, _) =
+            compiler::optimize(&parsed, DEFAULT_MAX_UNROLL_ITERATIONS, false, false, false);
+
+        assert_eq!(run(&name, &optimized), run(&name, &parsed), "{}", name);
+        names.push(name);
+    }
+
+    for required in REQUIRED {
+        assert!(
+            names.iter().any(|name| name == required),
+            "{} wasn't run",
+            required
+        );
+    }
+}
+
+#[test]
+fn rot13_example_rotates_its_input() {
+    let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("examples/Rot13.bf");
+    let source = fs::read_to_string(path).unwrap();
+    let parsed = compiler::parse(Tokenizer::new(&source)).unwrap();
+
+    assert_eq!(run("Rot13", &parsed), Ok(b"Uryyb, Jbeyq!\n".to_vec()));
+}